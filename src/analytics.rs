@@ -0,0 +1,116 @@
+// Learning analytics
+//
+// With the key "analytics: <url>" of exercise, the output posts an event
+// (JSON) to the url for each step of the students (see collect to store
+// them):
+//
+//   session    first passage shown in a session of the browser
+//   passage    passage shown (passage, kind: step, note, bad or end)
+//   step       passage left (passage, kind, duration in ms; a reload splits
+//              the time of a passage in several steps)
+//   choice     gate chosen in a step (passage, target, good)
+//   hint       note of a good gate shown (passage)
+//   complete   last passage shown (passage)
+//
+// Every event has type, exercise (id), title, learner (kept in localStorage),
+// session (kept in sessionStorage) and time. The renderers call the function
+// tweeproblemsShown(passage, kind) when a passage is shown.
+//
+
+use crate::exercise::Exercise;
+use crate::metadata::json_string;
+
+const SCRIPT: &str = r#"
+            // learning analytics (tweeproblems)
+            const tweeproblemsShown = (function () {
+                const { endpoint, exercise, title, start } = tweeproblemsAnalytics;
+                const newId = () => Math.random().toString(36).slice(2) + Date.now().toString(36);
+                const storage = (store, key, value) => {
+                    try {
+                        if (value !== undefined) {
+                            store.setItem(key, JSON.stringify(value));
+                        }
+                        return JSON.parse(store.getItem(key));
+                    } catch (e) {
+                        return value;
+                    }
+                };
+                const learner = storage(localStorage, "tweeproblems-learner") ||
+                    storage(localStorage, "tweeproblems-learner", newId());
+                const key = "tweeproblems-" + exercise;
+                // session and passage shown (kept while reloading)
+                let state = storage(sessionStorage, key);
+
+                function post(type, fields) {
+                    const body = JSON.stringify(Object.assign({
+                        type, exercise, title, learner,
+                        session: state.session,
+                        time: new Date().toISOString(),
+                    }, fields));
+                    if (!(navigator.sendBeacon && navigator.sendBeacon(endpoint, body))) {
+                        fetch(endpoint, { method: "POST", body, keepalive: true }).catch(() => {});
+                    }
+                }
+
+                // the step is posted when the page is left (or reloaded)
+                addEventListener("pagehide", () => {
+                    if (state && state.passage && !state.posted) {
+                        const { passage, kind } = state;
+                        post("step", { passage, kind, duration: Date.now() - state.since });
+                        Object.assign(state, { since: Date.now(), posted: true });
+                        storage(sessionStorage, key, state);
+                    }
+                });
+
+                return function (passage, kind) {
+                    if (!state) {
+                        state = { session: newId() };
+                        post("session", {});
+                    }
+                    const now = Date.now();
+                    if (state.passage === passage) {
+                        if (state.posted) {
+                            Object.assign(state, { since: now, posted: false });
+                            storage(sessionStorage, key, state);
+                        }
+                        return;
+                    }
+                    if (state.passage) {
+                        const previous = { passage: state.passage, kind: state.kind };
+                        if (!state.posted) {
+                            post("step", Object.assign({ duration: now - state.since }, previous));
+                        }
+                        if (previous.kind === "step" && passage !== start) {
+                            post("choice", { passage: previous.passage, target: passage, good: kind !== "bad" });
+                        }
+                    }
+                    Object.assign(state, { passage, kind, since: now, posted: false });
+                    storage(sessionStorage, key, state);
+
+                    post("passage", { passage, kind });
+                    if (kind === "note") {
+                        post("hint", { passage });
+                    } else if (kind === "end") {
+                        post("complete", { passage });
+                    }
+                };
+            })();
+"#;
+
+/// JavaScript of the events of exercise (empty without analytics). start is
+/// the id of the first passage.
+pub fn script(exercise: &Exercise, start: &str) -> String {
+    let endpoint = match &exercise.analytics {
+        Some(endpoint) => endpoint,
+        None => return String::new(),
+    };
+
+    let config = format!(
+        "\n            const tweeproblemsAnalytics = {{ endpoint: {}, exercise: {}, title: {}, start: {} }};",
+        json_string(endpoint),
+        json_string(&exercise.uuid.to_string()),
+        json_string(&exercise.title),
+        json_string(start)
+    );
+    (config + SCRIPT).replace("</", "<\\/")
+}
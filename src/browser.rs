@@ -0,0 +1,498 @@
+// Evaluation of values in the browser
+//
+// With the key "evaluation: browser" of exercise, the values shown in the
+// text aren't written in the output. Each one is a placeholder (⟦n⟧) and the
+// expression is compiled to JavaScript. The script computes the values when
+// the page is loaded (before MathJax) with a seed stored in localStorage:
+// each student has their own data (rand) and keeps it while the seed lives.
+//
+// Structure of passages, conditions (cond), formulas and the values that
+// don't depend on rand are computed when the exercise is built. Only scalar
+// values are compiled: matrices, lists, equations, magnitudes with
+// uncertainty, fractions (numeric=rational) and conditions can't depend on
+// rand (it is an error).
+//
+// The script formats the values as Magnitude::show: the prefixes, the
+// tolerance of rounding and the symbols of format are the ones of Rust.
+// It defines fillPlaceholders(root): the renderers call it with the page
+// (reveal) or with each passage when it is shown (twee).
+//
+// The program is created for each exercise (Macros::program) and shared by
+// its scopes: the values are compiled while the passages are converted and
+// the script is written at the end (Exercise::script).
+//
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::expression::{DictVariables, Expression};
+use crate::format::{Digits, Format, Notation};
+use crate::magnitude::{prefixes, EPSILON};
+
+#[derive(Debug, Default)]
+pub struct Program {
+    enabled: bool,
+    variables: Vec<(String, Expression)>, // memoized in script (by index)
+    random: Vec<(String, Expression, usize)>, // bound value and index of definition
+    definitions: Vec<String>,             // JavaScript of variables
+    outputs: Vec<String>,                 // JavaScript of placeholders
+    rands: usize,                         // rand operators compiled
+}
+
+// program shared by the scopes of an exercise
+pub type SharedProgram = Rc<RefCell<Program>>;
+
+/// Program of an exercise (values are compiled only if it is enabled)
+pub fn program(enabled: bool) -> SharedProgram {
+    Rc::new(RefCell::new(Program {
+        enabled,
+        ..Program::default()
+    }))
+}
+
+/// LaTeX of the value of expr: a placeholder in browser mode
+pub fn show_value(
+    program: &RefCell<Program>,
+    expr: &Expression,
+    dict: &DictVariables,
+    format: &Format,
+) -> String {
+    let compiled = if program.borrow().is_random(expr, dict) && compilable_value(expr, dict, format)
+    {
+        program.borrow_mut().compile(expr, dict)
+    } else {
+        None
+    };
+
+    match compiled {
+        Some(js) => {
+            let magnitude = expr.value(dict);
+            let unit = format.units.symbol(&magnitude.unit);
+            let has_prefix = !unit.is_empty() && format.units.admits_prefix(&magnitude.unit);
+            let js = format!(
+                "show({}, {}, {}, {})",
+                js,
+                js_string(&unit),
+                has_prefix,
+                js_format(format)
+            );
+            program.borrow_mut().placeholder(js)
+        }
+        None => {
+            program.borrow().check_static(expr, dict);
+            expr.show_value(dict, format)
+        }
+    }
+}
+
+/// Number (without units) of the value of expr: a placeholder in browser mode
+pub fn raw_value(program: &RefCell<Program>, expr: &Expression, dict: &DictVariables) -> String {
+    let compiled =
+        if program.borrow().is_random(expr, dict) && !expr.is_matrix(dict) && !expr.is_list(dict) {
+            program.borrow_mut().compile(expr, dict)
+        } else {
+            None
+        };
+
+    match compiled {
+        Some(js) => program.borrow_mut().placeholder(format!("String({})", js)),
+        None => {
+            program.borrow().check_static(expr, dict);
+            format!("{}", expr.value(dict).value)
+        }
+    }
+}
+
+/// text has values computed by the script
+pub fn has_placeholder(text: &str) -> bool {
+    text.contains('⟦')
+}
+
+/// Expression bound to a variable. A random value is drawn once: the value
+/// is bound and, in browser mode, the definition is compiled to the script
+pub fn bind(
+    program: &RefCell<Program>,
+    name: &str,
+    expr: &Expression,
+    dict: &DictVariables,
+) -> Expression {
+    if !depends_on_random(expr, dict, &program.borrow(), &mut Vec::new()) {
+        return expr.clone();
+    }
+
+    let value = expr.literal_value(dict);
+    let mut program = program.borrow_mut();
+    if program.enabled {
+        let index = program.variable(name, expr, dict).unwrap_or_else(|| {
+            panic!(
+                "Variable {} depends on rand and it can't be evaluated in the browser",
+                name
+            )
+        });
+        program
+            .random
+            .push((name.to_string(), value.clone(), index));
+    }
+    value
+}
+
+/// the value of expr is shown by the script as a plain magnitude
+fn compilable_value(expr: &Expression, dict: &DictVariables, format: &Format) -> bool {
+    if expr.is_matrix(dict) || expr.is_list(dict) {
+        return false;
+    }
+    let magnitude = expr.value(dict);
+    magnitude.uncertainty == 0.0 && !magnitude.is_fraction(format)
+}
+
+// visited avoids cycles of variables bound by solvers (x in x 2 pow ... x root)
+fn depends_on_random<'a>(
+    expr: &'a Expression,
+    dict: &'a DictVariables,
+    program: &Program,
+    visited: &mut Vec<&'a str>,
+) -> bool {
+    match expr {
+        Expression::Rand(..) => true,
+        Expression::Variable(name) => {
+            if visited.contains(&name.as_str()) {
+                return false;
+            }
+            visited.push(name);
+            dict.get(name).is_some_and(|e| {
+                depends_on_random(e, dict, program, visited)
+                    || program.random.iter().any(|(n, v, _)| n == name && v == e)
+            })
+        }
+        _ => expr
+            .operands()
+            .into_iter()
+            .any(|e| depends_on_random(e, dict, program, visited)),
+    }
+}
+
+impl Program {
+    /// Script with the program (empty if the mode isn't enabled). The seed is
+    /// stored with the key of exercise
+    pub fn script(&self, key: &str) -> String {
+        if !self.enabled {
+            return String::new();
+        }
+
+        let definitions: Vec<String> = self
+            .definitions
+            .iter()
+            .map(|js| format!("    () => {},", js))
+            .collect();
+        let outputs: Vec<String> = self
+            .outputs
+            .iter()
+            .map(|js| format!("    () => {},", js))
+            .collect();
+
+        let factors: Vec<String> = prefixes()
+            .iter()
+            .map(|(prefix, factor)| format!("[{}, {}]", js_string(prefix), js_number(*factor)))
+            .collect();
+
+        format!(
+            "const seedKey = {};\nconst FACTORS = [{}];\nconst EPSILON = {};\n{}\nconst definitions = [\n{}\n];\nconst outputs = [\n{}\n];\n{}",
+            js_string(&format!("tweeproblems-seed-{}", key)),
+            factors.join(", "),
+            js_number(EPSILON),
+            RUNTIME,
+            definitions.join("\n"),
+            outputs.join("\n"),
+            REPLACE
+        )
+    }
+
+    /// expr is compiled to the script (browser mode and it depends on rand)
+    fn is_random(&self, expr: &Expression, dict: &DictVariables) -> bool {
+        self.enabled && depends_on_random(expr, dict, self, &mut Vec::new())
+    }
+
+    /// a value written in the output must be the same for every student
+    fn check_static(&self, expr: &Expression, dict: &DictVariables) {
+        if self.is_random(expr, dict) {
+            panic!(
+                "Expression {} depends on rand and it can't be evaluated in the browser",
+                expr.show()
+            );
+        }
+    }
+
+    fn placeholder(&mut self, js: String) -> String {
+        self.outputs.push(js);
+        format!("⟦{}⟧", self.outputs.len() - 1)
+    }
+
+    /// JavaScript of the value (number) of expr or None if it isn't supported
+    fn compile(&mut self, expr: &Expression, dict: &DictVariables) -> Option<String> {
+        let js = match expr {
+            Expression::Magnitude(magnitude) if magnitude.uncertainty == 0.0 => {
+                js_number(magnitude.value)
+            }
+            Expression::Variable(name) => {
+                let definition = dict.get(name)?;
+                // the last binding of name (equal definition)
+                let random = self
+                    .random
+                    .iter()
+                    .rev()
+                    .find(|(n, value, _)| n == name && value == definition);
+                match random {
+                    Some((_, _, index)) => format!("val({})", index),
+                    None => format!("val({})", self.variable(name, definition, dict)?),
+                }
+            }
+            Expression::Add(items) => format!("({})", self.compile_all(items, dict)?.join(" + ")),
+            Expression::Neg(expr) => format!("(-{})", self.compile(expr, dict)?),
+            Expression::Prod(items) => format!("({})", self.compile_all(items, dict)?.join(" * ")),
+            Expression::Div(items) => format!("({})", self.compile_all(items, dict)?.join(" / ")),
+            Expression::Unit(expr, _) => self.compile(expr, dict)?,
+            Expression::Convert(expr, unit, units) => {
+                let (factor, offset) = units.conversion(&expr.value(dict).unit, unit)?;
+                format!(
+                    "({} * {} + {})",
+                    self.compile(expr, dict)?,
+                    js_number(factor),
+                    js_number(offset)
+                )
+            }
+            Expression::Sqrt(expr) => format!("Math.sqrt({})", self.compile(expr, dict)?),
+            Expression::Log(expr) => format!("Math.log10({})", self.compile(expr, dict)?),
+            Expression::Ln(expr) => format!("Math.log({})", self.compile(expr, dict)?),
+            Expression::Rand(items, _) => {
+                let limits = self.compile_all(items, dict)?;
+                self.rands += 1;
+                format!("rand({}, {}, {})", self.rands, limits[0], limits[1])
+            }
+            Expression::Pow(items) => {
+                format!("Math.pow({})", self.compile_all(items, dict)?.join(", "))
+            }
+            Expression::And(items) => self.fold("and", "1", items, dict)?,
+            Expression::Or(items) => self.fold("or", "0", items, dict)?,
+            Expression::Not(expr) => format!("({} == 1 ? 0 : 1)", self.compile(expr, dict)?),
+            Expression::Eq(items) => self.relation("==", items, dict)?,
+            Expression::Neq(items) => self.relation("!=", items, dict)?,
+            Expression::Le(items) => self.relation("<", items, dict)?,
+            Expression::Leq(items) => self.relation("<=", items, dict)?,
+            Expression::Ge(items) => self.relation(">", items, dict)?,
+            Expression::Geq(items) => self.relation(">=", items, dict)?,
+            Expression::Cases(items) => {
+                let compiled = self.compile_all(items, dict)?;
+                let mut js = compiled[compiled.len() - 1].clone();
+                for pair in compiled.chunks(2).filter(|pair| pair.len() == 2).rev() {
+                    js = format!("({} == 1 ? {} : {})", pair[1], pair[0], js);
+                }
+                js
+            }
+            Expression::Fact(expr) => format!("fact({})", self.compile(expr, dict)?),
+            Expression::Comb(items) => {
+                format!("comb({})", self.compile_all(items, dict)?.join(", "))
+            }
+            Expression::Perm(items) => {
+                format!("perm({})", self.compile_all(items, dict)?.join(", "))
+            }
+            _ => return None,
+        };
+        Some(js)
+    }
+
+    fn compile_all(&mut self, items: &[Expression], dict: &DictVariables) -> Option<Vec<String>> {
+        items.iter().map(|item| self.compile(item, dict)).collect()
+    }
+
+    /// index of the variable in script (its value is computed once: rand)
+    fn variable(
+        &mut self,
+        name: &str,
+        definition: &Expression,
+        dict: &DictVariables,
+    ) -> Option<usize> {
+        let key = (name.to_string(), definition.clone());
+        if let Some(index) = self.variables.iter().position(|v| *v == key) {
+            return Some(index);
+        }
+
+        let js = self.compile(definition, dict)?;
+        self.variables.push(key);
+        self.definitions.push(js);
+        Some(self.definitions.len() - 1)
+    }
+
+    fn fold(
+        &mut self,
+        function: &str,
+        start: &str,
+        items: &[Expression],
+        dict: &DictVariables,
+    ) -> Option<String> {
+        let compiled = self.compile_all(items, dict)?;
+        Some(compiled.into_iter().fold(start.to_string(), |acc, js| {
+            format!("{}({}, {})", function, acc, js)
+        }))
+    }
+
+    fn relation(
+        &mut self,
+        operator: &str,
+        items: &[Expression],
+        dict: &DictVariables,
+    ) -> Option<String> {
+        let compiled = self.compile_all(items, dict)?;
+        Some(format!(
+            "chain((a, b) => a {} b, [{}])",
+            operator,
+            compiled.join(", ")
+        ))
+    }
+}
+
+fn js_number(value: f64) -> String {
+    format!("{:?}", value)
+}
+
+fn js_string(string: &str) -> String {
+    format!("\"{}\"", string.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn js_format(format: &Format) -> String {
+    let (digits, n) = match format.digits {
+        Digits::Round(n) => ("round", n),
+        Digits::Decimals(n) => ("decimals", n),
+        Digits::Significant(n) => ("sig", n),
+    };
+    let notation = match format.notation {
+        Notation::Fixed => "fixed",
+        Notation::Scientific => "scientific",
+        Notation::Engineering => "engineering",
+    };
+    format!(
+        "{{digits: \"{}\", n: {}, notation: \"{}\", prefix: {}, decimal: {}, product: {}}}",
+        digits,
+        n,
+        notation,
+        format.prefix,
+        js_string(format.decimal_latex()),
+        js_string(format.product_latex())
+    )
+}
+
+// Functions used by the compiled expressions (see Magnitude::show; FACTORS
+// and EPSILON are written before)
+const RUNTIME: &str = r#"let seed = parseInt(localStorage.getItem(seedKey));
+if (isNaN(seed)) {
+    seed = Math.floor(Math.random() * 4294967296);
+    localStorage.setItem(seedKey, seed);
+}
+
+// mulberry32 of seed and the number of rand operator
+function rand(id, min, max) {
+    let t = (seed + Math.imul(id, 0x6D2B79F5)) >>> 0;
+    t = Math.imul(t ^ (t >>> 15), t | 1);
+    t ^= t + Math.imul(t ^ (t >>> 7), t | 61);
+    const r = ((t ^ (t >>> 14)) >>> 0) / 4294967296;
+    return (max - min) * r + min;
+}
+
+const memo = [];
+function val(i) {
+    if (!(i in memo)) {
+        memo[i] = definitions[i]();
+    }
+    return memo[i];
+}
+
+function and(a, b) { return a == 1 ? b : a; }
+function or(a, b) { return a != 1 ? b : a; }
+function chain(relation, values) {
+    for (let i = 1; i < values.length; i++) {
+        if (!relation(values[i - 1], values[i])) {
+            return 0;
+        }
+    }
+    return 1;
+}
+function falling(n, r) {
+    let p = 1;
+    for (let i = 0; i < r; i++) {
+        p *= n - i;
+    }
+    return p;
+}
+function fact(n) { return falling(n, n); }
+function perm(n, r) { return r > n ? 0 : falling(n, r); }
+function comb(n, r) { return r > n ? 0 : Math.round(falling(n, Math.min(r, n - r)) / fact(Math.min(r, n - r))); }
+
+function order(x) { return Math.floor(Math.log10(x) + EPSILON); }
+function factor(prefix) { return FACTORS.find(f => f[0] == prefix)[1]; }
+function scale(x, hasPrefix, f) {
+    if (x == 0) {
+        return [x, "", 0];
+    }
+    if (f.prefix && hasPrefix) {
+        for (const [prefix, k] of FACTORS) {
+            const scaled = x / k;
+            if (scaled >= 1 - EPSILON && scaled < 1e3 * (1 - EPSILON)) {
+                return [scaled, prefix, 0];
+            }
+        }
+    }
+    const e = f.notation == "fixed" ? 0
+        : f.notation == "scientific" ? order(x) : 3 * Math.floor(order(x) / 3);
+    return [x / Math.pow(10, e), "", e];
+}
+function decimals(x, f) {
+    if (f.digits != "sig") {
+        return f.n;
+    }
+    return x == 0 ? f.n - 1 : Math.max(0, f.n - 1 - order(x));
+}
+function round(x, f) {
+    if (f.digits == "sig" && x == 0) {
+        return x;
+    }
+    const k = Math.pow(10, f.digits == "sig" ? f.n - 1 - order(x) : f.n);
+    return Math.round(x * k) / k;
+}
+function digits(x, f) { return f.digits == "round" ? String(x) : x.toFixed(decimals(x, f)); }
+
+// LaTeX of magnitude
+function show(x, unit, hasPrefix, f) {
+    const sign = x < 0 ? "-" : "";
+    const abs = Math.abs(x);
+    let number;
+    if (!isFinite(abs)) {
+        number = sign + (isNaN(abs) ? "NaN" : "\\infty");
+        return unit ? number + "\\mathrm{" + unit + "}" : number;
+    }
+
+    if (abs != 0 && f.notation == "fixed") {
+        const [n, prefix, e] = scale(abs, hasPrefix, f);
+        if (round(n, f) == 0) {
+            f = Object.assign({}, f, {notation: "scientific"});
+        }
+    }
+    let [n, prefix, e] = scale(abs, hasPrefix, f);
+    [n, prefix, e] = scale(round(n, f) * factor(prefix) * Math.pow(10, e), hasPrefix, f);
+    number = sign + digits(round(n, f), f);
+    number = number.replace(".", f.decimal);
+    if (e != 0) {
+        number += " " + f.product + " 10^{" + e + "}";
+    }
+    return unit ? number + "\\mathrm{" + prefix + unit + "}" : number;
+}
+"#;
+
+// Replaces the placeholders in the text of root (before MathJax)
+const REPLACE: &str = r#"function fillPlaceholders(root) {
+    const walker = document.createTreeWalker(root, NodeFilter.SHOW_TEXT);
+    for (let node = walker.nextNode(); node; node = walker.nextNode()) {
+        if (node.nodeValue.includes("⟦")) {
+            node.nodeValue = node.nodeValue.replace(/⟦(\d+)⟧/g, (_, i) => outputs[i]());
+        }
+    }
+}
+"#;
@@ -0,0 +1,403 @@
+// Batch build (command "build")
+//
+// Builds the exercises of directories (or globs of files) into an output
+// tree, several at the same time. A manifest in the output directory keeps
+// a hash of each exercise with its macros and units files (and the
+// executable), so unchanged exercises are skipped. An index page links
+// every built exercise.
+//
+
+use anyhow::{bail, Result};
+use cityhash::cityhash_1_1_1::city_hash_64;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::exercise::{load_document, Exercise};
+use crate::figure;
+use crate::macros;
+use crate::render::Render;
+use crate::render_reveal::{escape, Reveal};
+use crate::sourcemap;
+
+const MANIFEST: &str = ".tweeproblems-manifest";
+
+struct Job {
+    input: PathBuf,
+    output: PathBuf, // relative to output directory
+    hash: u64,
+}
+
+// exercises (input, path relative to output directory)
+pub type Inputs = Vec<(PathBuf, PathBuf)>;
+
+// output -> (hash, title)
+type Manifest = BTreeMap<PathBuf, (u64, String)>;
+
+// Result of a build: outputs (relative to output directory)
+pub struct Report {
+    pub built: Vec<PathBuf>,
+    pub failed: usize,
+}
+
+pub fn run(
+    patterns: Vec<String>,
+    output_dir: &Path,
+    paths: Vec<String>,
+    jobs: usize,
+) -> Result<()> {
+    let report = build(&patterns, output_dir, &paths, jobs)?;
+    if report.failed > 0 {
+        bail!("{} exercises failed", report.failed);
+    }
+    Ok(())
+}
+
+/// Builds the changed exercises of patterns
+pub fn build(
+    patterns: &[String],
+    output_dir: &Path,
+    paths: &[String],
+    jobs: usize,
+) -> Result<Report> {
+    let (inputs, mut rejected) = reject_collisions(collect_all_inputs(patterns)?);
+
+    let executable = match std::env::current_exe().and_then(fs::read) {
+        Ok(bytes) => city_hash_64(&bytes),
+        Err(_) => 0,
+    };
+
+    let mut manifest = load_manifest(output_dir);
+    let mut pending = vec![];
+    let mut unchanged = 0;
+    for (input, relative) in inputs {
+        let output = Reveal::new().generate_output_filename(
+            relative.parent().unwrap_or_else(|| Path::new("")),
+            &relative,
+        );
+        // an exercise whose files can't be read is reported as failed
+        let hash = match hash_exercise(&input, paths, executable) {
+            Ok(hash) => hash,
+            Err(e) => {
+                rejected.push((input, e.to_string()));
+                continue;
+            }
+        };
+        match manifest.get(&output) {
+            Some((old, _)) if *old == hash && output_dir.join(&output).exists() => {
+                println!("unchanged: {:?}", input);
+                unchanged += 1;
+            }
+            _ => pending.push(Job {
+                input,
+                output,
+                hash,
+            }),
+        }
+    }
+
+    let queue = Mutex::new(pending.into_iter());
+    let results = Mutex::new(vec![]);
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| loop {
+                let job = match queue.lock().unwrap().next() {
+                    Some(job) => job,
+                    None => break,
+                };
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    build_exercise(&job.input, &output_dir.join(&job.output), paths)
+                }));
+                let result = match result {
+                    Ok(result) => result.map_err(|e| e.to_string().trim().to_string()),
+                    Err(e) => Err(match e.downcast_ref::<String>() {
+                        Some(message) => message.clone(),
+                        None => e.downcast_ref::<&str>().unwrap_or(&"panicked").to_string(),
+                    }),
+                };
+                results.lock().unwrap().push((job, result));
+            });
+        }
+    });
+
+    let mut built = vec![];
+    let mut failed = rejected.len();
+    for (input, e) in rejected {
+        println!("FAILED: {:?}: {}", input, e);
+    }
+    let mut results = results.into_inner().unwrap();
+    results.sort_by(|(a, _), (b, _)| a.input.cmp(&b.input));
+    for (job, result) in results {
+        match result {
+            Ok(title) => {
+                println!(
+                    "built: {:?} -> {:?}",
+                    job.input,
+                    output_dir.join(&job.output)
+                );
+                manifest.insert(job.output.clone(), (job.hash, title));
+                built.push(job.output);
+            }
+            Err(e) => {
+                println!("FAILED: {:?}: {}", job.input, e);
+                manifest.remove(&job.output);
+                failed += 1;
+            }
+        }
+    }
+
+    manifest.retain(|output, _| output_dir.join(output).exists());
+    save_manifest(output_dir, &manifest)?;
+    fs::write(output_dir.join("index.html"), index(&manifest))?;
+
+    println!(
+        "{} built, {} unchanged, {} failed",
+        built.len(),
+        unchanged,
+        failed
+    );
+    Ok(Report { built, failed })
+}
+
+/// Hash of the exercises of patterns with their macros and units files
+/// (it changes when any of them changes)
+pub fn fingerprint(patterns: &[String], paths: &[String]) -> Result<u64> {
+    let mut data = vec![];
+    for (input, _) in collect_all_inputs(patterns)? {
+        data.extend(input.to_string_lossy().as_bytes());
+        // the error of an exercise that can't be hashed (build reports it)
+        match hash_exercise(&input, paths, 0) {
+            Ok(hash) => data.extend(&hash.to_le_bytes()),
+            Err(e) => data.extend(e.to_string().as_bytes()),
+        }
+    }
+    Ok(city_hash_64(&data))
+}
+
+fn build_exercise(input: &Path, output: &Path, paths: &[String]) -> Result<String> {
+    let exercise = Exercise::load_exercise(input, paths.to_vec())?;
+    let mut reveal = Reveal::new();
+    let (render, context) = exercise.render(&mut reveal);
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(output, render)?;
+    figure::copy_figures(output, &context.figures)?;
+    sourcemap::write(output, input, &exercise, &reveal, &context.passages)?;
+    Ok(exercise.title)
+}
+
+//--------------------------------------------
+// Inputs
+
+/// Exercises (input, path relative to output directory) of patterns
+pub fn collect_all_inputs(patterns: &[String]) -> Result<Inputs> {
+    let mut inputs = vec![];
+    for pattern in patterns {
+        inputs.extend(collect_inputs(pattern)?);
+    }
+    if inputs.is_empty() {
+        bail!("No exercises in {:?}", patterns);
+    }
+    Ok(inputs)
+}
+
+/// Inputs without the ones with the same output as other (test.yaml and
+/// test.twp -> test.html), with the failures of those ones
+pub fn reject_collisions(inputs: Inputs) -> (Inputs, Vec<(PathBuf, String)>) {
+    let mut outputs: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for (input, relative) in &inputs {
+        outputs
+            .entry(relative.with_extension(""))
+            .or_default()
+            .push(input.clone());
+    }
+
+    let mut failures = vec![];
+    let inputs = inputs
+        .into_iter()
+        .filter(|(input, relative)| {
+            let same = &outputs[&relative.with_extension("")];
+            if same.len() > 1 {
+                let others: Vec<&PathBuf> = same.iter().filter(|other| *other != input).collect();
+                failures.push((input.clone(), format!("same output as {:?}", others)));
+            }
+            same.len() == 1
+        })
+        .collect();
+    (inputs, failures)
+}
+
+// exercises (input, path relative to output directory) of a directory
+// (recursively), a glob (* and ? in file name) or a file
+fn collect_inputs(pattern: &str) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let path = Path::new(pattern);
+    let mut output = vec![];
+
+    if path.is_dir() {
+        let mut files = vec![];
+        walk(path, &mut files)?;
+        for file in files {
+            if is_exercise(&file) {
+                let relative = file.strip_prefix(path).unwrap().to_path_buf();
+                output.push((file, relative));
+            }
+        }
+    } else if pattern.contains(&['*', '?'][..]) {
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let glob = path.file_name().unwrap().to_string_lossy().to_string();
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|file| matches(&glob, &file.file_name().unwrap().to_string_lossy()))
+            .filter(|file| is_exercise(file))
+            .collect();
+        files.sort();
+        for file in files {
+            let relative = PathBuf::from(file.file_name().unwrap());
+            output.push((file, relative));
+        }
+    } else if path.is_file() {
+        output.push((path.to_path_buf(), PathBuf::from(path.file_name().unwrap())));
+    } else {
+        bail!("{:?} isn't a directory, a glob or a file", pattern);
+    }
+    Ok(output)
+}
+
+fn walk(dir: &Path, output: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect();
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            walk(&entry, output)?;
+        } else if entry
+            .extension()
+            .is_some_and(|ext| ext == "yaml" || ext == "twp")
+        {
+            output.push(entry);
+        }
+    }
+    Ok(())
+}
+
+// yaml or twp files with passages (not macros or units files). Files that
+// can't be parsed are exercises (they fail when they are built).
+fn is_exercise(file: &Path) -> bool {
+    let extension = file.extension();
+    if extension != Some(OsStr::new("yaml")) && extension != Some(OsStr::new("twp")) {
+        return false;
+    }
+    if fs::read_to_string(file).is_ok_and(|contents| contents.trim().is_empty()) {
+        return false;
+    }
+    match load_document(file) {
+        Ok(doc) => !doc["passages"].is_badvalue(),
+        Err(_) => true,
+    }
+}
+
+// glob with * (any string) and ? (any char)
+fn matches(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    fn matches_from(glob: &[char], name: &[char]) -> bool {
+        match glob.first() {
+            None => name.is_empty(),
+            Some('*') => (0..=name.len()).any(|i| matches_from(&glob[1..], &name[i..])),
+            Some('?') => !name.is_empty() && matches_from(&glob[1..], &name[1..]),
+            Some(c) => name.first() == Some(c) && matches_from(&glob[1..], &name[1..]),
+        }
+    }
+    matches_from(&glob, &name)
+}
+
+//--------------------------------------------
+// Manifest
+
+// hash of the exercise, its macros and units files and the executable
+fn hash_exercise(input: &Path, paths: &[String], executable: u64) -> Result<u64> {
+    let contents = fs::read_to_string(input)?;
+    let mut data = format!("{:x}\n{}", executable, contents).into_bytes();
+
+    for file in macros::exercise_dependencies(input, paths)? {
+        data.extend(file.to_string_lossy().as_bytes());
+        data.extend(fs::read(&file)?);
+    }
+    Ok(city_hash_64(&data))
+}
+
+// lines: hash, output and title separated by tabs
+fn load_manifest(output_dir: &Path) -> Manifest {
+    let contents = fs::read_to_string(output_dir.join(MANIFEST)).unwrap_or_default();
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let hash = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let output = PathBuf::from(fields.next()?);
+            let title = fields.next()?.to_string();
+            Some((output, (hash, title)))
+        })
+        .collect()
+}
+
+fn save_manifest(output_dir: &Path, manifest: &Manifest) -> Result<()> {
+    let mut contents = String::new();
+    for (output, (hash, title)) in manifest {
+        contents.push_str(&format!(
+            "{:016x}\t{}\t{}\n",
+            hash,
+            output.to_string_lossy(),
+            title.replace(['\t', '\n'], " ")
+        ));
+    }
+    fs::create_dir_all(output_dir)?;
+    fs::write(output_dir.join(MANIFEST), contents)?;
+    Ok(())
+}
+
+//--------------------------------------------
+// Index
+
+fn index(manifest: &Manifest) -> String {
+    let items: String = manifest
+        .iter()
+        .map(|(output, (_, title))| {
+            format!(
+                "            <li><a href=\"{}\">{}</a></li>\n",
+                escape(&output.to_string_lossy()),
+                escape(title)
+            )
+        })
+        .collect();
+
+    format!(
+        r#"<!doctype html>
+<html>
+    <head>
+        <meta charset="utf-8">
+        <title>Exercises</title>
+    </head>
+    <body>
+        <h1>Exercises</h1>
+        <ul>
+{}        </ul>
+    </body>
+</html>
+"#,
+        items
+    )
+}
@@ -0,0 +1,142 @@
+// Catalogue of exercises (command "catalog")
+//
+// Lists the exercises of directories (or globs of files) with their
+// metadata in a page with a search box (catalog.html) and in JSON
+// (catalog.json). The links point to the pages written by "build" in the
+// same output directory.
+//
+
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::build;
+use crate::exercise::load_document;
+use crate::metadata::Metadata;
+use crate::render::Render;
+use crate::render_reveal::{escape, Reveal};
+
+struct Entry {
+    source: PathBuf,
+    page: PathBuf, // relative to output directory
+    title: String,
+    metadata: Metadata,
+}
+
+pub fn run(patterns: Vec<String>, output_dir: &Path) -> Result<()> {
+    let mut entries = vec![];
+    let (inputs, collisions) = build::reject_collisions(build::collect_all_inputs(&patterns)?);
+    for (input, e) in collisions {
+        println!("FAILED: {:?}: {}", input, e);
+    }
+    for (input, relative) in inputs {
+        let doc = match load_document(&input) {
+            Ok(doc) => doc,
+            Err(e) => {
+                println!("FAILED: {:?}: {}", input, e);
+                continue;
+            }
+        };
+        let page = Reveal::new().generate_output_filename(
+            relative.parent().unwrap_or_else(|| Path::new("")),
+            &relative,
+        );
+        entries.push(Entry {
+            title: doc["title"]
+                .as_str()
+                .map_or_else(|| page.to_string_lossy().to_string(), str::to_string),
+            metadata: Metadata::from_yaml(&doc),
+            source: input,
+            page,
+        });
+    }
+
+    fs::create_dir_all(output_dir)?;
+    fs::write(output_dir.join("catalog.json"), json(&entries))?;
+    fs::write(output_dir.join("catalog.html"), html(&entries))?;
+    println!(
+        "{} exercises: {:?}",
+        entries.len(),
+        output_dir.join("catalog.html")
+    );
+    Ok(())
+}
+
+fn json(entries: &[Entry]) -> String {
+    let entries: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "  {}",
+                entry.metadata.json(&[
+                    ("title", &entry.title),
+                    ("page", &entry.page.to_string_lossy()),
+                    ("source", &entry.source.to_string_lossy()),
+                ])
+            )
+        })
+        .collect();
+    if entries.is_empty() {
+        String::from("[]\n")
+    } else {
+        format!("[\n{}\n]\n", entries.join(",\n"))
+    }
+}
+
+fn html(entries: &[Entry]) -> String {
+    let rows: String = entries
+        .iter()
+        .map(|entry| {
+            let metadata = &entry.metadata;
+            let cells: Vec<String> = metadata
+                .fields()
+                .iter()
+                .map(|(_, value)| format!("<td>{}</td>", escape(value.unwrap_or(""))))
+                .collect();
+            format!(
+                "            <tr><td><a href=\"{}\">{}</a></td>{}<td>{}</td><td>{}</td></tr>\n",
+                escape(&entry.page.to_string_lossy()),
+                escape(&entry.title),
+                cells.join(""),
+                escape(&metadata.tags.join(", ")),
+                escape(&metadata.prerequisites.join(", "))
+            )
+        })
+        .collect();
+
+    format!(
+        r#"<!doctype html>
+<html>
+    <head>
+        <meta charset="utf-8">
+        <title>Catalogue of exercises</title>
+        <style>
+            table {{ border-collapse: collapse; }}
+            th, td {{ border-bottom: 1px solid #ccc; padding: 4px 8px; text-align: left; }}
+        </style>
+    </head>
+    <body>
+        <h1>Catalogue of exercises</h1>
+        <p><input id="search" type="search" placeholder="Search" size="40"></p>
+        <table>
+            <thead><tr><th>Title</th><th>Author</th><th>Course</th><th>Difficulty</th><th>Time</th><th>Language</th><th>License</th><th>Tags</th><th>Prerequisites</th></tr></thead>
+            <tbody>
+{}            </tbody>
+        </table>
+        <script>
+            // rows with all the words of the search
+            const search = document.getElementById("search");
+            search.addEventListener("input", () => {{
+                const words = search.value.toLowerCase().split(/\s+/).filter(word => word);
+                for (const row of document.querySelectorAll("tbody tr")) {{
+                    const text = row.textContent.toLowerCase();
+                    row.hidden = !words.every(word => text.includes(word));
+                }}
+            }});
+        </script>
+    </body>
+</html>
+"#,
+        rows
+    )
+}
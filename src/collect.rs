@@ -0,0 +1,129 @@
+// Collector of the events of analytics (command "collect")
+//
+// Receives the events posted by the exercises (see analytics) over HTTP and
+// appends them to a JSON lines file (one event for line, with the time of
+// reception in ms since the epoch in "received"). Events that aren't JSON
+// objects, or whose type or fields aren't the ones of analytics, are
+// rejected. The command "report" summarizes them.
+//
+
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// maximum size of an event
+const MAX_EVENT: usize = 64 * 1024;
+
+pub fn run(output: &Path, port: u16) -> Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(output)?;
+    let file = Arc::new(Mutex::new(file));
+
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!(
+        "collecting events at http://127.0.0.1:{}/ into {:?}",
+        port, output
+    );
+
+    for stream in listener.incoming().flatten() {
+        let file = file.clone();
+        thread::spawn(move || handle(stream, &file));
+    }
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, file: &Mutex<File>) {
+    let status = match receive(&stream) {
+        Ok(None) => "204 No Content", // preflight of CORS
+        Ok(Some(event)) => {
+            let mut file = file.lock().unwrap();
+            match writeln!(file, "{}", event) {
+                Ok(()) => "204 No Content",
+                Err(e) => {
+                    println!("Unable to store event: {}", e);
+                    "500 Internal Server Error"
+                }
+            }
+        }
+        Err(e) => {
+            println!("Bad event: {}", e);
+            "400 Bad Request"
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: POST, OPTIONS\r\nAccess-Control-Allow-Headers: Content-Type\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    let _ = stream.write_all(response.as_bytes());
+}
+
+// event of a request (with the time of reception), None if it isn't a POST
+fn receive(stream: &TcpStream) -> Result<Option<String>> {
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    let mut length = 0;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse()?;
+            }
+        }
+        line.clear();
+    }
+
+    if !request.starts_with("POST ") {
+        return Ok(None);
+    }
+    if length > MAX_EVENT {
+        bail!("{} bytes", length);
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    let mut event = match serde_json::from_slice(&body)? {
+        Value::Object(event) => event,
+        body => bail!("{} isn't a JSON object", body),
+    };
+    check(&event)?;
+
+    let received = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    event.insert(String::from("received"), Value::from(received as u64));
+    Ok(Some(serde_json::to_string(&event)?))
+}
+
+// checks the fields of an event (as the ones posted by analytics)
+fn check(event: &Map<String, Value>) -> Result<()> {
+    const TYPES: &[&str] = &["session", "passage", "step", "choice", "hint", "complete"];
+    const STRINGS: &[&str] = &[
+        "type", "exercise", "title", "learner", "session", "time", "passage", "kind", "target",
+    ];
+
+    match event.get("type").and_then(Value::as_str) {
+        Some(kind) if TYPES.contains(&kind) => {}
+        kind => bail!("Unknown type of event {:?}", kind),
+    }
+    if !event.contains_key("exercise") {
+        bail!("Event without exercise");
+    }
+    for (name, value) in event {
+        let valid = match name.as_str() {
+            name if STRINGS.contains(&name) => value.is_string(),
+            "good" => value.is_boolean(),
+            "duration" => value.is_number(),
+            "received" => false,
+            _ => true,
+        };
+        if !valid {
+            bail!("Bad field {:?}: {}", name, value);
+        }
+    }
+    Ok(())
+}
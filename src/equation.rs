@@ -0,0 +1,188 @@
+// Equations with one unknown
+//
+// Expression::Root(lhs rhs start(s), unknown) is the value of unknown that
+// makes lhs = rhs. The value is found numerically:
+//   * root (one start): secant method from the initial guess
+//   * bisect (two starts): bisection in bracket [min, max] (lhs - rhs must
+//     change its sign in bracket)
+// The unit of solution is the unit of the start values.
+//
+
+use crate::expression::{DictVariables, Expression};
+use crate::magnitude::Magnitude;
+
+// maximum number of iterations
+const ITERATIONS: usize = 200;
+// relative tolerance of solution
+const TOLERANCE: f64 = 1e-12;
+
+impl Expression {
+    pub fn solve_equation(
+        &self,
+        operands: &[Expression],
+        unknown: &str,
+        dict: &DictVariables,
+    ) -> Magnitude {
+        let starts: Vec<Magnitude> = operands[2..].iter().map(|e| e.value(dict)).collect();
+        let unit = match &starts[..] {
+            [start] => start.unit.clone(),
+            [min, max] => min
+                .compatible_unit(max)
+                .unwrap_or_else(|| panic!("Bracket of {} with different units", unknown)),
+            _ => panic!("Wrong starts of equation {:?}", self),
+        };
+
+        // lhs - rhs with unknown = x
+        let f = |x: f64| -> f64 {
+            let mut dict = dict.clone();
+            let magnitude = Magnitude {
+                value: x,
+                uncertainty: 0.0,
+                unit: unit.clone(),
+                rational: None,
+            };
+            dict.insert(unknown.to_string(), Expression::Magnitude(magnitude));
+
+            let lhs = operands[0].value(&dict);
+            let rhs = operands[1].value(&dict);
+            lhs.compatible_unit(&rhs)
+                .unwrap_or_else(|| panic!("Wrong units in equation: {:?} = {:?}", lhs, rhs));
+            lhs.value - rhs.value
+        };
+
+        let solution = match &starts[..] {
+            [start] => secant(f, start.value),
+            [min, max] => bisection(f, min.value, max.value),
+            _ => None,
+        };
+
+        match solution {
+            Some(value) => Magnitude {
+                value,
+                uncertainty: 0.0,
+                unit,
+                rational: None,
+            },
+            None => panic!("No solution found for {} in {}", unknown, self.show()),
+        }
+    }
+}
+
+fn converged(a: f64, b: f64) -> bool {
+    (a - b).abs() <= TOLERANCE * a.abs().max(b.abs())
+}
+
+fn secant(f: impl Fn(f64) -> f64, start: f64) -> Option<f64> {
+    let mut x0 = start;
+    let mut x1 = if start == 0.0 {
+        1e-4
+    } else {
+        start * (1.0 + 1e-4)
+    };
+    let mut f0 = f(x0);
+    let mut f1 = f(x1);
+    let f_start = f0.abs();
+
+    for _ in 0..ITERATIONS {
+        if f1 == 0.0 {
+            return Some(x1);
+        }
+        if f1 == f0 || !f1.is_finite() {
+            return None;
+        }
+        let x2 = x1 - f1 * (x1 - x0) / (f1 - f0);
+        if converged(x1, x2) {
+            return Some(x2);
+        }
+        x0 = x1;
+        f0 = f1;
+        x1 = x2;
+        f1 = f(x1);
+    }
+
+    // slow convergence (multiple roots): accepted if lhs - rhs is small enough
+    if f1.abs() <= TOLERANCE * f_start {
+        Some(x1)
+    } else {
+        None
+    }
+}
+
+fn bisection(f: impl Fn(f64) -> f64, min: f64, max: f64) -> Option<f64> {
+    let (mut a, mut b) = (min, max);
+    let mut fa = f(a);
+    let fb = f(b);
+
+    if fa == 0.0 {
+        return Some(a);
+    }
+    if fb == 0.0 {
+        return Some(b);
+    }
+    if fa.signum() == fb.signum() {
+        return None;
+    }
+
+    for _ in 0..ITERATIONS {
+        let middle = (a + b) / 2.0;
+        let fm = f(middle);
+        if fm == 0.0 || converged(a, b) {
+            return Some(middle);
+        }
+        if fm.signum() == fa.signum() {
+            a = middle;
+            fa = fm;
+        } else {
+            b = middle;
+        }
+    }
+
+    Some((a + b) / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::macros::Macros;
+
+    fn solve(rpn: &str, dict: &[(&str, &str)]) -> Magnitude {
+        let macros = Macros::new();
+        let dict: DictVariables = dict
+            .iter()
+            .map(|(name, rpn)| (name.to_string(), Expression::from(rpn, &macros)))
+            .collect();
+        Expression::from(rpn, &macros).value(&dict)
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() <= 1e-9 * b.abs().max(1.0), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn linear_implicit_relation() {
+        assert_close(solve("2 x * 3 + 7 x 0 root", &[]).value, 2.0);
+
+        // V = R I (the unknown I is on the right side)
+        let current = solve("V R I * I 1mA root", &[("V", "10V"), ("R", "2kohm")]);
+        assert_close(current.value, 0.005);
+        assert_eq!(current.unit, "A");
+    }
+
+    #[test]
+    fn nonlinear_equations() {
+        // V = R I + I^2 100 ohm (example of the README)
+        let current = solve(
+            "V R I * I 2 pow 100ohm * V : + I 1mA root",
+            &[("V", "10V"), ("R", "1kohm")],
+        );
+        assert_close(current.value, (-1000.0 + (1e6f64 + 4000.0).sqrt()) / 200.0);
+
+        assert_close(solve("x x * 2 x 0 2 bisect", &[]).value, 2f64.sqrt());
+    }
+
+    #[test]
+    #[should_panic(expected = "No solution found for x")]
+    fn bracket_without_change_of_sign() {
+        solve("x x * 2 x 2 3 bisect", &[]);
+    }
+}
@@ -1,26 +1,37 @@
 use anyhow::{bail, Result};
+use cityhash::cityhash_1_1_1::{city_hash_128, city_hash_64};
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
-use yaml_rust::YamlLoader;
+use yaml_rust::{Yaml, YamlLoader};
 
+use crate::browser;
 use crate::expression::DictVariables;
+use crate::format::Format;
 use crate::macros::Macros;
+use crate::metadata::Metadata;
 use crate::passage::{is_macros, PassageTitle, PassageTree};
-use crate::render::Render;
+use crate::random;
+use crate::render::{Context, Render};
+use crate::sourcemap::Lines;
+use crate::twp;
 
 #[derive(Clone)]
 pub struct Exercise {
     pub title: String,
-    pub uuid: Uuid,
+    pub metadata: Metadata,
+    pub uuid: Uuid, // id of the exercise (ifid in twee)
+    pub seed: u64,  // of rand and the order of options (see random)
     pub passage_tree: PassageTree,
+    pub script: String, // JavaScript to evaluate values in the browser
+    pub templates: HashMap<PathBuf, String>, // instantiated figures (see figure)
+    pub analytics: Option<String>, // url of the events of analytics
 }
 
 impl Exercise {
     pub fn load_exercise(file: &Path, paths: Vec<String>) -> Result<Exercise> {
-        let contents = fs::read_to_string(file).expect("Unable to read file");
-        let docs = YamlLoader::load_from_str(&contents).unwrap();
-        let doc = &docs[0];
+        let (doc, lines) = &load_document_lines(file)?;
 
         let variables = DictVariables::new();
 
@@ -34,10 +45,41 @@ impl Exercise {
         if let Some(macros_files) = is_macros("macros", doc) {
             macros.include_macros(macros_files);
         }
+        if let Some(units_files) = is_macros("units", doc) {
+            macros.include_units(units_files);
+        }
 
         let title = doc["title"].as_str().unwrap().to_owned();
+        let uuid = exercise_id(doc, file, &title)?;
+        let seed = city_hash_64(
+            format!("{}\n{}", uuid, scalar(&doc["seed"]).unwrap_or_default()).as_bytes(),
+        );
+        macros.rng = random::generator(seed);
+        let metadata = Metadata::from_yaml(doc);
+        let mut format = Format::from_yaml(&doc["format"]);
+        if let Some(numeric) = doc["numeric"].as_str() {
+            format = format.with_spec(&format!("numeric={}", numeric));
+        }
 
-        let mut passage_trees = PassageTree::from_yaml(&doc["passages"], &variables, &macros);
+        macros.program = browser::program(match doc["evaluation"].as_str() {
+            None | Some("build") => false,
+            Some("browser") => true,
+            Some(evaluation) => panic!("Unknown evaluation {:?} (build or browser)", evaluation),
+        });
+
+        let analytics = match &doc["analytics"] {
+            Yaml::BadValue | Yaml::Null => None,
+            Yaml::String(url) => Some(url.trim().to_string()),
+            url => panic!("The analytics url isn't a string: {:?}", url),
+        };
+
+        let mut passage_trees = PassageTree::from_yaml(
+            &doc["passages"],
+            lines.value("passages"),
+            &variables,
+            &macros,
+            &format,
+        );
 
         /*
         println!("\npassageTree: {:?}", passage_trees);
@@ -50,24 +92,92 @@ impl Exercise {
             bail!("\nThe document in file {:?} doesn't start with an passage (it starts with alternative or concurrent group)", file);
         }
 
-        let uuid = Uuid::new_v4();
+        let script = macros.program.borrow().script(&uuid.to_string());
+        let templates = macros.templates.take();
 
         Ok(Exercise {
             title,
+            metadata,
             uuid,
+            seed,
             passage_tree: passage_trees.pop().unwrap(),
+            script,
+            templates,
+            analytics,
         })
     }
 
-    pub fn render(&self, renderer: &mut dyn Render) -> String {
+    /// Output of the exercise and the context of its rendering (figures and
+    /// passages)
+    pub fn render(&self, renderer: &mut dyn Render) -> (String, Context) {
         let mut output = String::new();
+        let mut context = Context::new(self.seed, self.templates.clone());
         let passage_title = PassageTitle::new();
 
         output += &renderer.begin_exercise(self);
 
-        output += &self.passage_tree.render(renderer, "", &passage_title);
+        output += &self
+            .passage_tree
+            .render(renderer, &mut context, "", &passage_title);
 
         output += &renderer.end_exercise(self);
-        output
+        (output, context)
+    }
+}
+
+/// Yaml document of an exercise (yaml or twp file)
+pub fn load_document(file: &Path) -> Result<Yaml> {
+    Ok(load_document_lines(file)?.0)
+}
+
+/// Yaml document of an exercise (yaml or twp file) with the lines of its
+/// nodes in the file
+pub fn load_document_lines(file: &Path) -> Result<(Yaml, Lines)> {
+    let contents = fs::read_to_string(file)?;
+    if file.extension().is_some_and(|ext| ext == "twp") {
+        return twp::to_yaml(&contents);
+    }
+    match YamlLoader::load_from_str(&contents)?.into_iter().next() {
+        Some(doc) => Ok((doc, Lines::load(&contents)?)),
+        None => bail!("Empty file {:?}", file),
+    }
+}
+
+// id of the exercise: the key "id" (an uuid, or any string hashed into
+// one) or the hash of the name of the file and the title (so editing the
+// exercise doesn't change it)
+fn exercise_id(doc: &Yaml, file: &Path, title: &str) -> Result<Uuid> {
+    let id = match &doc["id"] {
+        Yaml::BadValue | Yaml::Null => {
+            if !doc["analytics"].is_badvalue() {
+                println!(
+                    "Warning: exercise {:?} with analytics and without id (its id changes if the file is renamed or its title changes)",
+                    file
+                );
+            }
+            let name = file.file_name().unwrap_or_default().to_string_lossy();
+            return Ok(hash_uuid(format!("{}\n{}", name, title).as_bytes()));
+        }
+        id => match scalar(id) {
+            Some(id) => id,
+            None => bail!("The id of the exercise {:?} isn't a string: {:?}", file, id),
+        },
+    };
+    Ok(Uuid::parse_str(&id).unwrap_or_else(|_| hash_uuid(id.as_bytes())))
+}
+
+// uuid with the hash of bytes (version 8: custom)
+fn hash_uuid(bytes: &[u8]) -> Uuid {
+    let mut bytes = city_hash_128(bytes).to_be_bytes();
+    bytes[6] = (bytes[6] & 0x0f) | 0x80;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Uuid::from_bytes(&bytes).unwrap()
+}
+
+fn scalar(yaml: &Yaml) -> Option<String> {
+    match yaml {
+        Yaml::String(value) => Some(value.trim().to_string()),
+        Yaml::Integer(value) => Some(value.to_string()),
+        _ => None,
     }
 }
@@ -2,11 +2,18 @@
 //
 
 use maplit::hashmap;
-use rand::random;
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 
+use crate::format::{DivisionStyle, Format, ProductStyle};
 use crate::macros::Macros;
 use crate::magnitude::{self, Magnitude};
+use crate::random;
+use crate::statistics::{combinations, factorial, permutations};
+use crate::units::Registry;
 
 pub type DictVariables = HashMap<String, Expression>;
 pub type Stack = Vec<Expression>;
@@ -14,20 +21,45 @@ pub type Argument = Box<Expression>;
 pub type Arguments = Vec<Expression>;
 pub type ArgumentsSlice = [Expression];
 
+// Precedence of operators in LaTeX (higher binds tighter)
+const PREC_OR: u8 = 1;
+const PREC_AND: u8 = 2;
+const PREC_REL: u8 = 3;
+const PREC_ADD: u8 = 4;
+const PREC_NEG: u8 = 5;
+const PREC_PROD: u8 = 6;
+const PREC_POW: u8 = 7;
+const PREC_ATOM: u8 = 8;
+
+// Operators over lists
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Sum,
+    Mean,
+    Median,
+    Stdev, // sample standard deviation (n - 1)
+    Var,   // sample variance (n - 1)
+    Count,
+    Min,
+    Max,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expression {
     Magnitude(Magnitude),
     Variable(String),
+    Unbound(String), // variable bound only in some paths (after alt or con)
     Add(Arguments),
     Neg(Argument),
     Prod(Arguments),
     Div(Arguments),
 
     Unit(Argument, String), // Assign Unit if unit = "¿?", verify unit if previus expression have unit
+    Convert(Argument, String, Rc<Registry>), // Convert value to unit with the units of its scope
     Sqrt(Argument),
     Log(Argument),
     Ln(Argument),
-    Rand(Arguments),
+    Rand(Arguments, u64), // limits and seed of value (see seed_rands)
     Pow(Arguments),
 
     And(Arguments),
@@ -39,6 +71,25 @@ pub enum Expression {
     Leq(Arguments),
     Ge(Arguments),
     Geq(Arguments),
+
+    Matrix(usize, usize, Arguments), // rows, columns and elements (by rows)
+    Element(Argument, Vec<usize>),   // indices from 1: [i] in vectors, [i, j] in matrices
+    Transpose(Argument),
+    Inverse(Argument),
+    Det(Argument),
+    Dot(Arguments),
+    Cross(Arguments),
+    Solve(Arguments), // A b -> x: A x = b
+
+    Root(Arguments, String), // lhs rhs start(s) unknown: value of unknown with lhs = rhs
+
+    Cases(Arguments), // v1 c1 v2 c2 ... default: first value with true condition
+
+    List(Arguments),
+    Aggregate(Aggregate, Argument),
+    Fact(Argument),
+    Comb(Arguments), // n r: combinations
+    Perm(Arguments), // n r: permutations
 }
 
 impl Expression {
@@ -57,6 +108,50 @@ impl Expression {
         }
     }
 
+    /// Stack effect of a macro body: the number of popped operands
+    /// (placeholders "$1", "$2", ... from the deepest one) and the pushed
+    /// results
+    pub fn stack_effect(string: &str, macros: &Macros) -> (usize, Stack) {
+        const DEPTH: usize = 32;
+        let placeholders: Stack = (1..=DEPTH)
+            .map(|i| Expression::Variable(format!("${}", i)))
+            .collect();
+        let mut stack = placeholders.clone();
+
+        Expression::inject(string, &mut stack, macros);
+
+        let index = |name: &str| name.strip_prefix('$').and_then(|i| i.parse::<usize>().ok());
+        let untouched = stack
+            .iter()
+            .zip(placeholders.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        // placeholders used by the results were popped too (dup: a -- a a)
+        let untouched = stack[untouched..]
+            .iter()
+            .flat_map(|result| result.free_variables())
+            .filter_map(|name| index(&name))
+            .fold(untouched, |untouched, i| untouched.min(i - 1));
+        let results = stack.split_off(untouched);
+
+        // renumber the popped placeholders from 1
+        fn renumber(expr: Expression, offset: usize) -> Expression {
+            match expr {
+                Expression::Variable(name) => match name.strip_prefix('$').map(str::parse::<usize>)
+                {
+                    Some(Ok(i)) if i > offset => Expression::Variable(format!("${}", i - offset)),
+                    _ => Expression::Variable(name),
+                },
+                _ => expr.map_operands(&|e| renumber(e, offset)),
+            }
+        }
+        let results = results
+            .into_iter()
+            .map(|result| renumber(result, untouched))
+            .collect();
+        (DEPTH - untouched, results)
+    }
+
     fn inject(string: &str, stack: &mut Stack, macros: &Macros) {
         let mut dictionary: DictVariables = hashmap! {};
 
@@ -72,7 +167,7 @@ impl Expression {
 
         for current in items {
             if current.is_empty() {
-            } else if let Some(magnitude) = Magnitude::get(current) {
+            } else if let Some(magnitude) = Magnitude::get(current, &macros.units) {
                 insert_magnitude(magnitude, stack);
             } else {
                 match current {
@@ -96,8 +191,9 @@ impl Expression {
                     "@" => from_dict(stack, &dictionary),
 
                     // units operators
-                    ":" => operator2(units, stack),
+                    ":" => operator2(|value, unit| units(value, unit, &macros.units), stack),
                     "::" => operator1(nounits, stack),
+                    "->" => operator2(|value, unit| convert(value, unit, &macros.units), stack),
 
                     // arithmetic operators
                     "+" => operator2(add_expression, stack),
@@ -125,6 +221,40 @@ impl Expression {
                     ">" => operator2(ge_expression, stack),
                     ">=" => operator2(geq_expression, stack),
 
+                    // vectors and matrices
+                    "vec" => vector_from_stack(stack),
+                    "mat" => matrix_from_stack(stack),
+                    "at" => element_from_stack(1, stack),
+                    "elem" => element_from_stack(2, stack),
+                    "trans" => operator1(transpose_expression, stack),
+                    "inv" => operator1(inverse_expression, stack),
+                    "det" => operator1(det_expression, stack),
+                    "dot" => operator2(dot_expression, stack),
+                    "cross" => operator2(cross_expression, stack),
+                    "solve" => operator2(solve_expression, stack),
+
+                    // equations
+                    "root" => root_from_stack(1, stack),
+                    "bisect" => root_from_stack(2, stack),
+
+                    // conditional values
+                    "if" => if_from_stack(stack),
+                    "piecewise" => piecewise_from_stack(stack),
+
+                    // lists, statistics and combinatorics
+                    "list" => list_from_stack(stack),
+                    "sum" => operator1(|e| aggregate(Aggregate::Sum, e), stack),
+                    "mean" => operator1(|e| aggregate(Aggregate::Mean, e), stack),
+                    "median" => operator1(|e| aggregate(Aggregate::Median, e), stack),
+                    "stdev" => operator1(|e| aggregate(Aggregate::Stdev, e), stack),
+                    "var" => operator1(|e| aggregate(Aggregate::Var, e), stack),
+                    "count" => operator1(|e| aggregate(Aggregate::Count, e), stack),
+                    "min" => operator1(|e| aggregate(Aggregate::Min, e), stack),
+                    "max" => operator1(|e| aggregate(Aggregate::Max, e), stack),
+                    "fact" => operator1(fact_expression, stack),
+                    "nCr" => operator2(comb_expression, stack),
+                    "nPr" => operator2(perm_expression, stack),
+
                     // macros and variables
                     _ => match macros.macros.get(current) {
                         Some(f) => {
@@ -139,65 +269,231 @@ impl Expression {
         }
     }
 
-    fn show_group(&self) -> String {
+    fn precedence(&self, format: &Format) -> u8 {
         match self {
-            Expression::Magnitude(..) => self.show(),
-            Expression::Variable(..) => self.show(),
-            Expression::Unit(..) => self.show(),
-            Expression::Sqrt(..) => self.show(),
-            Expression::Log(..) => self.show(),
-            Expression::Ln(..) => self.show(),
-            Expression::Rand(..) => self.show(),
-            Expression::Not(..) => self.show(),
-            _ => format!("( {} )", self.show()),
+            Expression::Or(..) => PREC_OR,
+            Expression::And(..) => PREC_AND,
+            Expression::Eq(..)
+            | Expression::Root(..)
+            | Expression::Neq(..)
+            | Expression::Le(..)
+            | Expression::Leq(..)
+            | Expression::Ge(..)
+            | Expression::Geq(..) => PREC_REL,
+            Expression::Add(..) => PREC_ADD,
+            Expression::Neg(..) => PREC_NEG,
+            Expression::Prod(..) => PREC_PROD,
+            Expression::Div(..) => match format.division {
+                DivisionStyle::Frac => PREC_ATOM,
+                DivisionStyle::Inline => PREC_PROD,
+            },
+            Expression::Pow(..) | Expression::Transpose(..) | Expression::Inverse(..) => PREC_POW,
+            Expression::Dot(..) | Expression::Cross(..) | Expression::Solve(..) => PREC_PROD,
+            Expression::Unit(expr, _) | Expression::Convert(expr, ..) => expr.precedence(format),
+            // -2 and 1.2 \cdot 10^{3} aren't atoms
+            Expression::Magnitude(magnitude) if magnitude.value < 0.0 => PREC_NEG,
+            Expression::Magnitude(magnitude) if magnitude.show(format).contains("10^{") => {
+                PREC_PROD
+            }
+            _ => PREC_ATOM,
+        }
+    }
+
+    /// show self, surrounded with parentheses if it binds weaker than min
+    fn show_prec(&self, min: u8, format: &Format) -> String {
+        if self.precedence(format) < min {
+            format!("( {} )", self.show_with(format))
+        } else {
+            self.show_with(format)
         }
     }
 
     pub fn show(&self) -> String {
+        self.show_with(&Format::new())
+    }
+
+    pub fn show_with(&self, format: &Format) -> String {
         match self {
-            Expression::Magnitude(magnitude) => format!("{}", magnitude),
-            Expression::Variable(string) => string.to_string(),
+            Expression::Magnitude(magnitude) => magnitude.show(format),
+            Expression::Variable(string) | Expression::Unbound(string) => string.to_string(),
             Expression::Add(items) => {
                 let mut output = String::new();
                 let mut iterator = items.iter();
                 let first = iterator.next().unwrap();
-                output.push_str(&first.show_group());
+                output.push_str(&first.show_prec(PREC_ADD, format));
                 for item in iterator {
-                    if let Expression::Neg(_) = item {
-                        output.push_str(&item.show_group());
+                    if let Expression::Neg(expr) = item {
+                        output.push_str(" - ");
+                        output.push_str(&expr.show_prec(PREC_PROD, format));
                     } else {
                         output.push_str(" + ");
-                        output.push_str(&item.show_group());
+                        output.push_str(&item.show_prec(PREC_ADD, format));
                     }
                 }
                 output
             }
-            Expression::Neg(expr) => format!("-{}", expr.show_group()),
-            Expression::Prod(items) => show_n_ary(" \\cdot ", items),
-            Expression::Div(items) => {
-                format!(" \\frac{{{}}}{{{}}} ", items[0].show(), items[1].show())
+            Expression::Neg(expr) => format!("-{}", expr.show_prec(PREC_PROD, format)),
+            Expression::Prod(items) => {
+                let sep = match format.product {
+                    ProductStyle::Cdot => " \\cdot ",
+                    ProductStyle::Times => " \\times ",
+                };
+                show_n_ary(sep, items, PREC_PROD, format)
             }
-            Expression::Unit(value, _name) => value.show(),
-            Expression::Sqrt(expr) => format!("\\sqrt{{{}}}", expr.show()),
-            Expression::Log(expr) => format!("\\operatorname{{log}}({})", expr.show()),
-            Expression::Ln(expr) => format!("\\operatorname{{ln}}({})", expr.show()),
-            Expression::Rand(items) => format!(
+            Expression::Div(items) => match format.division {
+                DivisionStyle::Frac => format!(
+                    "\\frac{{{}}}{{{}}}",
+                    items[0].show_with(format),
+                    items[1].show_with(format)
+                ),
+                DivisionStyle::Inline => format!(
+                    "{} / {}",
+                    items[0].show_prec(PREC_PROD, format),
+                    items[1].show_prec(PREC_POW, format)
+                ),
+            },
+            Expression::Unit(value, _name) => value.show_with(format),
+            Expression::Convert(value, ..) => value.show_with(format),
+            Expression::Sqrt(expr) => format!("\\sqrt{{{}}}", expr.show_with(format)),
+            Expression::Log(expr) => {
+                format!("\\operatorname{{log}}({})", expr.show_with(format))
+            }
+            Expression::Ln(expr) => format!("\\operatorname{{ln}}({})", expr.show_with(format)),
+            Expression::Rand(items, _) => format!(
                 "\\operatorname{{rand}}({}, {})",
-                items[0].show_group(),
-                items[1].show_group()
+                items[0].show_with(format),
+                items[1].show_with(format)
             ),
-            Expression::Pow(items) => format!("{}^{{{}}}", items[0].show_group(), items[1].show()),
-            Expression::And(items) => show_n_ary(" && ", items),
-            Expression::Or(items) => show_n_ary(" || ", items),
-            Expression::Not(expr) => format!("\\operatorname{{not}}({})", expr.show()),
-            Expression::Eq(items) => show_n_ary(" == ", items),
-            Expression::Neq(items) => {
-                format!("{} \\not= {}", items[0].show_group(), items[1].show_group())
-            }
-            Expression::Le(items) => show_n_ary(" < ", items),
-            Expression::Leq(items) => show_n_ary(" \\leq ", items),
-            Expression::Ge(items) => show_n_ary(" > ", items),
-            Expression::Geq(items) => show_n_ary(" \\geq ", items),
+            Expression::Pow(items) => {
+                // a magnitude with units (or a fraction) as base is ambiguous: 2m^2
+                let fraction =
+                    matches!(&items[0], Expression::Magnitude(m) if m.is_fraction(format));
+                let base = if items[0].is_literal_with_unit() || fraction {
+                    format!("( {} )", items[0].show_with(format))
+                } else {
+                    items[0].show_prec(PREC_ATOM, format)
+                };
+                format!("{}^{{{}}}", base, items[1].show_with(format))
+            }
+            Expression::And(items) => show_n_ary(" \\land ", items, PREC_REL, format),
+            Expression::Or(items) => show_n_ary(" \\lor ", items, PREC_AND, format),
+            Expression::Not(expr) => {
+                format!("\\operatorname{{not}}({})", expr.show_with(format))
+            }
+            Expression::Eq(items) => show_n_ary(" == ", items, PREC_ADD, format),
+            Expression::Root(items, _) => show_n_ary(" = ", &items[..2], PREC_ADD, format),
+            Expression::Cases(items) => {
+                let mut cases: Vec<String> = items
+                    .chunks(2)
+                    .filter(|pair| pair.len() == 2)
+                    .map(|pair| {
+                        format!(
+                            "{} & \\text{{if }} {}",
+                            pair[0].show_with(format),
+                            pair[1].show_with(format)
+                        )
+                    })
+                    .collect();
+                cases.push(format!(
+                    "{} & \\text{{otherwise}}",
+                    items[items.len() - 1].show_with(format)
+                ));
+                format!("\\begin{{cases}} {} \\end{{cases}}", cases.join(" \\\\ "))
+            }
+
+            Expression::List(items) => {
+                let items: Vec<String> = items.iter().map(|e| e.show_with(format)).collect();
+                format!("\\{{ {} \\}}", items.join(", "))
+            }
+            Expression::Aggregate(aggregate, expr) => match aggregate {
+                Aggregate::Sum => format!("\\sum {}", expr.show_prec(PREC_ATOM, format)),
+                Aggregate::Mean => format!("\\overline{{{}}}", expr.show_with(format)),
+                Aggregate::Min | Aggregate::Max => {
+                    format!("\\{}({})", aggregate.name(), expr.show_with(format))
+                }
+                _ => format!(
+                    "\\operatorname{{{}}}({})",
+                    aggregate.name(),
+                    expr.show_with(format)
+                ),
+            },
+            Expression::Fact(expr) => format!("{}!", expr.show_prec(PREC_ATOM, format)),
+            Expression::Comb(items) => format!(
+                "\\binom{{{}}}{{{}}}",
+                items[0].show_with(format),
+                items[1].show_with(format)
+            ),
+            Expression::Perm(items) => format!(
+                "\\operatorname{{P}}({}, {})",
+                items[0].show_with(format),
+                items[1].show_with(format)
+            ),
+            Expression::Neq(items) => show_n_ary(" \\not= ", items, PREC_ADD, format),
+            Expression::Le(items) => show_n_ary(" < ", items, PREC_ADD, format),
+            Expression::Leq(items) => show_n_ary(" \\leq ", items, PREC_ADD, format),
+            Expression::Ge(items) => show_n_ary(" > ", items, PREC_ADD, format),
+            Expression::Geq(items) => show_n_ary(" \\geq ", items, PREC_ADD, format),
+
+            Expression::Matrix(rows, cols, items) => {
+                let rows: Vec<String> = (0..*rows)
+                    .map(|i| {
+                        let row: Vec<String> = items[i * cols..(i + 1) * cols]
+                            .iter()
+                            .map(|item| item.show_with(format))
+                            .collect();
+                        row.join(" & ")
+                    })
+                    .collect();
+                format!(
+                    "\\begin{{pmatrix}} {} \\end{{pmatrix}}",
+                    rows.join(" \\\\ ")
+                )
+            }
+            Expression::Element(expr, indices) => {
+                let indices: Vec<String> = indices.iter().map(|i| i.to_string()).collect();
+                format!(
+                    "{}_{{{}}}",
+                    expr.show_prec(PREC_ATOM, format),
+                    indices.join(",")
+                )
+            }
+            Expression::Transpose(expr) => format!("{}^{{T}}", expr.show_prec(PREC_ATOM, format)),
+            Expression::Inverse(expr) => format!("{}^{{-1}}", expr.show_prec(PREC_ATOM, format)),
+            Expression::Det(expr) => format!("\\det( {} )", expr.show_with(format)),
+            Expression::Dot(items) => show_n_ary(" \\cdot ", items, PREC_POW, format),
+            Expression::Cross(items) => show_n_ary(" \\times ", items, PREC_POW, format),
+            Expression::Solve(items) => {
+                let product = match format.product {
+                    ProductStyle::Cdot => " \\cdot ",
+                    ProductStyle::Times => " \\times ",
+                };
+                format!(
+                    "{}^{{-1}}{}{}",
+                    items[0].show_prec(PREC_ATOM, format),
+                    product,
+                    items[1].show_prec(PREC_POW, format)
+                )
+            }
+        }
+    }
+
+    fn is_literal_with_unit(&self) -> bool {
+        match self {
+            Expression::Magnitude(magnitude) => !magnitude.unit.is_empty(),
+            Expression::Unit(expr, _) | Expression::Convert(expr, ..) => {
+                expr.is_literal_with_unit()
+            }
+            _ => false,
+        }
+    }
+
+    /// Panics if the expression uses a variable bound only in some paths
+    /// (Unbound in dict): the error is reported even if it is only shown
+    pub fn check_bound(&self, dict: &DictVariables) {
+        for name in self.free_variables() {
+            if let Some(Expression::Unbound(..)) = dict.get(&name) {
+                panic!("{}", unbound_diagnostic(&name));
+            }
         }
     }
 
@@ -211,6 +507,7 @@ impl Expression {
                     panic!("Variable {} not in dictionary", name)
                 }
             }
+            Expression::Unbound(name) => panic!("{}", unbound_diagnostic(name)),
             Expression::Add(operands) => value_n_ary(
                 Magnitude::new(0.0, String::from("¿?")),
                 |a, b| {
@@ -221,7 +518,14 @@ impl Expression {
                         )
                     });
                     let value = a.value + b.value;
-                    Magnitude { value, unit }
+                    let uncertainty = a.uncertainty.hypot(b.uncertainty);
+                    let rational = a.rational.zip(b.rational).and_then(|(a, b)| a.add(&b));
+                    Magnitude {
+                        value,
+                        uncertainty,
+                        unit,
+                        rational,
+                    }
                 },
                 operands,
                 dict,
@@ -230,12 +534,16 @@ impl Expression {
             Expression::Neg(expr) => {
                 let mag = expr.value(dict);
                 Magnitude::new(-1.0 * mag.value, mag.unit)
+                    .with_uncertainty(mag.uncertainty)
+                    .with_rational(mag.rational.and_then(|r| r.neg()))
             }
             Expression::Prod(operands) => value_n_ary(
                 Magnitude::new(1.0, String::from("¿?")),
                 |a, b| Magnitude {
                     value: a.value * b.value,
+                    uncertainty: (a.uncertainty * b.value).hypot(b.uncertainty * a.value),
                     unit: String::from("¿?"),
+                    rational: a.rational.zip(b.rational).and_then(|(a, b)| a.mul(&b)),
                 },
                 operands,
                 dict,
@@ -244,7 +552,12 @@ impl Expression {
                 let num = operands[0].value(dict);
                 let den = operands[1].value(dict);
 
-                Magnitude::new(num.value / den.value, String::from("¿?"))
+                let value = num.value / den.value;
+                let uncertainty =
+                    (num.uncertainty / den.value).hypot(den.uncertainty * value / den.value);
+                Magnitude::new(value, String::from("¿?"))
+                    .with_uncertainty(uncertainty)
+                    .with_rational(num.rational.zip(den.rational).and_then(|(a, b)| a.div(&b)))
             }
             Expression::Unit(expr, new_unit) => {
                 let mut mag = expr.value(dict);
@@ -254,24 +567,39 @@ impl Expression {
 
                 mag
             }
+            Expression::Convert(expr, new_unit, units) => {
+                let mag = expr.value(dict);
+                let (factor, offset) = units
+                    .conversion(&mag.unit, new_unit)
+                    .unwrap_or_else(|| panic!("{}", convert_diagnostic(expr, &mag, new_unit)));
+
+                Magnitude::new(mag.value * factor + offset, new_unit.clone())
+                    .with_uncertainty(mag.uncertainty * factor)
+                    .normalize(units)
+            }
             Expression::Sqrt(expr) => {
                 let mag = expr.value(dict);
-                Magnitude::new(mag.value.sqrt(), String::from("¿?"))
+                let value = mag.value.sqrt();
+                Magnitude::new(value, String::from("¿?"))
+                    .with_uncertainty(mag.uncertainty / (2.0 * value))
+                    .with_rational(mag.rational.and_then(|r| r.sqrt()))
             }
             Expression::Log(expr) => {
                 let mag = expr.value(dict);
                 mag.compatible_unit_str("")
                     .expect("log with arg units wrong");
-                Magnitude::new(mag.value.log10(), String::from(""))
+                let uncertainty = mag.uncertainty / (mag.value * std::f64::consts::LN_10);
+                Magnitude::new(mag.value.log10(), String::from("")).with_uncertainty(uncertainty)
             }
             Expression::Ln(expr) => {
                 let mag = expr.value(dict);
                 mag.compatible_unit_str("")
                     .expect("ln with arg units wrong");
                 Magnitude::new(mag.value.ln(), String::from(""))
+                    .with_uncertainty(mag.uncertainty / mag.value)
             }
 
-            Expression::Rand(items) => {
+            Expression::Rand(items, seed) => {
                 let min = items[0].value(dict).value;
                 let max = items[1].value(dict).value;
                 let unit = items[0]
@@ -280,8 +608,10 @@ impl Expression {
                     .expect("Randon value with limits with different units");
 
                 Magnitude {
-                    value: (max - min) * random::<f64>() + min,
+                    value: (max - min) * random::value(*seed) + min,
+                    uncertainty: 0.0,
                     unit,
+                    rational: None,
                 }
             }
             Expression::Pow(operands) => {
@@ -290,7 +620,16 @@ impl Expression {
 
                 exp.compatible_unit_str("")
                     .expect("exponent with arg units wrong");
-                Magnitude::new(base.value.powf(exp.value), String::from("¿?"))
+                let value = base.value.powf(exp.value);
+                // d(a^b) = b a^(b-1) da + a^b ln(a) db
+                let mut uncertainty =
+                    exp.value * base.value.powf(exp.value - 1.0) * base.uncertainty;
+                if exp.uncertainty > 0.0 {
+                    uncertainty = uncertainty.hypot(value * base.value.ln() * exp.uncertainty);
+                }
+                Magnitude::new(value, String::from("¿?"))
+                    .with_uncertainty(uncertainty)
+                    .with_rational(base.rational.zip(exp.rational).and_then(|(a, b)| a.pow(&b)))
             }
             Expression::And(operands) => value_n_ary(
                 magnitude::TRUE.clone(),
@@ -326,21 +665,166 @@ impl Expression {
                 }
             }
             Expression::Eq(operands) => relation_n_ary(|a, b| a == b, operands, dict),
+            Expression::Root(operands, unknown) => self.solve_equation(operands, unknown, dict),
+            Expression::Cases(operands) => {
+                for pair in operands.chunks(2).filter(|pair| pair.len() == 2) {
+                    let condition = pair[1].value(dict);
+                    condition
+                        .compatible_unit_str("bool")
+                        .unwrap_or_else(|| panic!("Condition {:?} isn't a bool", pair[1]));
+                    if condition == *magnitude::TRUE {
+                        return pair[0].value(dict);
+                    }
+                }
+                operands[operands.len() - 1].value(dict)
+            }
+
+            Expression::Aggregate(aggregate, expr) => expr.aggregate_value(*aggregate, dict),
+            Expression::Fact(expr) => factorial(&expr.value(dict)),
+            Expression::Comb(operands) => {
+                combinations(&operands[0].value(dict), &operands[1].value(dict))
+            }
+            Expression::Perm(operands) => {
+                permutations(&operands[0].value(dict), &operands[1].value(dict))
+            }
+            Expression::List(..) => panic!("List {:?} used as scalar", self),
             Expression::Neq(operands) => relation_n_ary(|a, b| a != b, operands, dict),
             Expression::Le(operands) => relation_n_ary(|a, b| a < b, operands, dict),
             Expression::Leq(operands) => relation_n_ary(|a, b| a <= b, operands, dict),
             Expression::Ge(operands) => relation_n_ary(|a, b| a > b, operands, dict),
             Expression::Geq(operands) => relation_n_ary(|a, b| a >= b, operands, dict),
+
+            Expression::Element(expr, indices) => {
+                let matrix = expr.matrix_value(dict);
+                match indices[..] {
+                    [i] => matrix.at(i),
+                    [i, j] => matrix.get(i, j),
+                    _ => panic!("Wrong indices {:?} of {:?}", indices, expr),
+                }
+            }
+            Expression::Det(expr) => expr.matrix_value(dict).det(),
+            Expression::Dot(operands) => operands[0]
+                .matrix_value(dict)
+                .dot(&operands[1].matrix_value(dict)),
+            Expression::Matrix(..)
+            | Expression::Transpose(..)
+            | Expression::Inverse(..)
+            | Expression::Cross(..)
+            | Expression::Solve(..) => panic!("Matrix {:?} used as scalar", self),
+        }
+    }
+
+    /// Subexpressions of self
+    pub fn operands(&self) -> Vec<&Expression> {
+        match self {
+            Expression::Magnitude(..) | Expression::Variable(..) | Expression::Unbound(..) => {
+                vec![]
+            }
+            Expression::Neg(expr)
+            | Expression::Unit(expr, _)
+            | Expression::Convert(expr, ..)
+            | Expression::Sqrt(expr)
+            | Expression::Log(expr)
+            | Expression::Ln(expr)
+            | Expression::Not(expr)
+            | Expression::Element(expr, _)
+            | Expression::Transpose(expr)
+            | Expression::Inverse(expr)
+            | Expression::Det(expr)
+            | Expression::Aggregate(_, expr)
+            | Expression::Fact(expr) => vec![expr],
+            Expression::Add(items)
+            | Expression::Prod(items)
+            | Expression::Div(items)
+            | Expression::Rand(items, _)
+            | Expression::Pow(items)
+            | Expression::And(items)
+            | Expression::Or(items)
+            | Expression::Eq(items)
+            | Expression::Root(items, _)
+            | Expression::Cases(items)
+            | Expression::List(items)
+            | Expression::Comb(items)
+            | Expression::Perm(items)
+            | Expression::Neq(items)
+            | Expression::Le(items)
+            | Expression::Leq(items)
+            | Expression::Ge(items)
+            | Expression::Geq(items)
+            | Expression::Matrix(_, _, items)
+            | Expression::Dot(items)
+            | Expression::Cross(items)
+            | Expression::Solve(items) => items.iter().collect(),
+        }
+    }
+
+    /// self with a seed from rng in each rand (its value)
+    pub fn seed_rands(self, rng: &RefCell<StdRng>) -> Expression {
+        match self {
+            Expression::Rand(items, _) => {
+                let seed = rng.borrow_mut().gen();
+                let items = items.into_iter().map(|e| e.seed_rands(rng)).collect();
+                Expression::Rand(items, seed)
+            }
+            _ => self.map_operands(&|e| e.seed_rands(rng)),
+        }
+    }
+
+    /// Rebuilds self applying f to each subexpression
+    pub fn map_operands(self, f: &dyn Fn(Expression) -> Expression) -> Expression {
+        let map = |items: Arguments| -> Arguments { items.into_iter().map(f).collect() };
+
+        match self {
+            Expression::Magnitude(..) | Expression::Variable(..) | Expression::Unbound(..) => self,
+            Expression::Add(items) => Expression::Add(map(items)),
+            Expression::Neg(expr) => Expression::Neg(Box::new(f(*expr))),
+            Expression::Prod(items) => Expression::Prod(map(items)),
+            Expression::Div(items) => Expression::Div(map(items)),
+            Expression::Unit(expr, unit) => Expression::Unit(Box::new(f(*expr)), unit),
+            Expression::Convert(expr, unit, units) => {
+                Expression::Convert(Box::new(f(*expr)), unit, units)
+            }
+            Expression::Sqrt(expr) => Expression::Sqrt(Box::new(f(*expr))),
+            Expression::Log(expr) => Expression::Log(Box::new(f(*expr))),
+            Expression::Ln(expr) => Expression::Ln(Box::new(f(*expr))),
+            Expression::Rand(items, seed) => Expression::Rand(map(items), seed),
+            Expression::Pow(items) => Expression::Pow(map(items)),
+            Expression::And(items) => Expression::And(map(items)),
+            Expression::Or(items) => Expression::Or(map(items)),
+            Expression::Not(expr) => Expression::Not(Box::new(f(*expr))),
+            Expression::Eq(items) => Expression::Eq(map(items)),
+            Expression::Root(items, unknown) => Expression::Root(map(items), unknown),
+            Expression::Cases(items) => Expression::Cases(map(items)),
+            Expression::List(items) => Expression::List(map(items)),
+            Expression::Aggregate(aggregate, expr) => {
+                Expression::Aggregate(aggregate, Box::new(f(*expr)))
+            }
+            Expression::Fact(expr) => Expression::Fact(Box::new(f(*expr))),
+            Expression::Comb(items) => Expression::Comb(map(items)),
+            Expression::Perm(items) => Expression::Perm(map(items)),
+            Expression::Neq(items) => Expression::Neq(map(items)),
+            Expression::Le(items) => Expression::Le(map(items)),
+            Expression::Leq(items) => Expression::Leq(map(items)),
+            Expression::Ge(items) => Expression::Ge(map(items)),
+            Expression::Geq(items) => Expression::Geq(map(items)),
+            Expression::Matrix(rows, cols, items) => Expression::Matrix(rows, cols, map(items)),
+            Expression::Element(expr, indices) => Expression::Element(Box::new(f(*expr)), indices),
+            Expression::Transpose(expr) => Expression::Transpose(Box::new(f(*expr))),
+            Expression::Inverse(expr) => Expression::Inverse(Box::new(f(*expr))),
+            Expression::Det(expr) => Expression::Det(Box::new(f(*expr))),
+            Expression::Dot(items) => Expression::Dot(map(items)),
+            Expression::Cross(items) => Expression::Cross(map(items)),
+            Expression::Solve(items) => Expression::Solve(map(items)),
         }
     }
 }
 
 //------------------------------------------------
 
-fn show_n_ary(sep: &str, items: &ArgumentsSlice) -> String {
+fn show_n_ary(sep: &str, items: &ArgumentsSlice, min: u8, format: &Format) -> String {
     items
         .iter()
-        .map(Expression::show_group)
+        .map(|item| item.show_prec(min, format))
         .collect::<Vec<String>>()
         .join(sep)
 }
@@ -397,7 +881,7 @@ fn operator1(f: fn(Expression) -> Expression, stack: &mut Stack) {
     stack.push(f(op1));
 }
 
-fn operator2(f: fn(Expression, Expression) -> Expression, stack: &mut Stack) {
+fn operator2(f: impl FnOnce(Expression, Expression) -> Expression, stack: &mut Stack) {
     let op2 = pop(stack);
     let op1 = pop(stack);
 
@@ -410,7 +894,8 @@ fn insert_magnitude(magnitude: Magnitude, stack: &mut Stack) {
     } else {
         let mag_abs = Magnitude {
             value: magnitude.value.abs(),
-            unit: magnitude.unit,
+            rational: magnitude.rational.map(|r| r.abs()),
+            ..magnitude
         };
         stack.push(Expression::Neg(Box::new(Expression::Magnitude(mag_abs))));
     }
@@ -457,15 +942,50 @@ fn from_dict(stack: &mut Stack, dict: &DictVariables) {
 //------------------------------------------------
 // Easy operations over expressions
 
-fn units(value: Expression, unit: Expression) -> Expression {
+fn units(value: Expression, unit: Expression, units: &Registry) -> Expression {
     let unit = match unit {
-        Expression::Variable(name) => name,
+        Expression::Variable(name) => units.canonical(&name),
         _ => panic!("Impossible to use assign unit"),
     };
 
     Expression::Unit(Box::new(value), unit)
 }
 
+fn convert(value: Expression, unit: Expression, units: &Rc<Registry>) -> Expression {
+    let unit = match unit {
+        Expression::Variable(name) => units.canonical(&name),
+        _ => panic!("Impossible to convert to unit {:?}", unit),
+    };
+
+    Expression::Convert(Box::new(value), unit, Rc::clone(units))
+}
+
+fn unbound_diagnostic(name: &str) -> String {
+    format!(
+        "Variable {} isn't bound in every path to this point (it is bound only in \
+         some branches of an alternative or with different values in branches)",
+        name
+    )
+}
+
+// error of a conversion: the unit of a product or a quotient is unknown
+fn convert_diagnostic(expr: &Expression, magnitude: &Magnitude, unit: &str) -> String {
+    if magnitude.unit == "¿?" {
+        format!(
+            "Impossible to convert {} into {}: its unit is unknown (products and quotients don't keep units), set it with ':' before '->'",
+            expr.show(),
+            unit
+        )
+    } else {
+        format!(
+            "Impossible to convert {} ({}) into {}: the units haven't the same base unit",
+            expr.show(),
+            magnitude.unit,
+            unit
+        )
+    }
+}
+
 fn nounits(value: Expression) -> Expression {
     Expression::Unit(Box::new(value), String::new())
 }
@@ -483,7 +1003,7 @@ fn ln_expression(value: Expression) -> Expression {
 }
 
 fn rand_expression(op1: Expression, op2: Expression) -> Expression {
-    Expression::Rand(vec![op1, op2])
+    Expression::Rand(vec![op1, op2], 0)
 }
 
 fn pow_expression(op1: Expression, op2: Expression) -> Expression {
@@ -494,6 +1014,151 @@ fn neq_expression(op1: Expression, op2: Expression) -> Expression {
     Expression::Neq(vec![op1, op2])
 }
 
+fn transpose_expression(value: Expression) -> Expression {
+    Expression::Transpose(Box::new(value))
+}
+
+fn inverse_expression(value: Expression) -> Expression {
+    Expression::Inverse(Box::new(value))
+}
+
+fn det_expression(value: Expression) -> Expression {
+    Expression::Det(Box::new(value))
+}
+
+fn dot_expression(op1: Expression, op2: Expression) -> Expression {
+    Expression::Dot(vec![op1, op2])
+}
+
+fn cross_expression(op1: Expression, op2: Expression) -> Expression {
+    Expression::Cross(vec![op1, op2])
+}
+
+fn solve_expression(op1: Expression, op2: Expression) -> Expression {
+    Expression::Solve(vec![op1, op2])
+}
+
+//------------------------------------------------
+// Vectors and matrices over stack
+
+/// pops a literal positive integer (size or index)
+fn pop_index(stack: &mut Stack) -> usize {
+    match pop(stack) {
+        Expression::Magnitude(mag) if mag.value >= 1.0 && mag.value.fract() == 0.0 => {
+            mag.value as usize
+        }
+        other => panic!(
+            "Expected a size or index (integer literal) and found {:?}",
+            other
+        ),
+    }
+}
+
+fn pop_elements(number: usize, stack: &mut Stack) -> Arguments {
+    if stack.len() < number {
+        panic!(
+            "Not enough elements in stack ({} needed): {:?}",
+            number, stack
+        );
+    }
+    stack.split_off(stack.len() - number)
+}
+
+// e1 e2 ... en n -- vector (column)
+fn vector_from_stack(stack: &mut Stack) {
+    let rows = pop_index(stack);
+    let elements = pop_elements(rows, stack);
+
+    stack.push(Expression::Matrix(rows, 1, elements));
+}
+
+// e11 e12 ... emn m n -- matrix (elements by rows)
+fn matrix_from_stack(stack: &mut Stack) {
+    let cols = pop_index(stack);
+    let rows = pop_index(stack);
+    let elements = pop_elements(rows * cols, stack);
+
+    stack.push(Expression::Matrix(rows, cols, elements));
+}
+
+// v i -- element (number = 1) or M i j -- element (number = 2)
+fn element_from_stack(number: usize, stack: &mut Stack) {
+    let mut indices: Vec<usize> = (0..number).map(|_| pop_index(stack)).collect();
+    indices.reverse();
+    let matrix = pop(stack);
+
+    stack.push(Expression::Element(Box::new(matrix), indices));
+}
+
+//------------------------------------------------
+// Equations over stack
+
+// lhs rhs unknown start -- root (number = 1: initial guess)
+// lhs rhs unknown min max -- root (number = 2: bracket)
+fn root_from_stack(number: usize, stack: &mut Stack) {
+    let mut starts: Arguments = (0..number).map(|_| pop(stack)).collect();
+    starts.reverse();
+    let unknown = match pop(stack) {
+        Expression::Variable(name) => name,
+        other => panic!("Expected the unknown of equation and found {:?}", other),
+    };
+    let rhs = pop(stack);
+    let lhs = pop(stack);
+
+    let mut items = vec![lhs, rhs];
+    items.append(&mut starts);
+    stack.push(Expression::Root(items, unknown));
+}
+
+//------------------------------------------------
+// Conditional values over stack
+
+// condition then else -- value
+fn if_from_stack(stack: &mut Stack) {
+    let otherwise = pop(stack);
+    let then = pop(stack);
+    let condition = pop(stack);
+
+    stack.push(Expression::Cases(vec![then, condition, otherwise]));
+}
+
+// v1 c1 v2 c2 ... vn cn default n -- value
+fn piecewise_from_stack(stack: &mut Stack) {
+    let number = pop_index(stack);
+    let otherwise = pop(stack);
+    let mut items = pop_elements(2 * number, stack);
+    items.push(otherwise);
+
+    stack.push(Expression::Cases(items));
+}
+
+//------------------------------------------------
+// Lists over stack
+
+// e1 e2 ... en n -- list
+fn list_from_stack(stack: &mut Stack) {
+    let number = pop_index(stack);
+    let items = pop_elements(number, stack);
+
+    stack.push(Expression::List(items));
+}
+
+fn aggregate(aggregate: Aggregate, value: Expression) -> Expression {
+    Expression::Aggregate(aggregate, Box::new(value))
+}
+
+fn fact_expression(value: Expression) -> Expression {
+    Expression::Fact(Box::new(value))
+}
+
+fn comb_expression(op1: Expression, op2: Expression) -> Expression {
+    Expression::Comb(vec![op1, op2])
+}
+
+fn perm_expression(op1: Expression, op2: Expression) -> Expression {
+    Expression::Perm(vec![op1, op2])
+}
+
 //------------------------------------------------
 // Complex operations over expressions
 
@@ -783,3 +1448,62 @@ fn geq_expression(op1: Expression, op2: Expression) -> Expression {
         Expression::Geq(operands1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rational::Rational;
+
+    fn value(rpn: &str) -> Magnitude {
+        Expression::from(rpn, &Macros::new()).value(&DictVariables::new())
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn uncertainty_of_sums_and_products() {
+        let sum = value("10.0±0.3 2.0±0.4 +");
+        assert_close(sum.value, 12.0);
+        assert_close(sum.uncertainty, 0.5);
+
+        // relative uncertainties are added in quadrature
+        let product = value("10.0±0.2 2.0±0.1 *");
+        assert_close(product.value, 20.0);
+        assert_close(product.uncertainty, 0.4f64.hypot(1.0));
+
+        let quotient = value("10.0±0.2 2.0±0.1 /");
+        assert_close(quotient.value, 5.0);
+        assert_close(quotient.uncertainty, 0.1f64.hypot(0.25));
+
+        let negative = value("10.0±0.2 neg");
+        assert_close(negative.value, -10.0);
+        assert_close(negative.uncertainty, 0.2);
+    }
+
+    #[test]
+    fn uncertainty_of_powers() {
+        let cube = value("2.0±0.1 3 pow");
+        assert_close(cube.value, 8.0);
+        assert_close(cube.uncertainty, 3.0 * 4.0 * 0.1);
+
+        // the uncertainty is positive with a negative exponent
+        let inverse_square = value("2.0±0.1 -2 pow");
+        assert_close(inverse_square.value, 0.25);
+        assert_close(inverse_square.uncertainty, 2.0 * 0.125 * 0.1);
+
+        let root = value("16±0.8 sqrt");
+        assert_close(root.value, 4.0);
+        assert_close(root.uncertainty, 0.1);
+    }
+
+    #[test]
+    fn exact_values_are_kept() {
+        let third = value("1 3 /");
+        assert_eq!(third.rational, Rational::new(1, 3));
+        assert_eq!(value("1 3 / 3 *").rational, Rational::new(1, 1));
+        assert_eq!(value("2 -2 pow").rational, Rational::new(1, 4));
+        assert_eq!(value("2 sqrt").rational, None);
+    }
+}
@@ -0,0 +1,360 @@
+// Figures of the gates
+//
+// A line of a gate starting with "@@@" is a figure:
+//
+//     @@@ circuit.svg | Caption of the figure | Alternative text
+//     @@@ circuit = circuit-2.svg | Simplified circuit
+//
+// The file is located through the paths (as the macros files) and it is
+// copied into the "figures" directory next to the output (with the hash of
+// its contents in the name). Caption and alternative text are
+// optional (alternative text is the caption by default). A named figure
+// is a variant: it replaces the previous figure with the same name in the
+// context of the following steps.
+//
+// A svg file with {{ }} is a template: its lines are processed as the lines
+// of the gate (with its variables) and the math is written as text.
+//
+
+use anyhow::{bail, Result};
+use cityhash::cityhash_1_1_1::city_hash_64;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::macros::locate_file;
+use crate::render_reveal::escape;
+
+pub const MARKER: &str = "@@@";
+const DIRECTORY: &str = "figures";
+
+#[derive(Debug, Clone)]
+pub struct Figure {
+    pub name: Option<String>,
+    pub file: PathBuf,
+    pub caption: String,
+    pub alt: String,
+}
+
+/// Contents of the instantiated templates of an exercise (by the file of
+/// their figures), shared by the scopes of the macros
+pub type Templates = Rc<RefCell<HashMap<PathBuf, String>>>;
+
+// Pieces of a text: markdown text or figures
+pub enum Block {
+    Text(String),
+    Figure(Figure),
+}
+
+impl Figure {
+    /// Figure of a line (without the marker)
+    pub fn from(line: &str) -> Option<Self> {
+        lazy_static! {
+            static ref RE_NAME: Regex = Regex::new(r"^\s*([[:word:]-]+)\s*=\s*(.*)$").unwrap();
+        }
+        let (name, line) = match RE_NAME.captures(line) {
+            Some(cap) => (Some(cap[1].to_string()), cap.get(2).unwrap().as_str()),
+            None => (None, line),
+        };
+
+        let mut fields = line.splitn(3, '|').map(str::trim);
+        let file = fields.next().filter(|file| !file.is_empty())?;
+        let caption = fields.next().unwrap_or("").to_string();
+        let alt = fields
+            .next()
+            .map_or_else(|| caption.clone(), str::to_string);
+
+        Some(Figure {
+            name,
+            file: PathBuf::from(file),
+            caption,
+            alt,
+        })
+    }
+
+    /// Figure with the file located through paths
+    pub fn locate(self, paths: &[String]) -> Result<Self> {
+        Ok(Figure {
+            file: locate_file(&self.file, paths)?,
+            ..self
+        })
+    }
+
+    /// Figure with the {{ }} of a svg file (a template) replaced by process
+    /// (as the lines of gates). The values of the math are written as text.
+    /// The contents are kept in templates (written by copy_figures) with a
+    /// file of its own (the hash of the contents in the directory of the
+    /// template).
+    pub fn instantiate(
+        self,
+        templates: &RefCell<HashMap<PathBuf, String>>,
+        mut process: impl FnMut(&str) -> String,
+    ) -> Result<Self> {
+        if self.file.extension() != Some(OsStr::new("svg")) {
+            return Ok(self);
+        }
+        let template = fs::read_to_string(&self.file)?;
+        if !template.contains("{{") {
+            return Ok(self);
+        }
+
+        let lines: Vec<String> = template
+            .split('\n')
+            .map(|line| math_to_text(&process(line), true))
+            .collect();
+        let contents = lines.join("\n");
+        if contents.contains('⟦') {
+            bail!(
+                "Figure {:?}: values evaluated in the browser can't be shown in figures",
+                self.file
+            );
+        }
+
+        let file = self
+            .file
+            .with_file_name(format!("{:x}", city_hash_64(contents.as_bytes())))
+            .join(self.file.file_name().unwrap());
+        templates.borrow_mut().insert(file.clone(), contents);
+        Ok(Figure { file, ..self })
+    }
+
+    /// HTML of figure (in one line). The figure is added to figures (name in
+    /// figures directory, contents) to be written by copy_figures.
+    pub fn html(
+        &self,
+        templates: &HashMap<PathBuf, String>,
+        figures: &mut Vec<(String, Vec<u8>)>,
+    ) -> String {
+        let bytes = match templates.get(&self.file) {
+            Some(contents) => contents.clone().into_bytes(),
+            None => fs::read(&self.file)
+                .unwrap_or_else(|_| panic!("Unable to read figure {:?}", self.file)),
+        };
+        let stem = self.file.file_stem().unwrap().to_string_lossy();
+        let target = match self.file.extension() {
+            Some(ext) => format!(
+                "{}-{:x}.{}",
+                stem,
+                city_hash_64(&bytes),
+                ext.to_string_lossy()
+            ),
+            None => format!("{}-{:x}", stem, city_hash_64(&bytes)),
+        };
+        if !figures.iter().any(|(t, _)| *t == target) {
+            figures.push((target.clone(), bytes));
+        }
+
+        let mut output = match &self.name {
+            Some(name) => format!("<figure data-variant=\"{}\">", name),
+            None => String::from("<figure>"),
+        };
+        output += &format!(
+            "<img src=\"{}/{}\" alt=\"{}\">",
+            DIRECTORY,
+            escape(&target),
+            escape(&math_to_text(&self.alt, false))
+        );
+        if !self.caption.is_empty() {
+            output += &format!("<figcaption>{}</figcaption>", escape(&self.caption));
+        }
+        output + "</figure>\n"
+    }
+}
+
+impl fmt::Display for Figure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ", MARKER)?;
+        if let Some(name) = &self.name {
+            write!(f, "{} = ", name)?;
+        }
+        write!(
+            f,
+            "{} | {} | {}",
+            self.file.to_string_lossy(),
+            self.caption,
+            self.alt
+        )
+    }
+}
+
+/// Text split into text and figure lines
+pub fn blocks(text: &str) -> Vec<Block> {
+    let mut output = vec![];
+    let mut lines: Vec<&str> = vec![];
+
+    for line in text.split('\n') {
+        match line.strip_prefix(MARKER).and_then(Figure::from) {
+            Some(figure) => {
+                if !lines.is_empty() {
+                    output.push(Block::Text(lines.join("\n")));
+                    lines.clear();
+                }
+                output.push(Block::Figure(figure));
+            }
+            None => lines.push(line),
+        }
+    }
+    if !lines.is_empty() {
+        output.push(Block::Text(lines.join("\n")));
+    }
+    output
+}
+
+/// context without the figures replaced by variants in text (rendered)
+pub fn replace_variants(context: &str, text: &str) -> String {
+    lazy_static! {
+        static ref RE_VARIANT: Regex = Regex::new(r#"<figure data-variant="([^"]*)">"#).unwrap();
+    }
+    let mut output = context.to_string();
+    for cap in RE_VARIANT.captures_iter(text) {
+        let previous = Regex::new(&format!(
+            r#"<figure data-variant="{}">.*?</figure>\n?"#,
+            regex::escape(&cap[1])
+        ))
+        .unwrap();
+        output = previous.replace_all(&output, "").to_string();
+    }
+    output
+}
+
+/// Writes the figures (name in figures directory, contents) into the figures
+/// directory next to output
+pub fn copy_figures(output: &Path, figures: &[(String, Vec<u8>)]) -> Result<()> {
+    if figures.is_empty() {
+        return Ok(());
+    }
+
+    let directory = output
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(DIRECTORY);
+    fs::create_dir_all(&directory)?;
+    for (target, contents) in figures {
+        fs::write(directory.join(target), contents)?;
+    }
+    Ok(())
+}
+
+//--------------------------------------------
+// Text of math (svg)
+
+// math of interpolations ([[[ ]]] and ((( )))) as text (escaped for xml)
+fn math_to_text(line: &str, xml: bool) -> String {
+    lazy_static! {
+        static ref RE_MATH: Regex = Regex::new(r"\[\[\[ (.*?) \]\]\]|\(\(\( (.*?) \)\)\)").unwrap();
+    }
+    RE_MATH
+        .replace_all(line, |cap: &regex::Captures| {
+            let text = latex_to_text(cap.get(1).or_else(|| cap.get(2)).unwrap().as_str());
+            if xml {
+                escape(&text)
+            } else {
+                text
+            }
+        })
+        .to_string()
+}
+
+// text of the LaTeX of magnitudes and expressions
+fn latex_to_text(latex: &str) -> String {
+    lazy_static! {
+        static ref RE_FRAC: Regex = Regex::new(r"\\frac\{([^{}]*)\}\{([^{}]*)\}").unwrap();
+        static ref RE_TEXT: Regex =
+            Regex::new(r"\\(?:mathrm|text|operatorname)\{([^{}]*)\}").unwrap();
+        static ref RE_SCRIPT: Regex = Regex::new(r"([\^_])(?:\{([^{}]*)\}|(\\?.))").unwrap();
+        static ref RE_UNIT: Regex = Regex::new(r"([0-9)}])\\mathrm").unwrap();
+        static ref RE_COMMAND: Regex = Regex::new(r"\\([A-Za-z]+) ?|\\(.)").unwrap();
+    }
+    const COMMANDS: &[(&str, &str)] = &[
+        ("Omega", "Ω"),
+        ("mu", "µ"),
+        ("pm", "±"),
+        ("cdot", "·"),
+        ("times", "×"),
+        ("infty", "∞"),
+        ("circ", "°"),
+        ("Rightarrow", "⇒"),
+        ("left", ""),
+        ("right", ""),
+        ("alpha", "α"),
+        ("beta", "β"),
+        ("gamma", "γ"),
+        ("delta", "δ"),
+        ("Delta", "Δ"),
+        ("epsilon", "ε"),
+        ("theta", "θ"),
+        ("lambda", "λ"),
+        ("pi", "π"),
+        ("rho", "ρ"),
+        ("sigma", "σ"),
+        ("tau", "τ"),
+        ("phi", "φ"),
+        ("omega", "ω"),
+    ];
+
+    // a space between number and unit
+    let mut text = RE_UNIT
+        .replace_all(&latex.replace("{,}", ","), "$1 \\mathrm")
+        .to_string();
+    // innermost groups first
+    loop {
+        let next = RE_FRAC.replace_all(&text, "$1/$2");
+        let next = RE_TEXT.replace_all(&next, "$1").to_string();
+        if next == text {
+            break;
+        }
+        text = next;
+    }
+    let text = RE_SCRIPT.replace_all(&text, |cap: &regex::Captures| {
+        let script = cap.get(2).or_else(|| cap.get(3)).unwrap().as_str();
+        match script {
+            "\\circ" => String::from("°"),
+            _ if &cap[1] == "^" => to_script(script, "0123456789+-n", "⁰¹²³⁴⁵⁶⁷⁸⁹⁺⁻ⁿ", "^"),
+            _ => to_script(
+                script,
+                "0123456789+-aeoxhklmnpst",
+                "₀₁₂₃₄₅₆₇₈₉₊₋ₐₑₒₓₕₖₗₘₙₚₛₜ",
+                "_",
+            ),
+        }
+    });
+    let text = RE_COMMAND.replace_all(&text, |cap: &regex::Captures| match cap.get(1) {
+        Some(name) => COMMANDS
+            .iter()
+            .find(|(command, _)| *command == name.as_str())
+            .map_or_else(
+                || name.as_str().to_string(),
+                |(_, symbol)| symbol.to_string(),
+            ),
+        None => match &cap[2] {
+            "," | ";" | " " => String::from(" "),
+            symbol => symbol.to_string(),
+        },
+    });
+
+    let text = text.replace(['{', '}'], "");
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// script with the chars of to (or marker and script if a char is missing)
+fn to_script(script: &str, from: &str, to: &str, marker: &str) -> String {
+    let chars: Option<String> = script
+        .chars()
+        .map(|c| {
+            from.chars()
+                .position(|f| f == c)
+                .and_then(|i| to.chars().nth(i))
+        })
+        .collect();
+    match chars {
+        Some(chars) => chars,
+        None if script.chars().count() == 1 => format!("{}{}", marker, script),
+        None => format!("{}({})", marker, script),
+    }
+}
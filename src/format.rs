@@ -0,0 +1,200 @@
+// Format of expressions
+//
+// Options to render expressions and magnitudes in LaTeX. They are defined
+// for the whole exercise (key "format" in yaml) and can be changed for an
+// expression with a spec after the expression:
+//
+//   {{; I = V R / | product=times, division=inline, sig=3 }}
+//
+// The key "numeric: rational" of exercise is a shortcut of format option
+// numeric=rational (exact fractions instead of decimals).
+//
+// The tolerance to rebind a variable (rtol, atol) is defined in the same way
+// (whole exercise or a binding):
+//
+//   {{; C = 2.2nF | rtol=1e-3 }}
+//
+
+use std::rc::Rc;
+use yaml_rust::Yaml;
+
+use crate::units::Registry;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProductStyle {
+    Cdot,  // a \cdot b
+    Times, // a \times b
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DivisionStyle {
+    Frac,   // \frac{a}{b}
+    Inline, // a / b
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Digits {
+    Round(usize),       // at most n decimals: 3.1, 3.14
+    Decimals(usize),    // always n decimals: 3.10, 3.14
+    Significant(usize), // n significant figures: 3.14, 0.00314
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Notation {
+    Fixed,       // 1234.5
+    Scientific,  // 1.23 \cdot 10^{3}
+    Engineering, // 1.23 \cdot 10^{3} (exponent multiple of 3)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecimalStyle {
+    Point, // 3.14
+    Comma, // 3{,}14
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Numeric {
+    Float,    // 0.33
+    Rational, // \frac{1}{3} (magnitudes with exact value)
+}
+
+/// Two values are the same if |a - b| <= absolute + relative * max(|a|, |b|)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    pub relative: f64,
+    pub absolute: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Format {
+    pub product: ProductStyle,
+    pub division: DivisionStyle,
+    pub digits: Digits,
+    pub notation: Notation,
+    pub prefix: bool, // use SI prefix (k, M, m, ...) in magnitudes with units
+    pub decimal: DecimalStyle,
+    pub numeric: Numeric,
+    pub tolerance: Tolerance, // to rebind variables
+    pub units: Rc<Registry>,  // symbols of units (the registry of the scope)
+}
+
+impl Format {
+    pub fn new() -> Self {
+        Format {
+            product: ProductStyle::Cdot,
+            division: DivisionStyle::Frac,
+            digits: Digits::Round(2),
+            notation: Notation::Fixed,
+            prefix: true,
+            decimal: DecimalStyle::Point,
+            numeric: Numeric::Float,
+            tolerance: Tolerance {
+                relative: 1e-6,
+                absolute: 0.0, // values of any scale (nF, pF, ...) are compared
+            },
+            units: Rc::new(Registry::builtin()),
+        }
+    }
+
+    /// Format defined in a yaml hash (key: value). Missing keys take default values
+    pub fn from_yaml(yaml: &Yaml) -> Self {
+        let mut format = Format::new();
+
+        if let Some(hash) = yaml.as_hash() {
+            for (key, value) in hash {
+                let key = key
+                    .as_str()
+                    .unwrap_or_else(|| panic!("Wrong format key {:?}", key));
+                let value = match value {
+                    Yaml::String(s) => s.clone(),
+                    Yaml::Integer(i) => i.to_string(),
+                    Yaml::Real(r) => r.clone(),
+                    Yaml::Boolean(b) => b.to_string(),
+                    _ => panic!("Wrong value for format key {}: {:?}", key, value),
+                };
+                format.set(key, &value);
+            }
+        }
+
+        format
+    }
+
+    /// Copy of self modified with a spec: "key=value, key=value, ..."
+    pub fn with_spec(&self, spec: &str) -> Self {
+        let mut format = self.clone();
+
+        for item in spec.split(',') {
+            let item = item.trim();
+            if item.is_empty() {
+                continue;
+            }
+            match item.split_once('=') {
+                Some((key, value)) => format.set(key.trim(), value.trim()),
+                None => panic!("Wrong format spec {:?} (expected key=value)", item),
+            }
+        }
+
+        format
+    }
+
+    /// Copy of self with the units of a scope
+    pub fn with_units(&self, units: &Rc<Registry>) -> Self {
+        Format {
+            units: Rc::clone(units),
+            ..self.clone()
+        }
+    }
+
+    /// LaTeX of the product of a number and a power of 10
+    pub fn product_latex(&self) -> &'static str {
+        match self.product {
+            ProductStyle::Cdot => "\\cdot",
+            ProductStyle::Times => "\\times",
+        }
+    }
+
+    /// LaTeX of the decimal separator
+    pub fn decimal_latex(&self) -> &'static str {
+        match self.decimal {
+            DecimalStyle::Point => ".",
+            DecimalStyle::Comma => "{,}",
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        match (key, value) {
+            ("product", "cdot") => self.product = ProductStyle::Cdot,
+            ("product", "times") => self.product = ProductStyle::Times,
+            ("division", "frac") => self.division = DivisionStyle::Frac,
+            ("division", "inline") => self.division = DivisionStyle::Inline,
+            ("round", n) => self.digits = Digits::Round(parse_digits(key, n)),
+            ("decimals", n) => self.digits = Digits::Decimals(parse_digits(key, n)),
+            ("sig", n) => self.digits = Digits::Significant(parse_digits(key, n).max(1)),
+            ("notation", "fixed") => self.notation = Notation::Fixed,
+            ("notation", "scientific") => self.notation = Notation::Scientific,
+            ("notation", "engineering") => self.notation = Notation::Engineering,
+            ("prefix", "on") | ("prefix", "true") => self.prefix = true,
+            ("prefix", "off") | ("prefix", "false") => self.prefix = false,
+            ("decimal", "point") => self.decimal = DecimalStyle::Point,
+            ("decimal", "comma") => self.decimal = DecimalStyle::Comma,
+            ("numeric", "float") => self.numeric = Numeric::Float,
+            ("numeric", "rational") => self.numeric = Numeric::Rational,
+            ("rtol", x) => self.tolerance.relative = parse_tolerance(key, x),
+            ("atol", x) => self.tolerance.absolute = parse_tolerance(key, x),
+            _ => panic!("Unknown format option {}={}", key, value),
+        }
+    }
+}
+
+fn parse_tolerance(key: &str, value: &str) -> f64 {
+    match value.parse::<f64>() {
+        Ok(tolerance) if tolerance >= 0.0 => tolerance,
+        _ => panic!("Wrong tolerance {}={}", key, value),
+    }
+}
+
+fn parse_digits(key: &str, value: &str) -> usize {
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("Wrong number of digits {}={}", key, value))
+}
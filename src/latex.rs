@@ -0,0 +1,626 @@
+// Parser of the LaTeX generated by Expression::show_with
+//
+// It is used by the tests to verify the rendering of expressions: the LaTeX
+// parsed back must have the same value that the original expression (round
+// trip). If not, the parentheses of the rendering are wrong and the formula
+// shown to the student is ambiguous.
+//
+// Grammar (only what show_with generates):
+//   or   := and ( \lor and )*
+//   and  := rel ( \land rel )*
+//   rel  := add ( (== | \not= | < | \leq | > | \geq) add )*
+//   add  := [-] prod ( (+ | -) prod )*
+//   prod := pow ( (\cdot | \times | /) pow )*
+//   pow  := atom [ ^{ or } ]
+//   atom := number | variable | ( or [\pm or] ) | - pow | \frac{or}{or} | \sqrt{or}
+//           | \operatorname{name}( or [, or] )
+//
+// Units (\mathrm{...}) and spaces (\,) are ignored: the value of a
+// magnitude is the number shown.
+//
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+
+use crate::expression::{DictVariables, Expression};
+use crate::format::Format;
+use crate::magnitude::Magnitude;
+
+// number of random evaluations in round trip
+const TRIALS: usize = 3;
+// relative tolerance comparing values
+const TOLERANCE: f64 = 1e-9;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Symbol(String),
+}
+
+fn tokenize(latex: &str) -> Option<Vec<Token>> {
+    let chars: Vec<char> = latex.chars().collect();
+    let mut tokens = vec![];
+    let mut it = 0;
+
+    while it < chars.len() {
+        let c = chars[it];
+
+        if c.is_whitespace() {
+            it += 1;
+        } else if c.is_ascii_digit() {
+            let mut number = String::new();
+            while it < chars.len() {
+                if chars[it].is_ascii_digit() || chars[it] == '.' {
+                    number.push(chars[it]);
+                    it += 1;
+                } else if starts_with(&chars, it, "{,}") {
+                    // decimal comma
+                    number.push('.');
+                    it += 3;
+                } else {
+                    break;
+                }
+            }
+            tokens.push(Token::Number(number.parse().ok()?));
+        } else if starts_with(&chars, it, "\\,") {
+            it += 2;
+        } else if c == '\\' {
+            let start = it;
+            it += 1;
+            while it < chars.len() && chars[it].is_alphabetic() {
+                it += 1;
+            }
+            let command: String = chars[start..it].iter().collect();
+            match command.as_str() {
+                "\\operatorname" => {
+                    let end = skip_group(&chars, it)?;
+                    let name: String = chars[it + 1..end - 1].iter().collect();
+                    tokens.push(Token::Symbol(format!("\\operatorname{{{}}}", name)));
+                    it = end;
+                }
+                "\\not" if it < chars.len() && chars[it] == '=' => {
+                    tokens.push(Token::Symbol(String::from("\\not=")));
+                    it += 1;
+                }
+                // units are ignored
+                "\\mathrm" => it = skip_group(&chars, it)?,
+                "\\cdot" | "\\times" | "\\frac" | "\\sqrt" | "\\leq" | "\\geq" | "\\land"
+                | "\\lor" | "\\pm" => tokens.push(Token::Symbol(command)),
+                _ => {
+                    // greek letters and other commands are names of variables
+                    it = name_suffix(&chars, it)?;
+                    tokens.push(Token::Name(chars[start..it].iter().collect()));
+                }
+            }
+        } else if c.is_alphabetic() || c == '@' {
+            let start = it;
+            it += 1;
+            while it < chars.len() && (chars[it].is_alphanumeric()) {
+                it += 1;
+            }
+            it = name_suffix(&chars, it)?;
+            tokens.push(Token::Name(chars[start..it].iter().collect()));
+        } else if starts_with(&chars, it, "==") {
+            tokens.push(Token::Symbol(String::from("==")));
+            it += 2;
+        } else if "+-/^(){}<>,".contains(c) {
+            tokens.push(Token::Symbol(c.to_string()));
+            it += 1;
+        } else {
+            return None;
+        }
+    }
+
+    Some(tokens)
+}
+
+fn starts_with(chars: &[char], it: usize, prefix: &str) -> bool {
+    prefix
+        .chars()
+        .enumerate()
+        .all(|(i, c)| chars.get(it + i) == Some(&c))
+}
+
+/// position after the {...} group starting at it
+fn skip_group(chars: &[char], it: usize) -> Option<usize> {
+    if chars.get(it) != Some(&'{') {
+        return None;
+    }
+
+    let mut level = 0;
+    for (pos, c) in chars.iter().enumerate().skip(it) {
+        match c {
+            '{' => level += 1,
+            '}' => {
+                level -= 1;
+                if level == 0 {
+                    return Some(pos + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// subscript and primes of a variable name: R_1, R_{12}, x'
+fn name_suffix(chars: &[char], mut it: usize) -> Option<usize> {
+    if chars.get(it) == Some(&'_') {
+        it += 1;
+        if chars.get(it) == Some(&'{') {
+            it = skip_group(chars, it)?;
+        } else if it < chars.len() {
+            it += 1;
+        } else {
+            return None;
+        }
+    }
+    while chars.get(it) == Some(&'\'') {
+        it += 1;
+    }
+
+    Some(it)
+}
+
+//------------------------------------------------
+
+struct Parser {
+    tokens: Vec<Token>,
+    it: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.it)
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(s)) if s == symbol)
+    }
+
+    fn expect(&mut self, symbol: &str) -> Option<()> {
+        if self.is_symbol(symbol) {
+            self.it += 1;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn or(&mut self) -> Option<Expression> {
+        let mut items = vec![self.and()?];
+        while self.expect("\\lor").is_some() {
+            items.push(self.and()?);
+        }
+        Some(n_ary(items, Expression::Or))
+    }
+
+    fn and(&mut self) -> Option<Expression> {
+        let mut items = vec![self.rel()?];
+        while self.expect("\\land").is_some() {
+            items.push(self.rel()?);
+        }
+        Some(n_ary(items, Expression::And))
+    }
+
+    fn rel(&mut self) -> Option<Expression> {
+        let mut items = vec![self.add()?];
+        let mut relation: Option<String> = None;
+
+        while let Some(Token::Symbol(s)) = self.peek() {
+            let s = s.clone();
+            if !["==", "\\not=", "<", "\\leq", ">", "\\geq"].contains(&s.as_str()) {
+                break;
+            }
+            // chains of different relations are never generated
+            if relation.is_some() && relation.as_ref() != Some(&s) {
+                return None;
+            }
+            self.it += 1;
+            items.push(self.add()?);
+            relation = Some(s);
+        }
+
+        match relation.as_deref() {
+            None => Some(items.pop().unwrap()),
+            Some("==") => Some(Expression::Eq(items)),
+            Some("\\not=") => Some(Expression::Neq(items)),
+            Some("<") => Some(Expression::Le(items)),
+            Some("\\leq") => Some(Expression::Leq(items)),
+            Some(">") => Some(Expression::Ge(items)),
+            Some("\\geq") => Some(Expression::Geq(items)),
+            _ => None,
+        }
+    }
+
+    fn add(&mut self) -> Option<Expression> {
+        let first = if self.expect("-").is_some() {
+            Expression::Neg(Box::new(self.prod()?))
+        } else {
+            self.prod()?
+        };
+
+        let mut items = vec![first];
+        loop {
+            if self.expect("+").is_some() {
+                items.push(self.prod()?);
+            } else if self.expect("-").is_some() {
+                items.push(Expression::Neg(Box::new(self.prod()?)));
+            } else {
+                break;
+            }
+        }
+        Some(n_ary(items, Expression::Add))
+    }
+
+    fn prod(&mut self) -> Option<Expression> {
+        let mut output = self.pow()?;
+        loop {
+            if self.expect("\\cdot").is_some() || self.expect("\\times").is_some() {
+                output = Expression::Prod(vec![output, self.pow()?]);
+            } else if self.expect("/").is_some() {
+                output = Expression::Div(vec![output, self.pow()?]);
+            } else {
+                break;
+            }
+        }
+        Some(output)
+    }
+
+    fn pow(&mut self) -> Option<Expression> {
+        let base = self.atom()?;
+        if self.expect("^").is_some() {
+            let exponent = self.group()?;
+            Some(Expression::Pow(vec![base, exponent]))
+        } else {
+            Some(base)
+        }
+    }
+
+    fn group(&mut self) -> Option<Expression> {
+        self.expect("{")?;
+        let expr = self.or()?;
+        self.expect("}")?;
+        Some(expr)
+    }
+
+    fn atom(&mut self) -> Option<Expression> {
+        let token = self.peek()?.clone();
+        self.it += 1;
+
+        match token {
+            Token::Number(value) => Some(Expression::Magnitude(Magnitude {
+                value,
+                uncertainty: 0.0,
+                unit: String::from("¿?"),
+                rational: None,
+            })),
+            Token::Name(name) => Some(Expression::Variable(name)),
+            Token::Symbol(symbol) => match symbol.as_str() {
+                "(" => {
+                    let expr = self.or()?;
+                    // the uncertainty is ignored
+                    if self.expect("\\pm").is_some() {
+                        self.or()?;
+                    }
+                    self.expect(")")?;
+                    Some(expr)
+                }
+                "-" => Some(Expression::Neg(Box::new(self.pow()?))),
+                "\\frac" => {
+                    let num = self.group()?;
+                    let den = self.group()?;
+                    Some(Expression::Div(vec![num, den]))
+                }
+                "\\sqrt" => Some(Expression::Sqrt(Box::new(self.group()?))),
+                "\\operatorname{log}" => {
+                    Some(Expression::Log(Box::new(self.arguments(1)?.remove(0))))
+                }
+                "\\operatorname{ln}" => {
+                    Some(Expression::Ln(Box::new(self.arguments(1)?.remove(0))))
+                }
+                "\\operatorname{not}" => {
+                    Some(Expression::Not(Box::new(self.arguments(1)?.remove(0))))
+                }
+                "\\operatorname{rand}" => Some(Expression::Rand(self.arguments(2)?, 0)),
+                _ => None,
+            },
+        }
+    }
+
+    fn arguments(&mut self, number: usize) -> Option<Vec<Expression>> {
+        self.expect("(")?;
+        let mut output = vec![self.or()?];
+        while output.len() < number {
+            self.expect(",")?;
+            output.push(self.or()?);
+        }
+        self.expect(")")?;
+        Some(output)
+    }
+}
+
+fn n_ary(mut items: Vec<Expression>, f: fn(Vec<Expression>) -> Expression) -> Expression {
+    if items.len() == 1 {
+        items.pop().unwrap()
+    } else {
+        f(items)
+    }
+}
+
+/// Expression from LaTeX generated by show_with (None if it can't be parsed)
+pub fn parse(latex: &str) -> Option<Expression> {
+    let mut parser = Parser {
+        tokens: tokenize(latex)?,
+        it: 0,
+    };
+
+    let expr = parser.or()?;
+    if parser.it == parser.tokens.len() {
+        Some(expr)
+    } else {
+        None
+    }
+}
+
+//------------------------------------------------
+
+/// replaces the literals of expr with variables (@0, @1, ...) defined in literals,
+/// except the numbers whose LaTeX (with format) is their value
+fn replace_literals(
+    expr: &Expression,
+    format: &Format,
+    literals: &RefCell<DictVariables>,
+) -> Expression {
+    match expr {
+        Expression::Magnitude(magnitude) if !shown_exactly(magnitude, format) => {
+            let mut literals = literals.borrow_mut();
+            let name = format!("@{}", literals.len());
+            let magnitude = Magnitude {
+                value: magnitude.value,
+                uncertainty: 0.0,
+                unit: String::from("¿?"),
+                rational: magnitude.rational,
+            };
+            literals.insert(name.clone(), Expression::Magnitude(magnitude));
+            Expression::Variable(name)
+        }
+        _ => expr
+            .clone()
+            .map_operands(&|e| replace_literals(&e, format, literals)),
+    }
+}
+
+fn shown_exactly(magnitude: &Magnitude, format: &Format) -> bool {
+    magnitude.unit.is_empty()
+        && magnitude.uncertainty == 0.0
+        && parse(&magnitude.show(format))
+            .is_some_and(|parsed| same(parsed.value(&DictVariables::new()).value, magnitude.value))
+}
+
+fn same(a: f64, b: f64) -> bool {
+    a == b || (a.is_nan() && b.is_nan()) || (a - b).abs() <= TOLERANCE * a.abs().max(b.abs())
+}
+
+/// random values, equations, matrices and lists can't be evaluated with
+/// random scalar values
+fn unverifiable(expr: &Expression) -> bool {
+    let node = matches!(
+        expr,
+        Expression::Rand(..)
+            | Expression::Root(..)
+            | Expression::Matrix(..)
+            | Expression::Element(..)
+            | Expression::Transpose(..)
+            | Expression::Inverse(..)
+            | Expression::Det(..)
+            | Expression::Dot(..)
+            | Expression::Cross(..)
+            | Expression::Solve(..)
+            | Expression::List(..)
+            | Expression::Aggregate(..)
+            | Expression::Fact(..)
+            | Expression::Comb(..)
+            | Expression::Perm(..)
+    );
+    node || expr.operands().into_iter().any(unverifiable)
+}
+
+/// Checks that the LaTeX of expr (with format) parsed back has the same value
+/// of expr. Expressions that can't be verified (see unverifiable) are accepted.
+pub fn round_trip(expr: &Expression, format: &Format) -> Result<(), String> {
+    if unverifiable(expr) {
+        return Ok(());
+    }
+
+    let literals = RefCell::new(DictVariables::new());
+    // conversions of units aren't shown
+    let expr = replace_literals(&expr.without_conversions(), format, &literals);
+    let literals = literals.into_inner();
+
+    let latex = expr.show_with(format);
+    let parsed = parse(&latex).ok_or_else(|| format!("{:?} can't be parsed", latex))?;
+
+    let names = expr.free_variables();
+    if let Some(name) = parsed
+        .free_variables()
+        .into_iter()
+        .find(|name| !names.contains(name))
+    {
+        return Err(format!("{:?} has the unknown variable {}", latex, name));
+    }
+
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..TRIALS {
+        let mut sample = literals.clone();
+        for name in &names {
+            if !sample.contains_key(name) {
+                let magnitude = Magnitude {
+                    value: 0.5 + 4.5 * rng.gen::<f64>(),
+                    uncertainty: 0.0,
+                    unit: String::from("¿?"),
+                    rational: None,
+                };
+                sample.insert(name.clone(), Expression::Magnitude(magnitude));
+            }
+        }
+
+        let a = expr.value(&sample).value;
+        let b = parsed.value(&sample).value;
+        if !same(a, b) {
+            return Err(format!(
+                "{:?} is {} but its LaTeX {:?} is {}",
+                expr, a, latex, b
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{DecimalStyle, Digits, DivisionStyle, Notation, Numeric, ProductStyle};
+    use crate::rational::Rational;
+
+    // number of expressions generated for each format
+    const CASES: usize = 500;
+    // depth of the expressions generated
+    const DEPTH: usize = 4;
+
+    const NAMES: [&str; 5] = ["x", "R_1", "\\alpha", "v_{max}", "y'"];
+
+    fn formats() -> Vec<Format> {
+        let base = Format::new().with_spec("round=6");
+        vec![
+            base.clone(),
+            base.with_spec("product=times, division=inline"),
+            base.with_spec("decimal=comma, sig=6"),
+            base.with_spec("notation=scientific, decimals=6"),
+            base.with_spec("notation=engineering, sig=6, division=inline"),
+            base.with_spec("numeric=rational"),
+        ]
+    }
+
+    fn literal(rng: &mut StdRng) -> Expression {
+        let sign = if rng.gen_bool(0.3) { -1 } else { 1 };
+        let magnitude = if rng.gen_bool(0.2) {
+            let rational = Rational::new(sign * rng.gen_range(1..20), rng.gen_range(2..9));
+            Magnitude::new(0.0, String::new()).with_rational(rational)
+        } else {
+            let value = (sign * rng.gen_range(1..20)) as f64 * 10f64.powi(rng.gen_range(-3..4));
+            Magnitude::new(value, String::new())
+        };
+        Expression::Magnitude(magnitude)
+    }
+
+    fn number(rng: &mut StdRng, depth: usize) -> Expression {
+        if depth == 0 || rng.gen_bool(0.2) {
+            return if rng.gen_bool(0.4) {
+                literal(rng)
+            } else {
+                Expression::Variable(NAMES[rng.gen_range(0..NAMES.len())].to_string())
+            };
+        }
+
+        let depth = depth - 1;
+        let operands =
+            |rng: &mut StdRng, n: usize| (0..n).map(|_| number(rng, depth)).collect::<Vec<_>>();
+        match rng.gen_range(0..8) {
+            0 => {
+                let n = rng.gen_range(2..4);
+                Expression::Add(operands(rng, n))
+            }
+            1 => Expression::Neg(Box::new(number(rng, depth))),
+            2 => {
+                let n = rng.gen_range(2..4);
+                Expression::Prod(operands(rng, n))
+            }
+            3 => Expression::Div(operands(rng, 2)),
+            4 => Expression::Pow(operands(rng, 2)),
+            5 => Expression::Sqrt(Box::new(number(rng, depth))),
+            6 => Expression::Log(Box::new(number(rng, depth))),
+            _ => Expression::Ln(Box::new(number(rng, depth))),
+        }
+    }
+
+    fn boolean(rng: &mut StdRng, depth: usize) -> Expression {
+        let depth = depth.saturating_sub(1);
+        match rng.gen_range(0..if depth == 0 { 6 } else { 9 }) {
+            0 => Expression::Eq(vec![number(rng, depth), number(rng, depth)]),
+            1 => Expression::Neq(vec![number(rng, depth), number(rng, depth)]),
+            2 => Expression::Le(vec![number(rng, depth), number(rng, depth)]),
+            3 => Expression::Leq(vec![number(rng, depth), number(rng, depth)]),
+            4 => Expression::Ge(vec![number(rng, depth), number(rng, depth)]),
+            5 => Expression::Geq(vec![number(rng, depth), number(rng, depth)]),
+            6 => Expression::And(vec![boolean(rng, depth), boolean(rng, depth)]),
+            7 => Expression::Or(vec![boolean(rng, depth), boolean(rng, depth)]),
+            _ => Expression::Not(Box::new(boolean(rng, depth))),
+        }
+    }
+
+    #[test]
+    fn round_trip_of_expressions() {
+        let mut rng = StdRng::seed_from_u64(27);
+        for format in formats() {
+            for _ in 0..CASES {
+                let expr = if rng.gen_bool(0.8) {
+                    number(&mut rng, DEPTH)
+                } else {
+                    boolean(&mut rng, DEPTH)
+                };
+                if let Err(e) = round_trip(&expr, &format) {
+                    panic!("{} (format {:?})", e, format);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn magnitudes_are_parsed() {
+        let mut rng = StdRng::seed_from_u64(27);
+        let units = ["", "m", "V", "ohm", "m/s"];
+        for format in formats() {
+            for _ in 0..CASES {
+                let value = rng.gen_range(-1e4..1e4) * 10f64.powi(rng.gen_range(-6..4));
+                let unit = units[rng.gen_range(0..units.len())].to_string();
+                let uncertainty = if rng.gen_bool(0.5) {
+                    value.abs() * rng.gen_range(0.001..0.1)
+                } else {
+                    0.0
+                };
+                let magnitude = Magnitude::new(value, unit).with_uncertainty(uncertainty);
+                let latex = magnitude.show(&format);
+                assert!(
+                    parse(&latex).is_some(),
+                    "{:?} of {:?} can't be parsed",
+                    latex,
+                    magnitude
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn styles_are_parsed() {
+        let format = Format {
+            product: ProductStyle::Times,
+            division: DivisionStyle::Inline,
+            digits: Digits::Significant(3),
+            notation: Notation::Scientific,
+            prefix: false,
+            decimal: DecimalStyle::Comma,
+            numeric: Numeric::Float,
+            ..Format::new()
+        };
+        let magnitude = Magnitude::new(-1234.0, String::from("V")).with_uncertainty(30.0);
+        let latex = magnitude.show(&format);
+        assert_eq!(latex, "(-1{,}23 \\pm 0{,}03) \\times 10^{3}\\,\\mathrm{V}");
+        let parsed = parse(&latex).unwrap().value(&DictVariables::new());
+        assert!(same(parsed.value, -1230.0));
+    }
+}
@@ -0,0 +1,225 @@
+// List of macros (command "macros")
+//
+// Every macro available to an exercise (or in the macros files of the
+// paths): file, rpn body, stack arity with units and documentation.
+//
+
+use anyhow::{bail, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use yaml_rust::Yaml;
+
+use crate::exercise::load_document;
+use crate::expression::Expression;
+use crate::macros::{locate_file, Macros};
+use crate::metadata::json_string;
+use crate::passage::is_macros;
+
+struct Entry {
+    name: String,
+    file: PathBuf,
+    rpn: String,
+    inputs: Vec<Option<String>>, // units of operands (deepest first)
+    outputs: Vec<Option<String>>,
+    doc: Option<String>,
+}
+
+/// Prints the macros of files (exercises or macros files) or, without
+/// files, of the macros files (macros*.yaml) in paths
+pub fn run(files: Vec<String>, mut paths: Vec<String>, json: bool) -> Result<()> {
+    let mut macros = Macros::new();
+
+    if files.is_empty() {
+        macros.add_paths(paths.clone());
+        macros.include_macros(macros_files(&paths)?);
+    }
+
+    for name in files {
+        let file = locate_file(Path::new(&name), &paths)?;
+        let doc = &load_document(&file)?;
+
+        if doc["passages"].is_badvalue() {
+            macros.add_paths(paths.clone());
+            macros.include_macros(vec![name]);
+        } else {
+            // the macros of an exercise as Exercise::load_exercise
+            paths.insert(0, file.parent().unwrap().to_str().unwrap().to_string());
+            macros.add_paths(paths.clone());
+            if let Some(units_files) = is_macros("units", doc) {
+                macros.include_units(units_files);
+            }
+            include_directives(doc, &mut macros);
+        }
+    }
+
+    let mut entries: Vec<Entry> = macros
+        .info
+        .iter()
+        .map(|(name, info)| entry(name, &info.file, &info.doc, &macros))
+        .collect();
+    entries.sort_by(|a, b| (&a.file, &a.name).cmp(&(&b.file, &b.name)));
+
+    if json {
+        println!("{}", show_json(&entries));
+    } else {
+        print!("{}", show_text(&entries));
+    }
+    Ok(())
+}
+
+// paths and macros directives of the exercise and its passages
+fn include_directives(yaml: &Yaml, macros: &mut Macros) {
+    match yaml {
+        Yaml::Hash(hash) => {
+            if let Some(paths) = is_macros("paths", yaml) {
+                macros.add_paths(paths);
+            }
+            if let Some(macros_files) = is_macros("macros", yaml) {
+                macros.include_macros(macros_files);
+            }
+            for value in hash.values() {
+                include_directives(value, macros);
+            }
+        }
+        Yaml::Array(items) => {
+            for item in items {
+                include_directives(item, macros);
+            }
+        }
+        _ => {}
+    }
+}
+
+// names of macros files (located through paths as the macros directive)
+fn macros_files(paths: &[String]) -> Result<Vec<String>> {
+    let mut output: Vec<String> = vec![];
+    for path in paths {
+        for entry in fs::read_dir(path)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if name.starts_with("macros") && name.ends_with(".yaml") && !output.contains(&name) {
+                output.push(name);
+            }
+        }
+    }
+    if output.is_empty() {
+        bail!("No macros files (macros*.yaml) in paths {:?}", paths)
+    }
+    output.sort();
+    Ok(output)
+}
+
+fn entry(name: &str, file: &Path, doc: &Option<String>, macros: &Macros) -> Entry {
+    let rpn = macros.macros[name].clone();
+    let (arity, results) = Expression::stack_effect(&rpn, macros);
+
+    Entry {
+        name: name.to_string(),
+        file: file.to_path_buf(),
+        inputs: (1..=arity)
+            .map(|i| {
+                let placeholder = format!("${}", i);
+                results
+                    .iter()
+                    .find_map(|expr| input_unit(expr, &placeholder))
+            })
+            .collect(),
+        outputs: results.iter().map(output_unit).collect(),
+        rpn: rpn.split_whitespace().collect::<Vec<_>>().join(" "),
+        doc: doc.clone(),
+    }
+}
+
+// unit assigned to operand (placeholder) with ":"
+fn input_unit(expr: &Expression, placeholder: &str) -> Option<String> {
+    match expr {
+        Expression::Unit(value, unit) if matches!(&**value, Expression::Variable(name) if name == placeholder) => {
+            Some(unit.clone())
+        }
+        _ => expr
+            .operands()
+            .into_iter()
+            .find_map(|expr| input_unit(expr, placeholder)),
+    }
+}
+
+fn output_unit(result: &Expression) -> Option<String> {
+    match result {
+        Expression::Unit(_, unit) | Expression::Convert(_, unit, _) => Some(unit.clone()),
+        _ => None,
+    }
+}
+
+fn show_unit(unit: &Option<String>) -> String {
+    match unit.as_deref() {
+        None => String::from("¿?"),
+        Some("") => String::from("1"),
+        Some(unit) => unit.to_string(),
+    }
+}
+
+fn show_text(entries: &[Entry]) -> String {
+    let mut output = String::new();
+    let mut file = None;
+
+    for entry in entries {
+        if file != Some(&entry.file) {
+            output.push_str(&format!("\n# {}\n", entry.file.display()));
+            file = Some(&entry.file);
+        }
+        let units = |units: &[Option<String>]| -> String {
+            units
+                .iter()
+                .map(|unit| format!("{} ", show_unit(unit)))
+                .collect()
+        };
+        output.push_str(&format!(
+            "{} ( {}-- {})\n    {}\n",
+            entry.name,
+            units(&entry.inputs),
+            units(&entry.outputs),
+            entry.rpn
+        ));
+        if let Some(doc) = &entry.doc {
+            for line in doc.lines() {
+                output.push_str(&format!("    {}\n", line));
+            }
+        }
+    }
+    output
+}
+
+fn show_json(entries: &[Entry]) -> String {
+    let units = |units: &[Option<String>]| -> String {
+        let units: Vec<String> = units
+            .iter()
+            .map(|unit| match unit {
+                Some(unit) => json_string(unit),
+                None => String::from("null"),
+            })
+            .collect();
+        format!("[{}]", units.join(", "))
+    };
+
+    let entries: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "  {{\"name\": {}, \"file\": {}, \"rpn\": {}, \"inputs\": {}, \"outputs\": {}, \"doc\": {}}}",
+                json_string(&entry.name),
+                json_string(&entry.file.to_string_lossy()),
+                json_string(&entry.rpn),
+                units(&entry.inputs),
+                units(&entry.outputs),
+                match &entry.doc {
+                    Some(doc) => json_string(doc),
+                    None => String::from("null"),
+                }
+            )
+        })
+        .collect();
+    if entries.is_empty() {
+        String::from("[]")
+    } else {
+        format!("[\n{}\n]", entries.join(",\n"))
+    }
+}
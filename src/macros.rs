@@ -4,7 +4,15 @@ use anyhow::{bail, Result};
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
-use yaml_rust::YamlLoader;
+use std::rc::Rc;
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::browser::{self, SharedProgram};
+use crate::exercise::load_document;
+use crate::figure::{self, Figure, Templates};
+use crate::passage::is_macros;
+use crate::random::{self, Generator};
+use crate::units::Registry;
 
 //--------------------------------------------
 // Paths
@@ -29,12 +37,100 @@ pub fn locate_file(input: &Path, paths: &[String]) -> Result<PathBuf> {
     }
 }
 
+/// Make rule of target with its dependencies (and an empty rule for each
+/// dependency, so a deleted file doesn't break the build)
+pub fn depfile(target: &Path, dependencies: &[PathBuf]) -> String {
+    let escape = |path: &Path| {
+        path.to_string_lossy()
+            .replace('$', "$$")
+            .replace('#', "\\#")
+            .replace(' ', "\\ ")
+    };
+
+    let mut output = format!("{}:", escape(target));
+    for dependency in dependencies {
+        output.push_str(&format!(" \\\n  {}", escape(dependency)));
+    }
+    output.push('\n');
+    for dependency in dependencies {
+        output.push_str(&format!("\n{}:\n", escape(dependency)));
+    }
+    output
+}
+
+/// Macros, units and figures files of the exercise in input (located with
+/// paths after the directory of input, as Exercise::load_exercise). It is an
+/// error if a macros or units file can't be located
+pub fn exercise_dependencies(input: &Path, paths: &[String]) -> Result<Vec<PathBuf>> {
+    let doc = load_document(input)?;
+    let mut paths = paths.to_vec();
+    paths.insert(0, input.parent().unwrap().to_str().unwrap().to_string());
+    let mut files = vec![];
+    dependencies(&doc, &paths, &mut files)?;
+    Ok(files)
+}
+
+/// Macros and units files used by the directives of an exercise (and its
+/// passages) and figures of its texts. The directives are scoped as in the
+/// conversion of passages: a paths directive applies to the next elements of
+/// its sequence, not to the branches beside it nor after the sequence
+pub fn dependencies(yaml: &Yaml, paths: &[String], output: &mut Vec<PathBuf>) -> Result<()> {
+    let mut paths = paths.to_vec();
+    match yaml {
+        Yaml::Hash(hash) => {
+            if let Some(new_paths) = is_macros("paths", yaml) {
+                paths.extend(new_paths);
+            }
+            for tag in &["macros", "units"] {
+                for file in is_macros(tag, yaml).unwrap_or_default() {
+                    output.push(locate_file(Path::new(&file), &paths)?);
+                }
+            }
+            for value in hash.values() {
+                dependencies(value, &paths, output)?;
+            }
+        }
+        Yaml::Array(items) => {
+            for item in items {
+                if let Some(new_paths) = is_macros("paths", item) {
+                    paths.extend(new_paths);
+                }
+                dependencies(item, &paths, output)?;
+            }
+        }
+        Yaml::String(text) => {
+            for line in text.lines() {
+                // the name of a figure can have values ({{ }}): it is located
+                // when the exercise is built
+                let figure = line.strip_prefix(figure::MARKER).and_then(Figure::from);
+                if let Some(Ok(figure)) = figure.map(|figure| figure.locate(&paths)) {
+                    output.push(figure.file);
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
 //--------------------------------------------
 // Macros
 
+// Where a macro was defined and its documentation
+#[derive(Clone, Debug)]
+pub struct MacroInfo {
+    pub file: PathBuf,
+    pub doc: Option<String>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Macros {
     pub macros: HashMap<String, String>,
+    pub info: HashMap<String, MacroInfo>,
+    pub units: Rc<Registry>,    // units of the scope
+    pub rng: Generator,         // seeds of rand (see random)
+    pub program: SharedProgram, // values evaluated in the browser (see browser)
+    pub templates: Templates,   // instantiated figures (see figure)
     paths: Vec<String>,
 }
 
@@ -42,6 +138,11 @@ impl Macros {
     pub fn new() -> Self {
         Macros {
             macros: HashMap::new(),
+            info: HashMap::new(),
+            units: Rc::new(Registry::builtin()),
+            rng: random::generator(0),
+            program: browser::program(false),
+            templates: Templates::default(),
             paths: Vec::new(),
         }
     }
@@ -52,21 +153,56 @@ impl Macros {
         }
     }
 
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
     pub fn include_macros(&mut self, macros_files: Vec<String>) {
         for file in macros_files {
             let file = locate_file(Path::new(&file), &self.paths)
                 .unwrap_or_else(|_| panic!("file {:?} not found", file));
             let contents =
-                fs::read_to_string(file).unwrap_or_else(|_| panic!("Unable to read file"));
+                fs::read_to_string(&file).unwrap_or_else(|_| panic!("Unable to read file"));
             let docs = YamlLoader::load_from_str(&contents).unwrap();
-            let doc = &docs[0];
+            let doc = match docs.first() {
+                Some(doc) => doc,
+                None => continue, // empty file
+            };
 
+            // name: "rpn" or name: { rpn: "rpn", doc: "documentation" }
             for (key, value) in doc.as_hash().unwrap() {
-                self.macros.insert(
-                    key.as_str().unwrap().to_string(),
-                    value.as_str().unwrap().to_string(),
+                let name = key.as_str().unwrap().to_string();
+                let (body, doc) = match value {
+                    Yaml::String(body) => (body.to_string(), None),
+                    Yaml::Hash(..) => (
+                        value["rpn"]
+                            .as_str()
+                            .unwrap_or_else(|| panic!("Macro {} without rpn", name))
+                            .to_string(),
+                        value["doc"].as_str().map(|doc| doc.trim().to_string()),
+                    ),
+                    _ => panic!("Macro {} isn't a string", name),
+                };
+                self.macros.insert(name.clone(), body);
+                self.info.insert(
+                    name,
+                    MacroInfo {
+                        file: file.clone(),
+                        doc,
+                    },
                 );
             }
         }
     }
+
+    /// Adds the units of files to the registry of units
+    pub fn include_units(&mut self, units_files: Vec<String>) {
+        for file in units_files {
+            let file = locate_file(Path::new(&file), &self.paths)
+                .unwrap_or_else(|_| panic!("file {:?} not found", file));
+            Rc::make_mut(&mut self.units)
+                .load(&file)
+                .unwrap_or_else(|e| panic!("Unable to load units: {}", e));
+        }
+    }
 }
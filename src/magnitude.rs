@@ -1,26 +1,51 @@
 // Magnitude descriptions
 //
-// a number with units
+// a number with units (and, optionally, its uncertainty: 10.0±0.2V)
 //
 // units are simply metadata, there is no verification in operations
 // only operations:
 //   normalize: move multiples to "fundamental". But only First unit:
 //     Ex: 1km/s => 1000m/s, but 1m/ms is not 1000m/s
-//   display: prettyprint in mathJax
+//     Units defined in registry (km/h, kWh, ...) are kept as they are
+//   display: prettyprint in mathJax (see Format for the options)
+//     magnitudes with uncertainty are rounded to the significant figures
+//     of uncertainty: (10.0 \pm 0.2)\,\mathrm{V}
+//     with numeric=rational, exact values are shown as fractions: \frac{1}{3}
+//
+// the exact value (rational) is kept while operations allow it (see Rational)
 //
 
 use lazy_static::lazy_static;
-use maplit::hashmap;
 use regex::Regex;
 use std::cmp::Ordering;
-use std::collections::HashMap;
 use std::fmt;
 
+use crate::format::{Digits, Format, Notation, Numeric, Tolerance};
+use crate::rational::Rational;
+use crate::units::Registry;
+
 // TYPES
 type ValueType = f64;
 
+// tolerance of rounding errors choosing prefix or exponent
+pub const EPSILON: ValueType = 1e-9;
+
 // TABLES
 
+// Factors (ordered from greater to lower)
+const FACTORS: [(char, ValueType); 10] = [
+    ('T', 1e12),
+    ('G', 1e9),
+    ('M', 1e6),
+    ('k', 1e3),
+    ('#', 1.0),
+    ('m', 1e-3),
+    ('u', 1e-6),
+    ('n', 1e-9),
+    ('p', 1e-12),
+    ('f', 1e-15),
+];
+
 lazy_static! {
 // Regex
     static ref RE_MAGNITUDE: Regex =
@@ -33,74 +58,102 @@ lazy_static! {
                     (e\d+)?            # 5
                    )
                    )
-                   (.*)                # 6 units
+                   (±                  # 6 uncertainty
+                    (\d+(\.\d+)?(e\d+)?) # 7 8 9
+                   )?
+                   (.*)                # 10 units
                    \s* $
                    ").unwrap();
 
-
-// Factors
-            static ref FACTORS : HashMap<char,ValueType> = hashmap!{
-                'T' => 1e12,
-                'G' => 1e9,
-                'M' => 1e6,
-                'k' => 1e3,
-                '#' => 1.0,
-                'm' => 1e-3,
-                'u' => 1e-6,
-                'n' => 1e-9,
-                'p' => 1e-12,
-                'f' => 1e-15,
-            };
-
-// Units
-            static ref UNITS: HashMap<&'static str,&'static str> = hashmap!{
-                "ohm" => "\\Omega",
-            };
-
 // Booleans constants
             pub static ref TRUE: Magnitude = Magnitude::new(1.0,String::from("bool"));
             pub static ref FALSE: Magnitude = Magnitude::new(0.0,String::from("bool"));
         }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub struct Magnitude {
     pub value: ValueType,
+    pub uncertainty: ValueType, // absolute (0 if value is exact)
     pub unit: String,
+    pub rational: Option<Rational>, // exact value (None if unknown)
 }
 
 impl Magnitude {
-    // try to process string into Magnitude.
-    pub fn get(string: &str) -> Option<Self> {
+    // try to process string into Magnitude (its unit is normalized with units)
+    pub fn get(string: &str, units: &Registry) -> Option<Self> {
         let without_underline = string.replace("_", "");
-        RE_MAGNITUDE
-            .captures(&without_underline)
-            .map(|cap| Magnitude::new(cap[1].parse::<ValueType>().unwrap(), cap[6].to_string()))
+        RE_MAGNITUDE.captures(&without_underline).map(|cap| {
+            let uncertainty = cap
+                .get(7)
+                .map_or(0.0, |u| u.as_str().parse::<ValueType>().unwrap());
+            (Magnitude {
+                value: cap[1].parse::<ValueType>().unwrap(),
+                uncertainty,
+                unit: cap[10].to_string(),
+                rational: Rational::from_decimal(&cap[1]),
+            })
+            .normalize(units)
+        })
     }
 
+    /// Magnitude with value in unit (unit is kept as it is: see normalize)
     pub fn new(value: ValueType, unit: String) -> Self {
-        (Magnitude { value, unit }).normalize()
+        Magnitude {
+            value,
+            uncertainty: 0.0,
+            unit,
+            rational: Rational::from_integer(value),
+        }
     }
 
-    // Normalize
+    pub fn with_uncertainty(self, uncertainty: ValueType) -> Self {
+        Magnitude {
+            uncertainty: uncertainty.abs(),
+            ..self
+        }
+    }
+
+    /// self with exact value rational (if it is known)
+    pub fn with_rational(self, rational: Option<Rational>) -> Self {
+        match rational {
+            Some(rational) => Magnitude {
+                value: rational.value(),
+                rational: Some(rational),
+                ..self
+            },
+            None => self,
+        }
+    }
+
+    // Normalize (with the registry of units)
     // TODO: add other factors
-    fn normalize(self) -> Self {
+    pub fn normalize(self, units: &Registry) -> Self {
         let mut value = self.value;
-        let mut unit = self.unit;
+        let mut uncertainty = self.uncertainty;
+        let mut rational = self.rational;
+        let mut unit = units.canonical(&self.unit);
 
-        let units: Vec<char> = unit.chars().collect();
-        if units.len() > 1 {
-            let first = units[0];
-            let second = units[1];
+        let chars: Vec<char> = unit.chars().collect();
+        if chars.len() > 1 && !units.is_defined(&unit) {
+            let first = chars[0];
+            let second = chars[1];
 
-            if ('a'..='z').contains(&second) || ('A'..='Z').contains(&second) {
-                if let Some(factor) = FACTORS.get(&first) {
+            if second.is_alphabetic() {
+                if let Some((_, factor)) = FACTORS.iter().find(|(name, _)| *name == first) {
                     value *= factor;
-                    unit = unit[1..].to_owned();
+                    uncertainty *= factor;
+                    rational = rational.and_then(|r| r.mul(&Rational::from_factor(*factor)?));
+                    unit = units.canonical(&unit[first.len_utf8()..]);
                 }
             }
         }
 
-        Magnitude { value, unit }
+        Magnitude {
+            value,
+            uncertainty,
+            unit,
+            rational,
+        }
     }
 
     /// Determines if self and b have compatible unit (same unit or unit and unknown)
@@ -115,6 +168,13 @@ impl Magnitude {
         }
     }
 
+    /// Determines if self and other have the same unit and values (with tolerance)
+    pub fn close(&self, other: &Self, tolerance: &Tolerance) -> bool {
+        let greater = self.value.abs().max(other.value.abs());
+        self.unit == other.unit
+            && (self.value - other.value).abs() <= tolerance.absolute + tolerance.relative * greater
+    }
+
     pub fn compatible_unit_str(&self, unit: &str) -> Option<String> {
         if self.unit == "¿?" {
             Some(unit.to_string())
@@ -128,11 +188,17 @@ impl Magnitude {
     }
 }
 
+// uncertainty isn't compared (10±0.2V is equal to 10V) and unknown unit
+// is compatible with any unit
+impl PartialEq for Magnitude {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value && self.compatible_unit(other).is_some()
+    }
+}
+
 impl PartialOrd for Magnitude {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        if self.unit != other.unit {
-            return None;
-        }
+        self.compatible_unit(other)?;
 
         self.value.partial_cmp(&other.value)
     }
@@ -140,42 +206,273 @@ impl PartialOrd for Magnitude {
 
 impl fmt::Display for Magnitude {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        let mut scaled: ValueType = 0.0;
-        let mut new_unit: String;
+        write!(formatter, "{}", self.show(&Format::new()))
+    }
+}
+
+impl Magnitude {
+    /// LaTeX of magnitude with format
+    pub fn show(&self, format: &Format) -> String {
+        let unit = format.units.symbol(&self.unit);
+        let has_prefix = !unit.is_empty() && format.units.admits_prefix(&self.unit);
+
+        let sign_str = if self.value < 0.0 { "-" } else { "" };
+        let value_abs = self.value.abs();
 
-        if let Some(pretty) = UNITS.get(&*self.unit) {
-            new_unit = (*pretty).to_owned();
+        if !value_abs.is_finite() {
+            let number = if value_abs.is_nan() { "NaN" } else { "\\infty" };
+            return with_unit(format!("{}{}", sign_str, number), "", &unit);
+        }
+
+        if let Some(rational) = self.rational.filter(|_| self.is_fraction(format)) {
+            return show_fraction(rational, has_prefix, &unit, format);
+        }
+
+        // a value rounded to 0 with the digits of format (0.00001 with round=2)
+        // is shown in scientific notation
+        let scientific;
+        let format = if self.vanishes(has_prefix, format) {
+            scientific = Format {
+                notation: Notation::Scientific,
+                ..format.clone()
+            };
+            &scientific
         } else {
-            new_unit = self.unit.clone();
+            format
+        };
+
+        // the rounding can move the value to next prefix/exponent (999.999 -> 1000)
+        let (number, prefix, exponent) = scale(value_abs, has_prefix, format);
+        let shown = self.shown_digits(prefix, exponent, format);
+        let rounded_abs = round(number, shown) * factor(prefix) * 10f64.powi(exponent);
+        let (number, prefix, exponent) = scale(rounded_abs, has_prefix, format);
+        let shown = self.shown_digits(prefix, exponent, format);
+        let number = round(number, shown);
+
+        let mut number = format!("{}{}", sign_str, digits(number, shown));
+        if self.uncertainty > 0.0 {
+            let uncertainty = self.uncertainty / (factor(prefix) * 10f64.powi(exponent));
+            let uncertainty = digits(round(uncertainty, shown), shown);
+            number = format!("({} \\pm {})", number, uncertainty);
+        }
+        number = number.replace('.', format.decimal_latex());
+        if exponent != 0 {
+            number = format!("{} {} 10^{{{}}}", number, format.product_latex(), exponent);
         }
 
-        let sign_str: &str;
-        let value_abs: ValueType;
+        let prefix = prefix_latex(prefix);
 
-        if self.value >= 0.0 {
-            sign_str = "";
-            value_abs = self.value;
+        if self.uncertainty > 0.0 && !unit.is_empty() {
+            format!("{}\\,\\mathrm{{{}{}}}", number, prefix, unit)
         } else {
-            sign_str = "-";
-            value_abs = -1.0 * self.value;
+            with_unit(number, &prefix, &unit)
         }
+    }
 
-        // not very elegant but, works!
-        for (factor_name, factor_value) in FACTORS.iter() {
-            scaled = value_abs / factor_value;
-            if (1.0..1e3).contains(&scaled) {
-                if *factor_name != '#' {
-                    new_unit = format!("{}{}", factor_name, new_unit);
-                }
-                break;
-            }
+    /// self is shown as a fraction (exact value, not integer, with numeric=rational)
+    pub fn is_fraction(&self, format: &Format) -> bool {
+        format.numeric == Numeric::Rational
+            && self.uncertainty == 0.0
+            && self.rational.is_some_and(|r| !r.is_integer())
+    }
+
+    /// self isn't 0 but it is shown as 0 with the digits of format (the
+    /// uncertainty can hide a value: (0.0 \pm 0.3))
+    fn vanishes(&self, has_prefix: bool, format: &Format) -> bool {
+        if self.value == 0.0 || self.uncertainty > 0.0 || format.notation != Notation::Fixed {
+            return false;
         }
+        let (number, prefix, exponent) = scale(self.value.abs(), has_prefix, format);
+        round(number, self.shown_digits(prefix, exponent, format)) == 0.0
+    }
 
-        scaled = (scaled * 100.0).round() / 100.0;
-        if new_unit.is_empty() {
-            write!(formatter, "{}{:}", sign_str, scaled)
+    /// digits to show: the ones of format or, with uncertainty, the decimals
+    /// of uncertainty (scaled with prefix and exponent) rounded to 1 significant
+    /// figure (2 if first figure is 1)
+    fn shown_digits(&self, prefix: char, exponent: i32, format: &Format) -> Digits {
+        if self.uncertainty > 0.0 {
+            let uncertainty = self.uncertainty / (factor(prefix) * 10f64.powi(exponent));
+            let first = uncertainty / 10f64.powi(order(uncertainty));
+            let figures = if first < 2.0 - EPSILON { 2 } else { 1 };
+            Digits::Decimals(significant_decimals(uncertainty, figures))
         } else {
-            write!(formatter, "{}{:}\\mathrm{{{}}}", sign_str, scaled, new_unit)
+            format.digits
         }
     }
 }
+
+/// exact value as a fraction (scaled with the prefix): -\frac{1}{3}\mathrm{mA}
+fn show_fraction(rational: Rational, has_prefix: bool, unit: &str, format: &Format) -> String {
+    let sign_str = if rational.num < 0 { "-" } else { "" };
+    let (_, prefix, _) = scale(rational.value().abs(), has_prefix, format);
+    let scaled = Rational::from_factor(factor(prefix))
+        .and_then(|f| rational.div(&f))
+        .filter(|_| prefix != '#');
+    let (rational, prefix) = match scaled {
+        Some(scaled) => (scaled, prefix),
+        None => (rational, '#'),
+    };
+
+    let number = if rational.is_integer() {
+        format!("{}{}", sign_str, rational.num.abs())
+    } else {
+        format!(
+            "{}\\frac{{{}}}{{{}}}",
+            sign_str,
+            rational.num.abs(),
+            rational.den
+        )
+    };
+    with_unit(number, &prefix_latex(prefix), unit)
+}
+
+fn prefix_latex(prefix: char) -> String {
+    match prefix {
+        '#' => String::new(),
+        'u' => String::from("\\mu "),
+        p => p.to_string(),
+    }
+}
+
+fn with_unit(number: String, prefix: &str, unit: &str) -> String {
+    if unit.is_empty() {
+        number
+    } else {
+        format!("{}\\mathrm{{{}{}}}", number, prefix, unit)
+    }
+}
+
+fn factor(prefix: char) -> ValueType {
+    FACTORS
+        .iter()
+        .find(|(name, _)| *name == prefix)
+        .map(|(_, value)| *value)
+        .unwrap_or(1.0)
+}
+
+/// prefixes (LaTeX) and their factors, from greater to lower
+pub fn prefixes() -> Vec<(String, ValueType)> {
+    FACTORS
+        .iter()
+        .map(|(name, value)| (prefix_latex(*name), *value))
+        .collect()
+}
+
+/// factor of a SI prefix (k, M, m, ...)
+pub fn prefix_factor(prefix: char) -> Option<ValueType> {
+    FACTORS
+        .iter()
+        .find(|(name, _)| *name == prefix && *name != '#')
+        .map(|(_, value)| *value)
+}
+
+/// splits value (positive) in (number, prefix, exponent): value = number * prefix * 10^exponent
+fn scale(value: ValueType, has_prefix: bool, format: &Format) -> (ValueType, char, i32) {
+    if value == 0.0 {
+        return (value, '#', 0);
+    }
+
+    if format.prefix && has_prefix {
+        for (name, factor) in FACTORS.iter() {
+            let scaled = value / factor;
+            if (1.0 - EPSILON..1e3 * (1.0 - EPSILON)).contains(&scaled) {
+                return (scaled, *name, 0);
+            }
+        }
+    }
+
+    let magnitude_order = order(value);
+    let exponent = match format.notation {
+        Notation::Fixed => 0,
+        Notation::Scientific => magnitude_order,
+        Notation::Engineering => 3 * magnitude_order.div_euclid(3),
+    };
+
+    (value / 10f64.powi(exponent), '#', exponent)
+}
+
+/// exponent of the power of 10 of value (positive): 0.05 -> -2, 123 -> 2
+fn order(value: ValueType) -> i32 {
+    (value.log10() + EPSILON).floor() as i32
+}
+
+fn significant_decimals(value: ValueType, figures: usize) -> usize {
+    if value == 0.0 {
+        figures - 1
+    } else {
+        (figures as i32 - 1 - order(value)).max(0) as usize
+    }
+}
+
+fn round(value: ValueType, digits: Digits) -> ValueType {
+    let decimals = match digits {
+        Digits::Round(n) | Digits::Decimals(n) => n as i32,
+        Digits::Significant(n) => {
+            if value == 0.0 {
+                return value;
+            }
+            n as i32 - 1 - order(value)
+        }
+    };
+
+    let factor = 10f64.powi(decimals);
+    (value * factor).round() / factor
+}
+
+/// string of value (positive and rounded) with digits
+fn digits(value: ValueType, digits: Digits) -> String {
+    match digits {
+        Digits::Round(_) => format!("{}", value),
+        Digits::Decimals(n) => format!("{:.*}", n, value),
+        Digits::Significant(n) => format!("{:.*}", significant_decimals(value, n), value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn show(magnitude: &str, spec: &str) -> String {
+        Magnitude::get(magnitude, &Registry::builtin())
+            .unwrap()
+            .show(&Format::new().with_spec(spec))
+    }
+
+    #[test]
+    fn small_values_are_not_rounded_to_zero() {
+        assert_eq!(show("0.00001", ""), "1 \\cdot 10^{-5}");
+        assert_eq!(show("0.0000123456", ""), "1.23 \\cdot 10^{-5}");
+        assert_eq!(show("0.004", "decimals=2"), "4.00 \\cdot 10^{-3}");
+        assert_eq!(show("0.00001V", ""), "10\\mathrm{\\mu V}");
+        assert_eq!(show("0.012", ""), "0.01");
+        assert_eq!(show("0", ""), "0");
+    }
+
+    #[test]
+    fn digits_and_notation() {
+        assert_eq!(show("3.14159", ""), "3.14");
+        assert_eq!(show("3.1", "decimals=3"), "3.100");
+        assert_eq!(show("0.00314159", "sig=3"), "0.00314");
+        assert_eq!(show("1234.5", "notation=scientific"), "1.23 \\cdot 10^{3}");
+        assert_eq!(show("12345", "notation=engineering"), "12.35 \\cdot 10^{3}");
+        assert_eq!(
+            show("0.00012345", "notation=engineering, sig=3, product=times"),
+            "123 \\times 10^{-6}"
+        );
+    }
+
+    #[test]
+    fn prefixes_and_decimal_comma() {
+        assert_eq!(show("4700ohm", ""), "4.7\\mathrm{k\\Omega}");
+        assert_eq!(show("4700ohm", "prefix=off"), "4700\\mathrm{\\Omega}");
+        assert_eq!(show("3.14159", "decimal=comma"), "3{,}14");
+        assert_eq!(
+            show("0.0000123456", "decimal=comma"),
+            "1{,}23 \\cdot 10^{-5}"
+        );
+        assert_eq!(
+            show("2.5±0.13V", "decimal=comma"),
+            "(2{,}50 \\pm 0{,}13)\\,\\mathrm{V}"
+        );
+    }
+}
@@ -1,17 +1,40 @@
 use anyhow::{bail, Result};
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 use std::fs::write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+mod analytics;
+mod browser;
+mod build;
+mod catalog;
+mod collect;
+mod equation;
 mod exercise;
 mod expression;
+mod figure;
+mod format;
+#[cfg(test)]
+mod latex;
+mod list_macros;
 mod macros;
 mod magnitude;
+mod markdown;
+mod matrix;
+mod metadata;
 mod passage;
+mod random;
+mod rational;
+mod serve;
+mod simplify;
+mod sourcemap;
+mod statistics;
+mod twp;
+mod units;
 
 mod render;
 mod render_mathjax;
 mod render_reveal;
+mod report;
 
 use crate::exercise::Exercise;
 use crate::render::Render;
@@ -48,12 +71,276 @@ fn main() -> Result<()> {
                 .help("What render use")
                 .short("r")
                 .long("render")
-                .possible_values(&["reveal"])
+                .possible_values(&["reveal", "twee"])
                 .takes_value(true)
                 .default_value("reveal"),
         )
+        .arg(
+            Arg::with_name("depfile")
+                .help("Writes a make rule with the files used by the exercise (input, macros, units and figures)")
+                .long("depfile")
+                .takes_value(true),
+        )
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::InferSubcommands)
+        .subcommand(
+            SubCommand::with_name("build")
+                .about("Builds the exercises of directories or globs (unchanged ones are skipped)")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Directories, globs (\"dir/parsing-*.yaml\") or files")
+                        .required(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .help("Output directory")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("paths")
+                        .help("Paths to look for sources and macros files")
+                        .short("p")
+                        .long("path")
+                        .takes_value(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .help("Exercises built at the same time (default: number of cpus)")
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Builds and serves exercises, rebuilding and reloading them on changes")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Exercise or directory of exercises")
+                        .required(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .help("Output directory (default: a temporary directory)")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("assets")
+                        .help("Directory of reveal.js assets (dist, plugin)")
+                        .short("a")
+                        .long("assets")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("paths")
+                        .help("Paths to look for sources and macros files")
+                        .short("p")
+                        .long("path")
+                        .takes_value(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("port")
+                        .help("Port of the server")
+                        .long("port")
+                        .takes_value(true)
+                        .default_value("8000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Converts an exercise between twp and yaml formats")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Exercise (.twp or .yaml)")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .help("Converted exercise (.yaml or .twp)")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("catalog")
+                .about("Writes a catalogue (html and json) of the exercises with their metadata")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Directories, globs (\"dir/parsing-*.yaml\") or files")
+                        .required(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .help("Output directory (the one of build)")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("collect")
+                .about("Receives the events of analytics of the exercises and stores them (JSON lines)")
+                .arg(
+                    Arg::with_name("output")
+                        .help("File of the events")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .default_value("events.jsonl"),
+                )
+                .arg(
+                    Arg::with_name("port")
+                        .help("Port of the receiver")
+                        .long("port")
+                        .takes_value(true)
+                        .default_value("8001"),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("report")
+                .about("Summarizes the events of analytics: error rates of steps and bad gates chosen")
+                .arg(
+                    Arg::with_name("EVENTS")
+                        .help("File of the events (of collect)")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("maps")
+                        .help("Directories with the maps of the outputs (*.map.json)")
+                        .short("m")
+                        .long("maps")
+                        .takes_value(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("macros")
+                .about("Lists the macros of an exercise or of the macros files in paths")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("Exercises or macros files (default: macros*.yaml in paths)")
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("paths")
+                        .help("Paths to look for sources and macros files")
+                        .short("p")
+                        .long("path")
+                        .takes_value(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .help("Output in JSON format")
+                        .long("json"),
+                ),
+        )
         .get_matches();
 
+    if let Some(args) = args.subcommand_matches("build") {
+        let mut paths: Vec<String> = vec![];
+        if let Some(p) = args.values_of("paths") {
+            paths.extend(p.map(|x| x.to_string()));
+        }
+        paths.insert(0, String::from("."));
+
+        let jobs = match args.value_of("jobs") {
+            Some(jobs) => jobs.parse()?,
+            None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        let inputs = args.values_of("INPUT").unwrap().map(|x| x.to_string());
+        return build::run(
+            inputs.collect(),
+            Path::new(args.value_of("output").unwrap()),
+            paths,
+            jobs,
+        );
+    }
+
+    if let Some(args) = args.subcommand_matches("serve") {
+        let mut paths: Vec<String> = vec![];
+        if let Some(p) = args.values_of("paths") {
+            paths.extend(p.map(|x| x.to_string()));
+        }
+        paths.insert(0, String::from("."));
+
+        let output_dir = match args.value_of("output") {
+            Some(output) => PathBuf::from(output),
+            None => std::env::temp_dir().join("tweeproblems-serve"),
+        };
+        let inputs = args.values_of("INPUT").unwrap().map(|x| x.to_string());
+        return serve::run(
+            inputs.collect(),
+            &output_dir,
+            args.value_of("assets").map(Path::new),
+            paths,
+            args.value_of("port").unwrap().parse()?,
+        );
+    }
+
+    if let Some(args) = args.subcommand_matches("convert") {
+        let input = check_input_file(Path::new(args.value_of("INPUT").unwrap()))?;
+        let output = Path::new(args.value_of("OUTPUT").unwrap());
+        let doc = exercise::load_document(input)?;
+        let converted = match output.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") => twp::emit_yaml(&doc),
+            Some("twp") => twp::from_yaml(&doc)?,
+            _ => bail!("OUTPUT file extension isn't yaml or twp."),
+        };
+        write(output, converted)?;
+        return Ok(());
+    }
+
+    if let Some(args) = args.subcommand_matches("catalog") {
+        let inputs = args.values_of("INPUT").unwrap().map(|x| x.to_string());
+        return catalog::run(
+            inputs.collect(),
+            Path::new(args.value_of("output").unwrap()),
+        );
+    }
+
+    if let Some(args) = args.subcommand_matches("collect") {
+        return collect::run(
+            Path::new(args.value_of("output").unwrap()),
+            args.value_of("port").unwrap().parse()?,
+        );
+    }
+
+    if let Some(args) = args.subcommand_matches("report") {
+        let maps = match args.values_of("maps") {
+            Some(maps) => maps.map(PathBuf::from).collect(),
+            None => vec![],
+        };
+        return report::run(Path::new(args.value_of("EVENTS").unwrap()), &maps);
+    }
+
+    if let Some(args) = args.subcommand_matches("macros") {
+        let mut paths: Vec<String> = vec![];
+        if let Some(p) = args.values_of("paths") {
+            paths.extend(p.map(|x| x.to_string()));
+        }
+        paths.insert(0, String::from("."));
+
+        let files = match args.values_of("FILE") {
+            Some(files) => files.map(|x| x.to_string()).collect(),
+            None => vec![],
+        };
+        return list_macros::run(files, paths, args.is_present("json"));
+    }
+
     let mut paths: Vec<String> = vec![];
     if let Some(p) = args.values_of("paths") {
         paths.extend(p.map(|x| x.to_string()));
@@ -61,11 +348,16 @@ fn main() -> Result<()> {
     paths.insert(0, String::from("."));
 
     let mut reveal;
+    let mut twee;
     let renderer: &mut dyn Render = match args.value_of("render").unwrap() {
         "reveal" => {
             reveal = Reveal::new();
             &mut reveal
         }
+        "twee" => {
+            twee = MathJax {};
+            &mut twee
+        }
         _ => panic!("unknown"),
     };
 
@@ -77,11 +369,25 @@ fn main() -> Result<()> {
     println!("input file: {:?}", input_file);
     println!("output file: {:?}", output_file);
 
-    let exercise = Exercise::load_exercise(&input_file, paths)?;
+    let exercise = Exercise::load_exercise(&input_file, paths.clone())?;
 
-    let render = exercise.render(renderer);
+    let (render, context) = exercise.render(renderer);
 
-    write(output_file, render)?;
+    write(&output_file, render)?;
+    figure::copy_figures(&output_file, &context.figures)?;
+    sourcemap::write(
+        &output_file,
+        &input_file,
+        &exercise,
+        renderer,
+        &context.passages,
+    )?;
+
+    if let Some(depfile) = args.value_of("depfile") {
+        let mut dependencies = vec![input_file.clone()];
+        dependencies.extend(macros::exercise_dependencies(&input_file, &paths)?);
+        write(depfile, macros::depfile(&output_file, &dependencies))?;
+    }
 
     Ok(())
 }
@@ -91,8 +397,8 @@ fn check_input_file(input: &Path) -> Result<&Path> {
     match input.extension() {
         None => bail!("INPUT file without extension"),
         Some(ext) => {
-            if ext != "yaml" {
-                bail!("INPUT file extension isn't yaml.")
+            if ext != "yaml" && ext != "twp" {
+                bail!("INPUT file extension isn't yaml or twp.")
             }
         }
     }
@@ -0,0 +1,151 @@
+// Markdown (CommonMark) of the texts of gates
+//
+// The text, follow and note of the gates are written in markdown: lists,
+// emphasis, code, tables, links... The math of the text (the [[[ ]]] and
+// ((( ))) markers of the interpolations and the \( \), \[ \] and $$ $$
+// written by hand) is kept out of the markdown parser, so the renderer
+// receives it unchanged.
+//
+
+use lazy_static::lazy_static;
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+use regex::Regex;
+
+/// HTML of markdown text
+pub fn to_html(text: &str) -> String {
+    let (text, math) = protect(text);
+
+    let mut output = String::new();
+    html::push_html(&mut output, Parser::new_ext(&text, options()));
+
+    restore(&output, &math)
+}
+
+/// Twee (SugarCube markup) of markdown text
+pub fn to_twee(text: &str) -> String {
+    let (text, math) = protect(text);
+
+    let mut output = String::new();
+    let mut lists: Vec<Option<u64>> = vec![];
+    let mut targets: Vec<String> = vec![];
+
+    for event in Parser::new_ext(&text, options()) {
+        match event {
+            Event::Start(tag) => match tag {
+                Tag::Heading(level, ..) => output += &"!".repeat(level as usize),
+                Tag::BlockQuote => output += "<blockquote>\n",
+                Tag::CodeBlock(_) => output += "{{{\n",
+                Tag::List(start) => {
+                    if !lists.is_empty() && !output.ends_with('\n') {
+                        output.push('\n');
+                    }
+                    lists.push(start);
+                }
+                Tag::Item => {
+                    let marker = |list: &Option<u64>| if list.is_some() { '#' } else { '*' };
+                    output += &lists.iter().map(marker).collect::<String>();
+                    output.push(' ');
+                }
+                Tag::TableHead | Tag::TableRow => output.push('|'),
+                Tag::Emphasis => output += "//",
+                Tag::Strong => output += "''",
+                Tag::Strikethrough => output += "==",
+                Tag::Link(_, url, _) => {
+                    output += "[[";
+                    targets.push(url.to_string());
+                }
+                Tag::Image(_, url, _) => {
+                    output += "[img[";
+                    targets.push(url.to_string());
+                }
+                _ => {}
+            },
+            Event::End(tag) => match tag {
+                Tag::Paragraph if lists.is_empty() => output += "\n\n",
+                Tag::Paragraph => output.push('\n'),
+                Tag::Heading(..) => output.push('\n'),
+                Tag::BlockQuote => output += "</blockquote>\n",
+                Tag::CodeBlock(_) => output += "}}}\n",
+                Tag::List(_) => {
+                    lists.pop();
+                    if lists.is_empty() {
+                        output.push('\n');
+                    }
+                }
+                Tag::Item if !output.ends_with('\n') => output.push('\n'),
+                Tag::Table(_) => output.push('\n'),
+                Tag::TableHead => output += "h\n",
+                Tag::TableRow => output.push('\n'),
+                Tag::TableCell => output.push('|'),
+                Tag::Emphasis => output += "//",
+                Tag::Strong => output += "''",
+                Tag::Strikethrough => output += "==",
+                Tag::Link(..) | Tag::Image(..) => {
+                    output += &format!("|{}]]", targets.pop().unwrap_or_default());
+                }
+                _ => {}
+            },
+            Event::Text(text) | Event::Html(text) => output += &text,
+            Event::Code(code) => output += &format!("{{{{{{{}}}}}}}", code),
+            Event::SoftBreak => output.push(' '),
+            Event::HardBreak => output.push('\n'),
+            Event::Rule => output += "----\n",
+            _ => {}
+        }
+    }
+
+    restore(&output, &math)
+}
+
+lazy_static! {
+    static ref RE_PLACEHOLDER: Regex = Regex::new("\u{e000}([0-9]+)\u{e001}").unwrap();
+}
+
+fn options() -> Options {
+    Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH
+}
+
+// math of text replaced by placeholders (private use chars, without
+// meaning in markdown)
+fn protect(text: &str) -> (String, Vec<String>) {
+    lazy_static! {
+        static ref RE_MATH: Regex = Regex::new(
+            r"(?xs)                 # extended mode
+               \[\[\[ .+? \]\]\]    # display math of interpolations
+             | \(\(\( .+? \)\)\)    # inline math of interpolations
+             | \\\[ .+? \\\]
+             | \\\( .+? \\\)
+             | \$\$ .+? \$\$
+               "
+        )
+        .unwrap();
+    }
+    let mut math = vec![];
+    let text = RE_MATH.replace_all(text, |cap: &regex::Captures| {
+        math.push(cap[0].to_string());
+        format!("\u{e000}{}\u{e001}", math.len() - 1)
+    });
+
+    // an indented equation (alone in its line) isn't a code block
+    let text: Vec<&str> = text
+        .split('\n')
+        .map(|line| {
+            let line_trimmed = line.trim();
+            if line_trimmed.starts_with('\u{e000}') && line_trimmed.ends_with('\u{e001}') {
+                line.trim_start()
+            } else {
+                line
+            }
+        })
+        .collect();
+
+    (text.join("\n"), math)
+}
+
+fn restore(text: &str, math: &[String]) -> String {
+    RE_PLACEHOLDER
+        .replace_all(text, |cap: &regex::Captures| {
+            math[cap[1].parse::<usize>().unwrap()].clone()
+        })
+        .to_string()
+}
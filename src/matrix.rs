@@ -0,0 +1,487 @@
+// Vectors and matrices
+//
+// Matrices are expressions (Expression::Matrix and the operations over
+// them) whose value is a Matrix of magnitudes instead of a Magnitude:
+//   * each element has its own unit. A unit coercion ("M V :") or a
+//     conversion is applied to every element. With a unit coercion, the
+//     elements without unit take the unit ("1 2 2 vec N :" is in N).
+//   * vectors are matrices with one column.
+//   * +, neg, * and / work with matrices (matrix + matrix, scalar * matrix,
+//     matrix * matrix, matrix / scalar). Operations over elements use the
+//     operations of magnitudes, so units are checked and uncertainties
+//     propagated.
+//   * det, inv and solve use gaussian elimination over the values (result
+//     has unknown unit "¿?").
+//
+
+use crate::expression::{DictVariables, Expression};
+use crate::format::{Format, Tolerance};
+use crate::magnitude::Magnitude;
+
+// pivots lower than it are considered zero (singular matrix)
+const SINGULAR: f64 = 1e-12;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matrix {
+    pub rows: usize,
+    pub cols: usize,
+    pub elements: Vec<Magnitude>, // by rows
+}
+
+// factor of a product: scalar or matrix
+enum Factor {
+    Scalar(Magnitude),
+    Matrix(Matrix),
+}
+
+impl Expression {
+    /// Determines if the value of self is a matrix (or a vector)
+    pub fn is_matrix(&self, dict: &DictVariables) -> bool {
+        match self {
+            Expression::Matrix(..)
+            | Expression::Transpose(..)
+            | Expression::Inverse(..)
+            | Expression::Cross(..)
+            | Expression::Solve(..) => true,
+            Expression::Variable(name) => dict.get(name).is_some_and(|e| e.is_matrix(dict)),
+            Expression::Add(..)
+            | Expression::Neg(..)
+            | Expression::Prod(..)
+            | Expression::Div(..)
+            | Expression::Unit(..)
+            | Expression::Convert(..) => self.operands().iter().any(|e| e.is_matrix(dict)),
+            _ => false,
+        }
+    }
+
+    pub fn matrix_value(&self, dict: &DictVariables) -> Matrix {
+        match self {
+            Expression::Matrix(rows, cols, items) => Matrix {
+                rows: *rows,
+                cols: *cols,
+                elements: items.iter().map(|e| e.value(dict)).collect(),
+            },
+            Expression::Variable(name) => match dict.get(name) {
+                Some(expr) => expr.matrix_value(dict),
+                None => panic!("Variable {} not in dictionary", name),
+            },
+            Expression::Add(items) => items
+                .iter()
+                .map(|e| e.matrix_value(dict))
+                .reduce(|a, b| a.add(&b))
+                .unwrap(),
+            Expression::Neg(expr) => expr
+                .matrix_value(dict)
+                .map(|m| Expression::Neg(Box::new(literal(m)))),
+            Expression::Prod(items) => {
+                let mut factors = items.iter().map(|e| {
+                    if e.is_matrix(dict) {
+                        Factor::Matrix(e.matrix_value(dict))
+                    } else {
+                        Factor::Scalar(e.value(dict))
+                    }
+                });
+                let first = factors.next().unwrap();
+                match factors.fold(first, product) {
+                    Factor::Matrix(matrix) => matrix,
+                    Factor::Scalar(_) => panic!("Expression {:?} isn't a matrix", self),
+                }
+            }
+            Expression::Div(items) => {
+                let den = items[1].value(dict);
+                items[0]
+                    .matrix_value(dict)
+                    .map(|m| Expression::Div(vec![literal(m), literal(&den)]))
+            }
+            Expression::Unit(expr, unit) => expr.matrix_value(dict).map(|m| {
+                // elements without unit take the unit of matrix
+                let mut m = m.clone();
+                if m.unit.is_empty() {
+                    m.unit = String::from("¿?");
+                }
+                Expression::Unit(Box::new(literal(&m)), unit.clone())
+            }),
+            Expression::Convert(expr, unit, units) => expr
+                .matrix_value(dict)
+                .map(|m| Expression::Convert(Box::new(literal(m)), unit.clone(), units.clone())),
+            Expression::Transpose(expr) => expr.matrix_value(dict).transpose(),
+            Expression::Inverse(expr) => expr.matrix_value(dict).inverse(),
+            Expression::Cross(items) => items[0]
+                .matrix_value(dict)
+                .cross(&items[1].matrix_value(dict)),
+            Expression::Solve(items) => items[0]
+                .matrix_value(dict)
+                .solve(&items[1].matrix_value(dict)),
+            _ => panic!("Expression {:?} isn't a matrix", self),
+        }
+    }
+
+    /// LaTeX of the value of self (scalar, matrix or list)
+    pub fn show_value(&self, dict: &DictVariables, format: &Format) -> String {
+        if self.is_matrix(dict) {
+            self.matrix_value(dict).show(format)
+        } else if self.is_list(dict) {
+            let values: Vec<String> = self
+                .list_values(dict)
+                .iter()
+                .map(|m| m.show(format))
+                .collect();
+            format!("\\{{ {} \\}}", values.join(", "))
+        } else {
+            self.value(dict).show(format)
+        }
+    }
+
+    /// Value of self (scalar, matrix or list) as a literal expression
+    pub fn literal_value(&self, dict: &DictVariables) -> Expression {
+        if self.is_matrix(dict) {
+            let matrix = self.matrix_value(dict);
+            let elements = matrix.elements.iter().map(literal).collect();
+            Expression::Matrix(matrix.rows, matrix.cols, elements)
+        } else if self.is_list(dict) {
+            let values = self.list_values(dict);
+            Expression::List(values.iter().map(literal).collect())
+        } else {
+            literal(&self.value(dict))
+        }
+    }
+}
+
+impl Matrix {
+    /// element (i, j) counting from 1
+    pub fn get(&self, i: usize, j: usize) -> Magnitude {
+        if i < 1 || i > self.rows || j < 1 || j > self.cols {
+            panic!(
+                "Element ({}, {}) out of matrix of {}x{}",
+                i, j, self.rows, self.cols
+            );
+        }
+        self.elements[(i - 1) * self.cols + (j - 1)].clone()
+    }
+
+    /// element i (counting from 1) of a vector
+    pub fn at(&self, i: usize) -> Magnitude {
+        if self.cols == 1 {
+            self.get(i, 1)
+        } else if self.rows == 1 {
+            self.get(1, i)
+        } else {
+            panic!("Matrix of {}x{} isn't a vector", self.rows, self.cols)
+        }
+    }
+
+    fn map(&self, f: impl Fn(&Magnitude) -> Expression) -> Matrix {
+        Matrix {
+            rows: self.rows,
+            cols: self.cols,
+            elements: self.elements.iter().map(|m| scalar(f(m))).collect(),
+        }
+    }
+
+    fn add(&self, other: &Matrix) -> Matrix {
+        if self.rows != other.rows || self.cols != other.cols {
+            panic!(
+                "Adding matrices of {}x{} and {}x{}",
+                self.rows, self.cols, other.rows, other.cols
+            );
+        }
+        Matrix {
+            rows: self.rows,
+            cols: self.cols,
+            elements: self
+                .elements
+                .iter()
+                .zip(other.elements.iter())
+                .map(|(a, b)| scalar(Expression::Add(vec![literal(a), literal(b)])))
+                .collect(),
+        }
+    }
+
+    fn scale(&self, factor: &Magnitude) -> Matrix {
+        self.map(|m| Expression::Prod(vec![literal(factor), literal(m)]))
+    }
+
+    fn mul(&self, other: &Matrix) -> Matrix {
+        if self.cols != other.rows {
+            panic!(
+                "Multiplying matrices of {}x{} and {}x{}",
+                self.rows, self.cols, other.rows, other.cols
+            );
+        }
+        let mut elements = vec![];
+        for i in 1..=self.rows {
+            for j in 1..=other.cols {
+                let terms = (1..=self.cols)
+                    .map(|k| {
+                        Expression::Prod(vec![literal(&self.get(i, k)), literal(&other.get(k, j))])
+                    })
+                    .collect();
+                elements.push(scalar(Expression::Add(terms)));
+            }
+        }
+        Matrix {
+            rows: self.rows,
+            cols: other.cols,
+            elements,
+        }
+    }
+
+    fn transpose(&self) -> Matrix {
+        let mut elements = vec![];
+        for j in 1..=self.cols {
+            for i in 1..=self.rows {
+                elements.push(self.get(i, j));
+            }
+        }
+        Matrix {
+            rows: self.cols,
+            cols: self.rows,
+            elements,
+        }
+    }
+
+    fn vector_len(&self) -> usize {
+        if self.cols != 1 && self.rows != 1 {
+            panic!("Matrix of {}x{} isn't a vector", self.rows, self.cols);
+        }
+        self.elements.len()
+    }
+
+    pub fn dot(&self, other: &Matrix) -> Magnitude {
+        let len = self.vector_len();
+        if len != other.vector_len() {
+            panic!(
+                "Dot product of vectors of {} and {} elements",
+                len,
+                other.vector_len()
+            );
+        }
+        let terms = (1..=len)
+            .map(|i| Expression::Prod(vec![literal(&self.at(i)), literal(&other.at(i))]))
+            .collect();
+        scalar(Expression::Add(terms))
+    }
+
+    fn cross(&self, other: &Matrix) -> Matrix {
+        if self.vector_len() != 3 || other.vector_len() != 3 {
+            panic!("Cross product needs vectors of 3 elements");
+        }
+        let component = |i: usize, j: usize| {
+            let a = Expression::Prod(vec![literal(&self.at(i)), literal(&other.at(j))]);
+            let b = Expression::Prod(vec![literal(&self.at(j)), literal(&other.at(i))]);
+            scalar(Expression::Add(vec![a, Expression::Neg(Box::new(b))]))
+        };
+        Matrix {
+            rows: 3,
+            cols: 1,
+            elements: vec![component(2, 3), component(3, 1), component(1, 2)],
+        }
+    }
+
+    pub fn det(&self) -> Magnitude {
+        let (det, _) = self.eliminate(&Matrix::identity(0, self.rows));
+        Magnitude::new(det, String::from("¿?"))
+    }
+
+    fn inverse(&self) -> Matrix {
+        self.solve(&Matrix::identity(self.rows, self.rows))
+    }
+
+    /// x with self x = b
+    fn solve(&self, b: &Matrix) -> Matrix {
+        if b.rows != self.rows {
+            panic!(
+                "Solving system of {}x{} with right side of {}x{}",
+                self.rows, self.cols, b.rows, b.cols
+            );
+        }
+        match self.eliminate(b) {
+            (_, Some(values)) => Matrix {
+                rows: b.rows,
+                cols: b.cols,
+                elements: values
+                    .into_iter()
+                    .map(|v| Magnitude::new(v, String::from("¿?")))
+                    .collect(),
+            },
+            (_, None) => panic!("Singular matrix: {:?}", self),
+        }
+    }
+
+    /// identity of size n with cols columns (square if cols == n)
+    fn identity(cols: usize, n: usize) -> Matrix {
+        let mut elements = vec![];
+        for i in 0..n {
+            for j in 0..cols {
+                let value = if i == j { 1.0 } else { 0.0 };
+                elements.push(Magnitude::new(value, String::new()));
+            }
+        }
+        Matrix {
+            rows: n,
+            cols,
+            elements,
+        }
+    }
+
+    /// gauss-jordan elimination with partial pivoting of [self | b]
+    /// returns the determinant of self and the solution (None if singular)
+    fn eliminate(&self, b: &Matrix) -> (f64, Option<Vec<f64>>) {
+        if self.rows != self.cols {
+            panic!("Matrix of {}x{} isn't square", self.rows, self.cols);
+        }
+        let n = self.rows;
+        let mut rows: Vec<Vec<f64>> = (1..=n)
+            .map(|i| {
+                let mut row: Vec<f64> = (1..=n).map(|j| self.get(i, j).value).collect();
+                row.extend((1..=b.cols).map(|j| b.get(i, j).value));
+                row
+            })
+            .collect();
+
+        let mut det = 1.0;
+        for col in 0..n {
+            let pivot = (col..n)
+                .max_by(|&a, &b| rows[a][col].abs().partial_cmp(&rows[b][col].abs()).unwrap())
+                .unwrap();
+            if rows[pivot][col].abs() < SINGULAR {
+                return (0.0, None);
+            }
+            if pivot != col {
+                rows.swap(pivot, col);
+                det = -det;
+            }
+            det *= rows[col][col];
+
+            let head = rows[col][col];
+            for value in rows[col].iter_mut() {
+                *value /= head;
+            }
+            let pivot_row = rows[col].clone();
+            for (i, row) in rows.iter_mut().enumerate() {
+                if i != col {
+                    let factor = row[col];
+                    for (value, pivot) in row.iter_mut().zip(pivot_row.iter()).skip(col) {
+                        *value -= factor * pivot;
+                    }
+                }
+            }
+        }
+
+        let solution = rows.into_iter().flat_map(|row| row[n..].to_vec()).collect();
+        (det, Some(solution))
+    }
+
+    /// Determines if self and other have the same size, units and values
+    /// (with tolerance)
+    pub fn close(&self, other: &Matrix, tolerance: &Tolerance) -> bool {
+        self.rows == other.rows
+            && self.cols == other.cols
+            && self
+                .elements
+                .iter()
+                .zip(other.elements.iter())
+                .all(|(a, b)| a.close(b, tolerance))
+    }
+
+    /// LaTeX of matrix
+    pub fn show(&self, format: &Format) -> String {
+        let rows: Vec<String> = (1..=self.rows)
+            .map(|i| {
+                let row: Vec<String> = (1..=self.cols)
+                    .map(|j| self.get(i, j).show(format))
+                    .collect();
+                row.join(" & ")
+            })
+            .collect();
+        format!(
+            "\\begin{{pmatrix}} {} \\end{{pmatrix}}",
+            rows.join(" \\\\ ")
+        )
+    }
+}
+
+fn product(a: Factor, b: Factor) -> Factor {
+    match (a, b) {
+        (Factor::Scalar(a), Factor::Scalar(b)) => {
+            Factor::Scalar(scalar(Expression::Prod(vec![literal(&a), literal(&b)])))
+        }
+        (Factor::Scalar(a), Factor::Matrix(b)) => Factor::Matrix(b.scale(&a)),
+        (Factor::Matrix(a), Factor::Scalar(b)) => Factor::Matrix(a.scale(&b)),
+        (Factor::Matrix(a), Factor::Matrix(b)) => Factor::Matrix(a.mul(&b)),
+    }
+}
+
+fn literal(magnitude: &Magnitude) -> Expression {
+    Expression::Magnitude(magnitude.clone())
+}
+
+// value of an operation between magnitudes
+fn scalar(expr: Expression) -> Magnitude {
+    expr.value(&DictVariables::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::macros::Macros;
+
+    fn expr(rpn: &str) -> Expression {
+        Expression::from(rpn, &Macros::new())
+    }
+
+    fn values(rpn: &str) -> Vec<f64> {
+        let matrix = expr(rpn).matrix_value(&DictVariables::new());
+        matrix.elements.iter().map(|m| m.value).collect()
+    }
+
+    fn assert_close(a: &[f64], b: &[f64]) {
+        assert_eq!(a.len(), b.len(), "{:?} != {:?}", a, b);
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() < 1e-9, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn products_and_transpose() {
+        let product = "1 2 3 4 2 2 mat 5 6 7 8 2 2 mat *";
+        assert_close(&values(product), &[19.0, 22.0, 43.0, 50.0]);
+        assert_close(&values("1 2 3 4 2 2 mat 2 *"), &[2.0, 4.0, 6.0, 8.0]);
+        assert_close(&values("1 2 3 1 3 mat trans"), &[1.0, 2.0, 3.0]);
+        let m = expr("1 2 3 4 5 6 2 3 mat trans").matrix_value(&DictVariables::new());
+        assert_eq!((m.rows, m.cols), (3, 2));
+    }
+
+    #[test]
+    fn vectors() {
+        let dot = expr("1 2 3 3 vec 4 5 6 3 vec dot").value(&DictVariables::new());
+        assert_eq!(dot.value, 32.0);
+        assert_close(&values("1 0 0 3 vec 0 1 0 3 vec cross"), &[0.0, 0.0, 1.0]);
+        let second = expr("7 8 9 3 vec 2 at").value(&DictVariables::new());
+        assert_eq!(second.value, 8.0);
+    }
+
+    #[test]
+    fn linear_algebra() {
+        let det = expr("1 2 3 4 2 2 mat det").value(&DictVariables::new());
+        assert!((det.value + 2.0).abs() < 1e-9);
+        assert_close(&values("4 7 2 6 2 2 mat inv"), &[0.6, -0.7, -0.2, 0.4]);
+        // 2x + y = 3, x + 3y = 5
+        assert_close(&values("2 1 1 3 2 2 mat 3 5 2 vec solve"), &[0.8, 1.4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Singular matrix")]
+    fn singular_matrix() {
+        values("1 2 2 4 2 2 mat inv");
+    }
+
+    #[test]
+    fn units_of_elements() {
+        let matrix = expr("1 2 2 vec N :").matrix_value(&DictVariables::new());
+        assert!(matrix.elements.iter().all(|m| m.unit == "N"));
+        let sum = expr("1m 2s 2 vec 3m 4s 2 vec +").matrix_value(&DictVariables::new());
+        let units: Vec<&str> = sum.elements.iter().map(|m| m.unit.as_str()).collect();
+        assert_eq!(units, ["m", "s"]);
+    }
+}
@@ -0,0 +1,133 @@
+// Metadata of exercise
+//
+// Optional keys of the exercise (next to title) to describe and catalogue
+// it:
+//
+//   author: Javier MM
+//   course: Electrotecnia
+//   tags: [resistors, ohm]     # a list or a string
+//   difficulty: 2
+//   time: 15 min               # estimated time
+//   language: es
+//   license: CC-BY-SA-4.0      # (or licence)
+//   prerequisites: [ohm-law]   # a list or a string
+//
+// They are embedded in the output of the renderers (as JSON) and listed by
+// the command "catalog".
+//
+
+use yaml_rust::Yaml;
+
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub author: Option<String>,
+    pub course: Option<String>,
+    pub tags: Vec<String>,
+    pub difficulty: Option<String>,
+    pub time: Option<String>,
+    pub language: Option<String>,
+    pub license: Option<String>,
+    pub prerequisites: Vec<String>,
+}
+
+impl Metadata {
+    pub fn from_yaml(doc: &Yaml) -> Self {
+        let license = match doc["license"] {
+            Yaml::BadValue => scalar(doc, "licence"),
+            _ => scalar(doc, "license"),
+        };
+
+        Metadata {
+            author: scalar(doc, "author"),
+            course: scalar(doc, "course"),
+            tags: list(doc, "tags"),
+            difficulty: scalar(doc, "difficulty"),
+            time: scalar(doc, "time"),
+            language: scalar(doc, "language"),
+            license,
+            prerequisites: list(doc, "prerequisites"),
+        }
+    }
+
+    /// Single valued fields (name, value)
+    pub fn fields(&self) -> [(&'static str, Option<&str>); 6] {
+        [
+            ("author", self.author.as_deref()),
+            ("course", self.course.as_deref()),
+            ("difficulty", self.difficulty.as_deref()),
+            ("time", self.time.as_deref()),
+            ("language", self.language.as_deref()),
+            ("license", self.license.as_deref()),
+        ]
+    }
+
+    /// JSON object with the fields (keys of the exercise first)
+    pub fn json(&self, extra: &[(&str, &str)]) -> String {
+        let list = |items: &[String]| -> String {
+            let items: Vec<String> = items.iter().map(|item| json_string(item)).collect();
+            format!("[{}]", items.join(", "))
+        };
+
+        let mut fields: Vec<String> = extra
+            .iter()
+            .map(|(name, value)| format!("\"{}\": {}", name, json_string(value)))
+            .collect();
+        for (name, value) in self.fields() {
+            let value = value.map_or_else(|| String::from("null"), json_string);
+            fields.push(format!("\"{}\": {}", name, value));
+        }
+        fields.push(format!("\"tags\": {}", list(&self.tags)));
+        fields.push(format!("\"prerequisites\": {}", list(&self.prerequisites)));
+
+        format!("{{{}}}", fields.join(", "))
+    }
+}
+
+fn scalar(doc: &Yaml, key: &str) -> Option<String> {
+    match &doc[key] {
+        Yaml::BadValue | Yaml::Null => None,
+        Yaml::String(value) => Some(value.trim().to_string()),
+        Yaml::Integer(value) => Some(value.to_string()),
+        Yaml::Real(value) => Some(value.to_string()),
+        value => panic!("Metadata {} isn't a string: {:?}", key, value),
+    }
+}
+
+// a list of strings or one string (items separated with commas)
+fn list(doc: &Yaml, key: &str) -> Vec<String> {
+    match &doc[key] {
+        Yaml::Array(items) => items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| match item {
+                Yaml::String(item) => item.trim().to_string(),
+                Yaml::Integer(item) => item.to_string(),
+                _ => panic!("Item {} of metadata {} isn't a string", i + 1, key),
+            })
+            .collect(),
+        Yaml::BadValue | Yaml::Null => vec![],
+        _ => scalar(doc, key)
+            .unwrap_or_default()
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect(),
+    }
+}
+
+/// JSON string of string
+pub fn json_string(string: &str) -> String {
+    let mut output = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
@@ -1,15 +1,20 @@
 // Passage generation
 
+use cityhash::cityhash_1_1_1::city_hash_64;
 use lazy_static::lazy_static;
-use rand::seq::SliceRandom;
 use regex::Regex;
 use std::fmt;
 use yaml_rust::yaml::Hash;
 use yaml_rust::Yaml;
 
+use crate::browser;
 use crate::expression::{DictVariables, Expression};
+use crate::figure::{self, Figure};
+use crate::format::{Format, Numeric};
 use crate::macros::Macros;
-use crate::render::Render;
+use crate::random;
+use crate::render::{Context, Kind, Render};
+use crate::sourcemap::{self, Lines};
 
 // Gate: info about an option
 #[derive(Debug, Clone)]
@@ -18,6 +23,8 @@ pub struct Gate {
     follow: String,           // ... text shown after election in the main history
     note: String,             // --- text shown after election in a temporal history
     variables: DictVariables, // variables defined after this gate.
+    source: String,           // yaml of the gate (key of its passages)
+    line: Option<usize>,      // line of the gate in the source
 }
 
 impl Gate {
@@ -27,10 +34,18 @@ impl Gate {
             follow: String::new(),
             note: String::new(),
             variables: DictVariables::new(),
+            source: String::new(),
+            line: None,
         }
     }
 
-    fn from(string: &str, variables: &DictVariables, macros: &Macros) -> Self {
+    fn from(
+        string: &str,
+        line: Option<usize>,
+        variables: &DictVariables,
+        macros: &Macros,
+        format: &Format,
+    ) -> Self {
         let mut text = Vec::<String>::new();
         let mut follow = Vec::<String>::new();
         let mut note = Vec::<String>::new();
@@ -51,6 +66,24 @@ impl Gate {
                 continue;
             }
 
+            if let Some(figure) = line.strip_prefix(figure::MARKER) {
+                let figure = Figure::from(&process_line(figure, &mut variables, macros, format))
+                    .unwrap_or_else(|| panic!("Figure without file: {:?}", line))
+                    .locate(macros.paths())
+                    .and_then(|figure| {
+                        figure.instantiate(&macros.templates, |line| {
+                            process_line(line, &mut variables, macros, format)
+                        })
+                    })
+                    .unwrap_or_else(|e| panic!("{}", e));
+                match status {
+                    GateStatus::Text => text.push(figure.to_string()),
+                    GateStatus::Follow => follow.push(figure.to_string()),
+                    GateStatus::Note => note.push(figure.to_string()),
+                }
+                continue;
+            }
+
             if let Some(rest) = line.strip_prefix("___") {
                 status = GateStatus::Text;
                 line = rest;
@@ -63,9 +96,11 @@ impl Gate {
             }
 
             match status {
-                GateStatus::Text => text.push(process_line(line, &mut variables, macros)),
-                GateStatus::Follow => follow.push(process_line(line, &mut variables, macros)),
-                GateStatus::Note => note.push(process_line(line, &mut variables, macros)),
+                GateStatus::Text => text.push(process_line(line, &mut variables, macros, format)),
+                GateStatus::Follow => {
+                    follow.push(process_line(line, &mut variables, macros, format))
+                }
+                GateStatus::Note => note.push(process_line(line, &mut variables, macros, format)),
             }
         }
 
@@ -74,20 +109,23 @@ impl Gate {
             follow: follow.join("\n"),
             note: note.join("\n"),
             variables,
+            source: string.to_string(),
+            line,
         }
     }
 
     fn passage_note(
         &self,
         renderer: &mut dyn Render,
+        context: &mut Context,
         current_link: &PassageTitle,
         msg: &str,
         output_link: &PassageTitle,
     ) -> String {
         let mut output = String::new();
 
-        output += &renderer.begin_passage(&current_link.to_string());
-        output += &renderer.text(&self.note);
+        output += &renderer.begin_passage(&current_link.to_string(), Kind::Note);
+        output += &renderer.text(&self.note, context);
         output += &renderer.link(msg, &output_link.to_string());
         output += &renderer.end_passage(&current_link.to_string());
 
@@ -97,31 +135,48 @@ impl Gate {
     fn passage_bad_note(
         &self,
         renderer: &mut dyn Render,
+        context: &mut Context,
         current_link: &PassageTitle,
         msg: &str,
         output_link: &PassageTitle,
     ) -> String {
         let mut output = String::new();
 
-        output += &renderer.begin_passage(&current_link.to_string());
-        output += &renderer.text("Opción errónea");
-        output += &renderer.text(&self.note);
+        output += &renderer.begin_passage(&current_link.to_string(), Kind::Bad);
+        output += &renderer.text("Opción errónea", context);
+        output += &renderer.text(&self.note, context);
         output += &renderer.link(msg, &output_link.to_string());
         output += &renderer.end_passage(&current_link.to_string());
 
         output
     }
 
-    fn passage_choice(&self, renderer: &mut dyn Render, next_link: &PassageTitle) -> String {
+    fn passage_choice(
+        &self,
+        renderer: &mut dyn Render,
+        context: &mut Context,
+        next_link: &PassageTitle,
+    ) -> String {
         let mut output = String::new();
         output += &renderer.begin_option("", &next_link.to_string());
         output += &renderer.link("Opción:", &next_link.to_string());
-        output += &renderer.text(&self.text);
+        output += &renderer.text(&self.text, context);
         output += &renderer.end_option(&next_link.to_string());
 
         output
     }
 
+    // passage reached through the gate (in the map of the output)
+    fn record(&self, context: &mut Context, title: &PassageTitle, kind: Kind, key: &'static str) {
+        context.passages.push(sourcemap::Entry {
+            title: title.to_string(),
+            kind,
+            key,
+            line: self.line,
+            source: self.source.clone(),
+        });
+    }
+
     fn has_note(&self) -> bool {
         !self.note.is_empty()
     }
@@ -138,7 +193,7 @@ enum GateStatus {
 }
 
 //-------------------------
-fn process_line(line: &str, vars: &mut DictVariables, macros: &Macros) -> String {
+fn process_line(line: &str, vars: &mut DictVariables, macros: &Macros, format: &Format) -> String {
     let mut output_vec = Vec::<String>::new();
 
     let line = encode_line(line);
@@ -149,9 +204,10 @@ fn process_line(line: &str, vars: &mut DictVariables, macros: &Macros) -> String
                \{\{                # initial parantheses 
                (.)                 # 1 code for interpolation type
                \s*                  
-               ( ([[:^blank:]]+?) \s* = \s* )?    # 2 3 possible binding
-               (.+?)               # 4 definition
+               ( ([[:^blank:]]+?) \s* (:?=) \s* )?    # 2 3 4 possible binding (:= redefines)
+               (.+?)               # 5 definition
                \s*
+               ( \| \s* ([^|]*?) \s* )?  # 6 7 possible format spec
                \}\}
                "
         )
@@ -170,33 +226,56 @@ fn process_line(line: &str, vars: &mut DictVariables, macros: &Macros) -> String
         output_vec.push(decode_line(&line[it..m.start()]));
 
         //println!("\n\nreading line: {:?}", &line);
-        let value: Expression = Expression::from(&decode_line(&cap[4]), macros);
+        let value: Expression =
+            Expression::from(&decode_line(&cap[5]), macros).seed_rands(&macros.rng);
+        value.check_bound(vars);
         //println!("Expression: {:?}", value);
 
-        // binding
-        let var_name: String = match cap.get(3) {
-            Some(var) => decode_line(var.as_str()),
-            None => String::new(),
+        let format = match cap.get(7) {
+            Some(spec) => format.with_spec(&decode_line(spec.as_str())),
+            None => format.clone(),
+        };
+
+        // binding (equations are bound to their unknown by default)
+        let equation = match &value {
+            Expression::Root(_, unknown) => Some(unknown.clone()),
+            _ => None,
+        };
+        let var_name: String = match (cap.get(3), &equation) {
+            (Some(var), _) => decode_line(var.as_str()),
+            (None, Some(unknown)) => unknown.clone(),
+            (None, None) => String::new(),
         };
 
+        let redefine = cap.get(4).is_some_and(|op| op.as_str() == ":=");
+
         if !var_name.is_empty() {
             // there is binding
 
             match vars.get(&var_name) {
-                Some(value_dict) => {
-                    let v1 = value.value(vars);
-                    let v2 = value_dict.value(vars);
-
-                    if (v1.value - v2.value).abs() > 1e-5 || v1.unit != v2.unit {
-                        panic!("Attempt of overwrite variable: {:?}. Old value: {:?}={} and new value: {:?}={}", var_name, value_dict, value_dict.value(vars), value, value.value(vars));
+                Some(value_dict) if !redefine && !matches!(value_dict, Expression::Unbound(..)) => {
+                    if !same_value(&value, vars, value_dict, vars, &format) {
+                        panic!(
+                            "{}",
+                            overwrite_diagnostic(&var_name, value_dict, &value, vars, &format)
+                        );
                     }
                 }
-                None => {
-                    vars.insert(var_name.clone(), value.clone());
+                _ => {
+                    let definition = browser::bind(&macros.program, &var_name, &value, vars);
+                    vars.insert(var_name.clone(), definition);
                 }
             }
         }
 
+        // the value of a bound variable is the one in dictionary (random
+        // values are drawn once)
+        let shown = if var_name.is_empty() {
+            value.clone()
+        } else {
+            Expression::Variable(var_name.clone())
+        };
+
         // printing/inyecting
         match &cap[1] {
             "." => {
@@ -205,27 +284,32 @@ fn process_line(line: &str, vars: &mut DictVariables, macros: &Macros) -> String
                 if !var_name.is_empty() {
                     output_vec.push(format!("{} = ", var_name));
                 }
-                output_vec.push(format!("{}", value.value(vars)));
+                output_vec.push(browser::show_value(&macros.program, &shown, vars, &format));
                 output_vec.push(end_math.to_string());
             }
             "," => {
                 // show only the calculation
                 output_vec.push(start_math.to_string());
-                if !var_name.is_empty() {
+                if !var_name.is_empty() && equation.is_none() {
                     output_vec.push(format!("{} = ", var_name));
                 }
-                output_vec.push(value.show());
+                output_vec.push(value.show_with(&format));
                 output_vec.push(end_math.to_string());
             }
             ";" => {
                 // calculation and later the value
                 output_vec.push(start_math.to_string());
-                if !var_name.is_empty() {
+                if !var_name.is_empty() && equation.is_none() {
                     output_vec.push(format!("{} = ", var_name));
                 }
-                output_vec.push(value.show());
-                output_vec.push(String::from(" = "));
-                output_vec.push(format!("{}", value.value(vars)));
+                output_vec.push(value.show_with(&format));
+                if equation.is_some() {
+                    // the equation and its solution
+                    output_vec.push(format!(" \\Rightarrow {} = ", var_name));
+                } else {
+                    output_vec.push(String::from(" = "));
+                }
+                output_vec.push(browser::show_value(&macros.program, &shown, vars, &format));
                 output_vec.push(end_math.to_string());
             }
             "!" => {
@@ -233,7 +317,7 @@ fn process_line(line: &str, vars: &mut DictVariables, macros: &Macros) -> String
                 if !var_name.is_empty() {
                     output_vec.push(format!("{}=", var_name));
                 }
-                output_vec.push(format!("{}", value.value(vars).value));
+                output_vec.push(browser::raw_value(&macros.program, &shown, vars));
             }
             "_" => {} // Make calculation but doesn't show anything
             _ => {
@@ -255,6 +339,61 @@ fn process_line(line: &str, vars: &mut DictVariables, macros: &Macros) -> String
     output_vec.join("")
 }
 
+/// Determines if a (evaluated with dict_a) and b (with dict_b) have the same
+/// value (with the tolerance of format)
+fn same_value(
+    a: &Expression,
+    dict_a: &DictVariables,
+    b: &Expression,
+    dict_b: &DictVariables,
+    format: &Format,
+) -> bool {
+    if a.is_matrix(dict_a) || b.is_matrix(dict_b) {
+        a.matrix_value(dict_a)
+            .close(&b.matrix_value(dict_b), &format.tolerance)
+    } else if a.is_list(dict_a) || b.is_list(dict_b) {
+        let (la, lb) = (a.list_values(dict_a), b.list_values(dict_b));
+        la.len() == lb.len()
+            && la
+                .iter()
+                .zip(lb.iter())
+                .all(|(va, vb)| va.close(vb, &format.tolerance))
+    } else {
+        let va = a.value(dict_a);
+        let vb = b.value(dict_b);
+        // exact values are compared exactly in rational mode
+        match (format.numeric, va.rational, vb.rational) {
+            (Numeric::Rational, Some(ra), Some(rb)) => ra == rb && va.unit == vb.unit,
+            _ => va.close(&vb, &format.tolerance),
+        }
+    }
+}
+
+/// Description of a rebinding of var_name with a different value
+fn overwrite_diagnostic(
+    var_name: &str,
+    old: &Expression,
+    new: &Expression,
+    vars: &DictVariables,
+    format: &Format,
+) -> String {
+    // enough digits to see the difference
+    let precise = format.with_spec("sig=10, numeric=float");
+    format!(
+        "Attempt of overwrite variable {name} (use \"{name} := ...\" to redefine it)\n\
+         \told: {name} = {} = {}\n\
+         \tnew: {name} = {} = {}\n\
+         \ttolerance: rtol={:e}, atol={:e}",
+        old.show_with(format),
+        old.show_value(vars, &precise),
+        new.show_with(format),
+        new.show_value(vars, &precise),
+        format.tolerance.relative,
+        format.tolerance.absolute,
+        name = var_name,
+    )
+}
+
 fn is_displaymode(line: &str) -> bool {
     lazy_static! {
         static ref RE_ISDISPLAY: Regex = Regex::new(
@@ -362,8 +501,15 @@ impl PassageTree {
         PassageTree(passage, vec![])
     }
 
-    pub fn from_yaml(yaml: &Yaml, dictionary: &DictVariables, macros: &Macros) -> Vec<Self> {
-        let passages = convert_yaml(yaml, dictionary, macros);
+    /// Passages of yaml (with the lines of its nodes in the source)
+    pub fn from_yaml(
+        yaml: &Yaml,
+        lines: &Lines,
+        dictionary: &DictVariables,
+        macros: &Macros,
+        format: &Format,
+    ) -> Vec<Self> {
+        let passages = convert_yaml(yaml, lines, dictionary, macros, format);
 
         PassageTree::from(&passages.0)
     }
@@ -427,22 +573,28 @@ impl PassageTree {
     pub fn render(
         &self,
         renderer: &mut dyn Render,
+        context: &mut Context,
         acumulated_text: &str,
         current_link: &PassageTitle,
     ) -> String {
-        let mut output: String = renderer.begin_passage(&current_link.to_string());
+        let kind = if self.is_endnode() {
+            Kind::End
+        } else {
+            Kind::Step
+        };
+        let mut output: String = renderer.begin_passage(&current_link.to_string(), kind);
+        self.0.text.record(context, current_link, kind, "text");
         let mut suboutput = String::new();
 
         let mut acumulated_text = acumulated_text.to_string();
-        acumulated_text = acumulated_text
-            + &renderer.text(&self.0.text.text)
-            + &renderer.text(&self.0.text.follow)
-            + "\n";
+        let text = renderer.text(&self.0.text.text, context)
+            + &renderer.text(&self.0.text.follow, context);
+        acumulated_text = figure::replace_variants(&acumulated_text, &text) + &text + "\n";
 
         output += &acumulated_text;
 
         // end of render
-        if self.is_endnode() {
+        if kind == Kind::End {
             output += &renderer.begin_choices("");
             output += &renderer.link("Este es el final del problema.", "Start");
             output += &renderer.end_choices("");
@@ -453,23 +605,39 @@ impl PassageTree {
         // output gates
         let mut output_gates: Vec<String> = vec![];
 
-        // sub_links
-        let mut sub_link = current_link.clone();
-        sub_link.add_level();
+        // sub_links (from the sources of the gates, so they don't change
+        // when other gates are added)
+        let mut sources: Vec<String> = vec![];
+        let mut sub_link = |kind: &str, gate: &Gate| {
+            let source = format!("{}\n{}", kind, gate.source);
+            let repeated = sources.iter().filter(|s| **s == source).count();
+            sources.push(source.clone());
+            current_link.child(&format!("{}\n{}", source, repeated))
+        };
 
-        // bad_gates
-        let mut bad_gates = self.0.post_bad.clone();
+        // bad_gates (with their key)
+        let mut bad_gates: Vec<(&'static str, Gate)> = self
+            .0
+            .post_bad
+            .iter()
+            .map(|gate| ("post_bad", gate.clone()))
+            .collect();
         for next in &self.1 {
-            bad_gates.extend(next.0.previous_bad.clone());
+            bad_gates.extend(next.0.previous_bad.iter().map(|gate| ("pre_bad", gate.clone())));
         }
 
-        for bad_gate in bad_gates {
-            output_gates.push(bad_gate.passage_choice(renderer, &sub_link));
+        for (key, bad_gate) in bad_gates {
+            let bad_link = sub_link("bad", &bad_gate);
+            bad_gate.record(context, &bad_link, Kind::Bad, key);
+            output_gates.push(bad_gate.passage_choice(renderer, context, &bad_link));
             // TODO I18N
-            suboutput +=
-                &bad_gate.passage_bad_note(renderer, &sub_link, "Volver a intentarlo", current_link);
-
-            sub_link.inc();
+            suboutput += &bad_gate.passage_bad_note(
+                renderer,
+                context,
+                &bad_link,
+                "Volver a intentarlo",
+                current_link,
+            );
         }
 
         // good_gates
@@ -478,16 +646,19 @@ impl PassageTree {
             if gate.is_empty() {
                 continue;
             }
-            output_gates.push(gate.passage_choice(renderer, &sub_link));
+            let next_link = sub_link("good", &gate);
             if gate.has_note() {
-                let temp_link = sub_link.clone();
-                sub_link.inc();
+                let note_link = next_link.child("note");
+                gate.record(context, &note_link, Kind::Note, "text");
+                output_gates.push(gate.passage_choice(renderer, context, &note_link));
                 // I18N
-                suboutput += &gate.passage_note(renderer, &temp_link, "Continuar", &sub_link);
+                suboutput +=
+                    &gate.passage_note(renderer, context, &note_link, "Continuar", &next_link);
+            } else {
+                output_gates.push(gate.passage_choice(renderer, context, &next_link));
             }
 
-            suboutput += &next.render(renderer, &acumulated_text, &sub_link);
-            sub_link.inc();
+            suboutput += &next.render(renderer, context, &acumulated_text, &next_link);
         }
 
         // randomize of gates and output
@@ -497,7 +668,7 @@ impl PassageTree {
             output += &renderer.begin_choices(
                 "Marque una opción que considere correcta (puede haber más de una)",
             );
-            output_gates.shuffle(&mut rand::thread_rng());
+            random::shuffle(&mut output_gates, context.seed, &current_link.to_string());
         } else {
             // TODO I18N
             output += &renderer.begin_choices("Marque la opción indicada para continuar");
@@ -569,19 +740,44 @@ pub fn is_macros(tag: &'static str, elem: &Yaml) -> Option<Vec<String>> {
 
 fn convert_yaml(
     yaml: &Yaml,
+    lines: &Lines,
     dictionary: &DictVariables,
     macros: &Macros,
+    format: &Format,
 ) -> (PassageElem, DictVariables, Macros) {
+    // magnitudes are shown with the units of the scope
+    let format = &format.with_units(&macros.units);
     match yaml {
-        Yaml::Array(_) => convert_seq(yaml.as_vec().unwrap(), dictionary, macros),
+        Yaml::Array(elems) => convert_seq(elems, lines, dictionary, macros, format),
         Yaml::Hash(hash) => match main_key(hash) {
-            Some("pass") => convert_pass(&yaml["pass"], dictionary, macros),
-            Some("seq") => convert_seq(yaml["seq"].as_vec().unwrap(), dictionary, macros),
-            Some("alt") => convert_alt(yaml["alt"].as_vec().unwrap(), dictionary, macros),
-            Some("con") => convert_con(yaml["con"].as_vec().unwrap(), dictionary, macros),
-            Some("cond") => convert_cond(&yaml["cond"], &yaml["cont"], dictionary, macros),
+            Some("pass") => {
+                convert_pass(&yaml["pass"], lines.value("pass"), dictionary, macros, format)
+            }
+            Some("seq") => convert_seq(
+                yaml["seq"].as_vec().unwrap(),
+                lines.value("seq"),
+                dictionary,
+                macros,
+                format,
+            ),
+            Some("alt") => convert_alt(
+                yaml["alt"].as_vec().unwrap(),
+                lines.value("alt"),
+                dictionary,
+                macros,
+                format,
+            ),
+            Some("con") => convert_con(
+                yaml["con"].as_vec().unwrap(),
+                lines.value("con"),
+                dictionary,
+                macros,
+                format,
+            ),
+            Some("cond") => convert_cond(yaml, lines, dictionary, macros, format),
             Some("paths") => panic!("'paths' directive misplaced"),
             Some("macros") => panic!("'macros' directive misplaced"),
+            Some("units") => panic!("'units' directive misplaced"),
             _ => panic!("I don't know how to process {:?}", hash),
         },
         _ => panic!("I don't know how to process {:?}", yaml),
@@ -590,27 +786,51 @@ fn convert_yaml(
 
 fn convert_pass(
     pass: &Yaml,
+    lines: &Lines,
     dictionary: &DictVariables,
     macros: &Macros,
+    format: &Format,
 ) -> (PassageElem, DictVariables, Macros) {
-    let text = Gate::from(pass["text"].as_str().unwrap(), dictionary, macros);
+    let text = Gate::from(
+        pass["text"].as_str().unwrap(),
+        lines.value("text").line,
+        dictionary,
+        macros,
+        format,
+    );
 
     let mut previous_bad = vec![];
     let mut post_bad = vec![];
 
     if let Some(vec) = pass["pre_bad"].as_vec() {
-        for item in vec {
+        for (i, item) in vec.iter().enumerate() {
             //I18N
-            previous_bad.push(Gate::from(item.as_str().unwrap(), dictionary, macros));
+            previous_bad.push(Gate::from(
+                item.as_str().unwrap(),
+                lines.value("pre_bad").item(i).line,
+                dictionary,
+                macros,
+                format,
+            ));
         }
     }
 
     if let Some(vec) = pass["post_bad"].as_vec() {
-        for item in vec {
-            post_bad.push(Gate::from(item.as_str().unwrap(), dictionary, macros));
+        for (i, item) in vec.iter().enumerate() {
+            post_bad.push(Gate::from(
+                item.as_str().unwrap(),
+                lines.value("post_bad").item(i).line,
+                dictionary,
+                macros,
+                format,
+            ));
         }
     }
 
+    for bad_gate in &previous_bad {
+        check_bad_gate(bad_gate, &text, dictionary);
+    }
+
     let vars = text.variables.clone();
     (
         PassageElem::Passage(Passage {
@@ -623,83 +843,196 @@ fn convert_pass(
     )
 }
 
+/// Warns if a bad gate binds a variable to an expression equivalent to the
+/// one bound by the good gate (the bad gate is, accidentally, correct)
+fn check_bad_gate(bad_gate: &Gate, good_gate: &Gate, dictionary: &DictVariables) {
+    for (name, bad_expr) in &bad_gate.variables {
+        if dictionary.contains_key(name) {
+            continue;
+        }
+
+        if let Some(good_expr) = good_gate.variables.get(name) {
+            let bad_expanded = bad_expr.expand(&bad_gate.variables);
+            let good_expanded = good_expr.expand(&good_gate.variables);
+
+            if bad_expanded.equivalent(&good_expanded, dictionary) {
+                println!(
+                    "Warning: pre_bad gate is equivalent to the good gate.\n\tGate: {:?}\n\t{} = {} (good) and {} = {} (bad)",
+                    bad_gate.text,
+                    name,
+                    good_expr.show(),
+                    name,
+                    bad_expr.show()
+                );
+            }
+        }
+    }
+}
+
 //-----------
 
 fn convert_seq(
     elems: &[Yaml],
+    lines: &Lines,
     dictionary: &DictVariables,
     macros: &Macros,
+    format: &Format,
 ) -> (PassageElem, DictVariables, Macros) {
     let mut dict = dictionary.clone();
     let mut mac = macros.clone();
     let mut passages = Vec::<PassageElem>::new();
 
-    for elem in elems {
+    for (i, elem) in elems.iter().enumerate() {
         if let Some(paths) = is_macros("paths", elem) {
             mac.add_paths(paths);
         } else if let Some(macros_files) = is_macros("macros", elem) {
             mac.include_macros(macros_files);
+        } else if let Some(units_files) = is_macros("units", elem) {
+            mac.include_units(units_files);
         } else {
-            let (passelem, ndict, nmac) = convert_yaml(elem, &dict, &mac);
+            let (passelem, ndict, _) = convert_yaml(elem, lines.item(i), &dict, &mac, format);
             dict = ndict;
-            mac = nmac;
             passages.push(passelem);
         }
     }
-    (PassageElem::Sequence(passages), dict, mac)
+    // the directives of the sequence aren't used after it
+    (PassageElem::Sequence(passages), dict, macros.clone())
 }
 
 fn convert_con(
     elems: &[Yaml],
+    lines: &Lines,
     dictionary: &DictVariables,
     macros: &Macros,
+    format: &Format,
 ) -> (PassageElem, DictVariables, Macros) {
-    let base_dict = dictionary.clone();
-    let mut dict = dictionary.clone();
-    let mut mac = macros.clone();
+    let mut dicts = Vec::<DictVariables>::new();
     let mut passages = Vec::<PassageElem>::new();
 
-    for elem in elems {
-        let (passelem, ndict, nmac) = convert_yaml(elem, &base_dict, &mac);
-        dict.extend(ndict);
-        mac = nmac;
+    for (i, elem) in elems.iter().enumerate() {
+        let (passelem, ndict, _) = convert_yaml(elem, lines.item(i), dictionary, macros, format);
+        dicts.push(ndict);
         passages.push(passelem)
     }
-    (PassageElem::Concurrent(passages), dict, mac)
+    // every branch is done: their variables are merged
+    let dict = join_branches(dictionary, &dicts, false, format);
+    (PassageElem::Concurrent(passages), dict, macros.clone())
 }
 
 fn convert_alt(
     elems: &[Yaml],
+    lines: &Lines,
     dictionary: &DictVariables,
     macros: &Macros,
+    format: &Format,
 ) -> (PassageElem, DictVariables, Macros) {
-    let base_dict = dictionary.clone();
-    let mut dict = dictionary.clone();
-    let mut mac = macros.clone();
+    let mut dicts = Vec::<DictVariables>::new();
     let mut passages = Vec::<PassageElem>::new();
 
-    for elem in elems {
-        let (passelem, ndict, nmac) = convert_yaml(elem, &base_dict, &mac);
-        dict = ndict;
-        mac = nmac;
+    for (i, elem) in elems.iter().enumerate() {
+        let (passelem, ndict, _) = convert_yaml(elem, lines.item(i), dictionary, macros, format);
+        dicts.push(ndict);
         passages.push(passelem)
     }
-    // dict is the dictionary of last branch
-    (PassageElem::Alternative(passages), dict, mac)
+    // only one branch is done: its variables must be bound in every branch
+    let dict = join_branches(dictionary, &dicts, true, format);
+    (PassageElem::Alternative(passages), dict, macros.clone())
+}
+
+/// Dictionary after the branches of alt or con (dicts). A variable changed in
+/// branches is kept if it has the same value in all of them and, with
+/// every_branch, it is bound in every branch. Otherwise it is unbound: using
+/// it later is an error. A kept variable keeps the definition of its first
+/// branch while the variables of definition are the same after the join
+/// (otherwise it keeps its value).
+fn join_branches(
+    base: &DictVariables,
+    dicts: &[DictVariables],
+    every_branch: bool,
+    format: &Format,
+) -> DictVariables {
+    let mut dict = base.clone();
+    let mut kept = Vec::<(&String, &Expression, &DictVariables)>::new();
+
+    let mut names: Vec<&String> = dicts.iter().flat_map(|d| d.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    for name in names {
+        let bound: Vec<(&Expression, &DictVariables)> = dicts
+            .iter()
+            .filter_map(|d| d.get(name).map(|expr| (expr, d)))
+            .collect();
+        let (first, first_dict) = bound[0];
+
+        let everywhere = bound.len() == dicts.len();
+        if everywhere && bound.iter().all(|(expr, _)| base.get(name) == Some(expr)) {
+            // unchanged in branches
+            continue;
+        }
+
+        let consistent = (everywhere || !every_branch)
+            && bound
+                .iter()
+                .all(|(expr, _)| !matches!(expr, Expression::Unbound(..)))
+            && bound
+                .iter()
+                .all(|(expr, d)| same_value(first, first_dict, expr, d, format));
+
+        if consistent {
+            dict.insert(name.clone(), first.clone());
+            kept.push((name, first, first_dict));
+        } else {
+            dict.insert(name.clone(), Expression::Unbound(name.clone()));
+        }
+    }
+
+    // definitions with variables changed by the join are replaced by values
+    loop {
+        let changed = kept.iter().position(|(_, first, first_dict)| {
+            first
+                .free_variables()
+                .iter()
+                .any(|v| dict.get(v) != first_dict.get(v))
+        });
+        match changed {
+            Some(pos) => {
+                let (name, first, first_dict) = kept.remove(pos);
+                dict.insert(name.clone(), first.literal_value(first_dict));
+            }
+            None => break,
+        }
+    }
+
+    dict
 }
 
 fn convert_cond(
-    cond: &Yaml,
-    cont: &Yaml,
+    yaml: &Yaml,
+    lines: &Lines,
     dictionary: &DictVariables,
     macros: &Macros,
+    format: &Format,
 ) -> (PassageElem, DictVariables, Macros) {
-    let cond = Gate::from(cond.as_str().unwrap(), dictionary, macros);
+    let cond = Gate::from(
+        yaml["cond"].as_str().unwrap(),
+        lines.value("cond").line,
+        dictionary,
+        macros,
+        format,
+    );
+    let (cont, lines) = (&yaml["cont"], lines.value("cont"));
+    if browser::has_placeholder(&cond.text) {
+        panic!(
+            "Condition {:?} depends on rand and it can't be evaluated in the browser",
+            cond.source
+        );
+    }
 
     if cond.text == "1" {
-        convert_yaml(cont, dictionary, macros)
+        convert_yaml(cont, lines, dictionary, macros, format)
     } else {
-        let (passage_elem, _, _) = convert_yaml(cont, dictionary, macros);
+        let (passage_elem, _, _) = convert_yaml(cont, lines, dictionary, macros, format);
         let text = passage_elem.text();
         (
             PassageElem::Passage(Passage {
@@ -714,35 +1047,29 @@ fn convert_cond(
 }
 //-------------------------
 
+// Title of passage: Start or the hash of the keys of the passages from Start
 #[derive(Debug, Clone)]
-pub struct PassageTitle(Vec<u16>);
+pub struct PassageTitle(Option<u64>);
 
 impl PassageTitle {
     pub fn new() -> Self {
-        PassageTitle(vec![0])
-    }
-
-    pub fn inc(&mut self) {
-        let number = self.0.pop().unwrap();
-
-        self.0.push(number + 1);
+        PassageTitle(None)
     }
 
-    pub fn add_level(&mut self) {
-        self.0.push(0);
+    /// Title of a passage after this one
+    pub fn child(&self, key: &str) -> Self {
+        let parent = self.0.map_or_else(String::new, |hash| format!("{:x}", hash));
+        PassageTitle(Some(city_hash_64(
+            format!("{}\n{}", parent, key).as_bytes(),
+        )))
     }
 }
 
 impl fmt::Display for PassageTitle {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.0.len() == 1 && self.0[0] == 0 {
-            write!(f, "Start")
-        } else {
-            let mut output = String::from("Chapter");
-            for digit in &self.0[1..] {
-                output = format!("{}-{}", output, digit + 1);
-            }
-            write!(f, "{}", output)
+        match self.0 {
+            None => write!(f, "Start"),
+            Some(hash) => write!(f, "Chapter-{:x}", hash),
         }
     }
 }
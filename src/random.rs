@@ -0,0 +1,35 @@
+// Random numbers of the exercise
+//
+// The values of rand (evaluated in the build) and the order of the options
+// are drawn from generators seeded with the seed of the exercise (its id and
+// its optional key "seed"), so rebuilding an unchanged exercise gives the same
+// output. Each rand of a gate gets its own seed from the generator of the
+// exercise (Macros::rng) when the gate is read: its value is the same every
+// time it is evaluated. The order of the options of a passage depends only
+// on the seed and the passage, not on the values drawn before.
+//
+
+use cityhash::cityhash_1_1_1::city_hash_64;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// generator shared by the scopes of an exercise
+pub type Generator = Rc<RefCell<StdRng>>;
+
+pub fn generator(seed: u64) -> Generator {
+    Rc::new(RefCell::new(StdRng::seed_from_u64(seed)))
+}
+
+/// Random value in [0, 1) of a rand with seed
+pub fn value(seed: u64) -> f64 {
+    StdRng::seed_from_u64(seed).gen::<f64>()
+}
+
+/// Shuffles items with a generator of seed and key
+pub fn shuffle<T>(items: &mut [T], seed: u64, key: &str) {
+    let seed = city_hash_64(format!("{:x}\n{}", seed, key).as_bytes());
+    items.shuffle(&mut StdRng::seed_from_u64(seed));
+}
@@ -0,0 +1,193 @@
+// Exact rational numbers
+//
+// Magnitudes keep the exact value (numerator / denominator) while the
+// operations allow it: literals, +, -, *, / and pow with integer exponent.
+// Irrational operations (sqrt of non squares, log, ln, ...) and overflows
+// fall back to floating point (rational is None).
+//
+
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rational {
+    pub num: i64,
+    pub den: i64, // positive and coprime with num
+}
+
+// greatest integer exactly representable in f64
+const MAX_EXACT: f64 = 9007199254740992.0;
+
+fn gcd(a: i64, b: i64) -> i64 {
+    let (mut a, mut b) = (a.abs(), b.abs());
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    a
+}
+
+impl Rational {
+    pub fn new(num: i64, den: i64) -> Option<Self> {
+        if den == 0 {
+            return None;
+        }
+        let divisor = gcd(num, den).max(1);
+        let sign = if den < 0 { -1 } else { 1 };
+        Some(Rational {
+            num: (num / divisor).checked_mul(sign)?,
+            den: (den / divisor).checked_mul(sign)?,
+        })
+    }
+
+    /// Rational of a float if it is an integer
+    pub fn from_integer(value: f64) -> Option<Self> {
+        if value.fract() == 0.0 && value.abs() <= MAX_EXACT {
+            Rational::new(value as i64, 1)
+        } else {
+            None
+        }
+    }
+
+    /// Rational of a decimal literal: "12", "0.25", "1.5e3"
+    pub fn from_decimal(string: &str) -> Option<Self> {
+        let (mantissa, exponent) = match string.split_once('e') {
+            Some((mantissa, exponent)) => (mantissa, exponent.parse::<u32>().ok()?),
+            None => (string, 0),
+        };
+        let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let digits = format!("{}{}", integer, fraction);
+        let sign = if digits.starts_with('-') { -1 } else { 1 };
+        let digits = digits.trim_start_matches(&['-', '+'][..]);
+
+        let num: i64 = digits.parse().ok()?;
+        let den = 10i64.checked_pow(fraction.len() as u32)?;
+        let scale = 10i64.checked_pow(exponent)?;
+        Rational::new(num.checked_mul(sign)?.checked_mul(scale)?, den)
+    }
+
+    /// Rational of a power of 10 (factors of prefixes)
+    pub fn from_factor(factor: f64) -> Option<Self> {
+        if factor >= 1.0 {
+            Rational::from_integer(factor.round())
+        } else {
+            Rational::new(1, Rational::from_integer((1.0 / factor).round())?.num)
+        }
+    }
+
+    pub fn value(&self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.den == 1
+    }
+
+    pub fn add(&self, other: &Rational) -> Option<Rational> {
+        let num = self
+            .num
+            .checked_mul(other.den)?
+            .checked_add(other.num.checked_mul(self.den)?)?;
+        Rational::new(num, self.den.checked_mul(other.den)?)
+    }
+
+    pub fn neg(&self) -> Option<Rational> {
+        Rational::new(self.num.checked_neg()?, self.den)
+    }
+
+    pub fn abs(&self) -> Rational {
+        Rational {
+            num: self.num.abs(),
+            den: self.den,
+        }
+    }
+
+    pub fn mul(&self, other: &Rational) -> Option<Rational> {
+        // cross reduction avoids some overflows
+        let a = gcd(self.num, other.den).max(1);
+        let b = gcd(other.num, self.den).max(1);
+        Rational::new(
+            (self.num / a).checked_mul(other.num / b)?,
+            (self.den / b).checked_mul(other.den / a)?,
+        )
+    }
+
+    pub fn inv(&self) -> Option<Rational> {
+        Rational::new(self.den, self.num)
+    }
+
+    pub fn div(&self, other: &Rational) -> Option<Rational> {
+        self.mul(&other.inv()?)
+    }
+
+    /// self^exponent if exponent is an integer
+    pub fn pow(&self, exponent: &Rational) -> Option<Rational> {
+        if !exponent.is_integer() {
+            return None;
+        }
+        let base = if exponent.num < 0 { self.inv()? } else { *self };
+        let exponent = u32::try_from(exponent.num.checked_abs()?).ok()?;
+        Rational::new(
+            base.num.checked_pow(exponent)?,
+            base.den.checked_pow(exponent)?,
+        )
+    }
+
+    /// square root if numerator and denominator are perfect squares
+    pub fn sqrt(&self) -> Option<Rational> {
+        let root = |n: i64| -> Option<i64> {
+            let r = (n as f64).sqrt().round() as i64;
+            if n >= 0 && r.checked_mul(r)? == n {
+                Some(r)
+            } else {
+                None
+            }
+        };
+        Rational::new(root(self.num)?, root(self.den)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rational(num: i64, den: i64) -> Rational {
+        Rational::new(num, den).unwrap()
+    }
+
+    #[test]
+    fn fractions_are_reduced() {
+        assert_eq!(rational(6, 8), Rational { num: 3, den: 4 });
+        assert_eq!(rational(3, -6), Rational { num: -1, den: 2 });
+        assert_eq!(rational(0, 5), Rational { num: 0, den: 1 });
+        assert_eq!(Rational::new(1, 0), None);
+    }
+
+    #[test]
+    fn decimal_literals() {
+        assert_eq!(Rational::from_decimal("0.25"), Some(rational(1, 4)));
+        assert_eq!(Rational::from_decimal("-1.5"), Some(rational(-3, 2)));
+        assert_eq!(Rational::from_decimal("1.5e3"), Some(rational(1500, 1)));
+        assert_eq!(Rational::from_factor(1e-3), Some(rational(1, 1000)));
+        assert_eq!(Rational::from_integer(2.5), None);
+    }
+
+    #[test]
+    fn operations() {
+        let (half, third) = (rational(1, 2), rational(1, 3));
+        assert_eq!(half.add(&third), Some(rational(5, 6)));
+        assert_eq!(half.mul(&third), Some(rational(1, 6)));
+        assert_eq!(half.div(&third), Some(rational(3, 2)));
+        assert_eq!(third.pow(&rational(-2, 1)), Some(rational(9, 1)));
+        assert_eq!(third.pow(&half), None);
+        assert_eq!(rational(4, 9).sqrt(), Some(rational(2, 3)));
+        assert_eq!(rational(2, 1).sqrt(), None);
+    }
+
+    #[test]
+    fn overflows_are_not_exact() {
+        let big = rational(i64::MAX, 1);
+        assert_eq!(big.add(&big), None);
+        assert_eq!(rational(10, 1).pow(&rational(30, 1)), None);
+    }
+}
@@ -1,6 +1,48 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
 use crate::exercise::Exercise;
+use crate::sourcemap;
+
+/// Kind of passage (in the events of analytics)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Step, // text of the exercise with its choices
+    Note, // note of a good gate
+    Bad,  // note of a bad gate
+    End,  // last passage
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Step => "step",
+            Kind::Note => "note",
+            Kind::Bad => "bad",
+            Kind::End => "end",
+        }
+    }
+}
+
+/// State of the rendering of an exercise: the seed of the order of the
+/// options and what is found while rendering
+pub struct Context {
+    pub seed: u64,
+    pub templates: HashMap<PathBuf, String>, // instantiated figures (see figure)
+    pub figures: Vec<(String, Vec<u8>)>,     // (name in figures directory, contents)
+    pub passages: Vec<sourcemap::Entry>,     // passages of the map
+}
+
+impl Context {
+    pub fn new(seed: u64, templates: HashMap<PathBuf, String>) -> Self {
+        Context {
+            seed,
+            templates,
+            figures: Vec::new(),
+            passages: Vec::new(),
+        }
+    }
+}
 
 pub trait Render {
     fn generate_output_filename(&self, output_dir: &Path, input_filename: &Path) -> PathBuf;
@@ -10,13 +52,17 @@ pub trait Render {
         String::new()
     }
 
-    fn begin_passage(&self, id: &str) -> String;
+    /// Id of passage in the output (anchor, name...)
+    fn anchor(&self, id: &str) -> String {
+        id.to_string()
+    }
+
+    fn begin_passage(&self, id: &str, kind: Kind) -> String;
     fn end_passage(&self, _id: &str) -> String {
         String::new()
     }
 
-
-    fn text(&mut self, text: &str) -> String;
+    fn text(&mut self, text: &str, context: &mut Context) -> String;
     fn link(&self, text: &str, target: &str) -> String;
 
     fn begin_choices(&self, text: &str) -> String;
@@ -28,5 +74,4 @@ pub trait Render {
     fn end_option(&self, _id: &str) -> String {
         String::new()
     }
-
 }
@@ -1,8 +1,13 @@
+use lazy_static::lazy_static;
+use regex::Regex;
 use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
+use crate::analytics;
 use crate::exercise::Exercise;
-use crate::render::Render;
+use crate::figure::{self, Block};
+use crate::markdown;
+use crate::render::{Context, Kind, Render};
 
 pub struct MathJax {}
 
@@ -16,7 +21,7 @@ impl Render for MathJax {
     }
 
     fn begin_exercise(&self, exercise: &Exercise) -> String {
-        let output = format!(
+        let mut output = format!(
             "::StoryTitle
 
 {}
@@ -37,20 +42,68 @@ importScripts([
     }})
     .catch(err => console.error(`MathJax load error: ${{err}}`));
 
+/* Metadata of the exercise. */
+setup.metadata = {};
+
 ",
             exercise.title,
             exercise.uuid,
+            exercise.metadata.json(&[("title", &exercise.title)]),
         );
 
+        // values computed in the browser: each passage is filled when it is
+        // shown (MathJax typesets it again)
+        if !exercise.script.is_empty() {
+            output += &format!(
+                "/* Values of the exercise. */
+{}$(document).on(':passagedisplay', ev => {{
+    fillPlaceholders(ev.content);
+    if (window.MathJax && MathJax.typesetPromise) {{
+        MathJax.typesetPromise([ev.content]);
+    }}
+}});
+
+",
+                exercise.script
+            );
+        }
+
+        // events of analytics (passages shown, with their kind in the tags)
+        let script = analytics::script(exercise, "Start");
+        if !script.is_empty() {
+            output += &format!(
+                "/* Events of analytics. */{}
+$(document).on(':passagedisplay', ev => {{
+    const kinds = [\"step\", \"note\", \"bad\", \"end\"];
+    tweeproblemsShown(ev.passage.title, ev.passage.tags.find(tag => kinds.includes(tag)) || \"step\");
+}});
+
+",
+                script
+            );
+        }
+
         output
     }
 
-    fn begin_passage(&self, id: &str) -> String {
-        format!(":: {}\n\n", id)
+    fn begin_passage(&self, id: &str, kind: Kind) -> String {
+        format!(":: {} [{}]\n\n", id, kind.name())
     }
 
-    fn text(&mut self, text: &str) -> String {
-        text.to_string()
+    fn text(&mut self, text: &str, context: &mut Context) -> String {
+        lazy_static! {
+            static ref RE_DISPLAY: Regex = Regex::new(r"\[\[\[(.+?)\]\]\]").unwrap();
+            static ref RE_INLINE: Regex = Regex::new(r"\(\(\((.+?)\)\)\)").unwrap();
+        }
+        let output = figure::blocks(text)
+            .into_iter()
+            .map(|block| match block {
+                Block::Text(text) => markdown::to_twee(&text),
+                Block::Figure(figure) => figure.html(&context.templates, &mut context.figures),
+            })
+            .collect::<String>();
+        let output = RE_DISPLAY.replace_all(&output, r"\[$1\]");
+        RE_INLINE.replace_all(&output, r"\($1\)").to_string()
     }
 
     fn link(&self, text: &str, target: &str) -> String {
@@ -3,8 +3,11 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use std::path::{Path, PathBuf};
 
+use crate::analytics;
 use crate::exercise::Exercise;
-use crate::render::Render;
+use crate::figure::{self, Block};
+use crate::markdown;
+use crate::render::{Context, Kind, Render};
 
 pub struct Reveal {
     eqnumber: usize,
@@ -94,6 +97,14 @@ impl Reveal {
     }
 }
 
+/// Text escaped for HTML (text and attribute values)
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
 impl Render for Reveal {
     fn generate_output_filename(&self, output_dir: &Path, input: &Path) -> PathBuf {
         let mut output = output_dir.to_path_buf();
@@ -104,15 +115,35 @@ impl Render for Reveal {
     }
 
     fn begin_exercise(&self, exercise: &Exercise) -> String {
+        let metadata = &exercise.metadata;
+        let lang = match &metadata.language {
+            Some(language) => format!(" lang=\"{}\"", escape(language)),
+            None => String::new(),
+        };
+        let mut meta = String::new();
+        if let Some(author) = &metadata.author {
+            meta += &format!("        <meta name=\"author\" content=\"{}\">\n", escape(author));
+        }
+        if !metadata.tags.is_empty() {
+            meta += &format!(
+                "        <meta name=\"keywords\" content=\"{}\">\n",
+                escape(&metadata.tags.join(", "))
+            );
+        }
+        let json = metadata
+            .json(&[("title", &exercise.title)])
+            .replace("</", "<\\/");
+
         let output = format!(
             r#"<!doctype html>
-<html>
+<html{}>
     <head>
         <meta charset="utf-8">
         <meta name="viewport" content="width=device-width, initial-scale=1.0, maximum-scale=1.0, user-scalable=no">
-
+{}
         <title>{}</title>
         <script>var uuid ="{}"</script>
+        <script type="application/json" id="metadata">{}</script>
 
         <link rel="stylesheet" href="dist/reset.css">
         <link rel="stylesheet" href="dist/reveal.css">
@@ -157,19 +188,33 @@ impl Render for Reveal {
         <div class="reveal">
             <div class="slides">
 "#,
+            lang,
+            meta,
             exercise.title,
             exercise.uuid,
+            json,
         );
 
         output
     }
 
-    fn end_exercise(&self, _exercise: &Exercise) -> String {
-        String::from(
+    fn end_exercise(&self, exercise: &Exercise) -> String {
+        let mut output = String::from(
             r#"
             </div>
         </div>
+"#,
+        );
+
+        // values computed in the browser (before MathJax)
+        if !exercise.script.is_empty() {
+            output += &format!(
+                "\n        <script>\n{}fillPlaceholders(document.body);\n        </script>\n",
+                exercise.script
+            );
+        }
 
+        output += r#"
         <script src="dist/reveal.js"></script>
         <script src="plugin/math/math.js"></script>
         <script>
@@ -216,63 +261,55 @@ impl Render for Reveal {
                 handleSlideScrolling(event.currentSlide);
                 });
         </script>
-        <script>
-            // event listener
-            document.addEventListener(`click`, e => {
-                const origin = e.target.closest("a");
-  
-                if (origin) {
-                    console.log(`UUID ${uuid}`);
-                    console.log(`You clicked ${origin.href}`);
-                }
-            });
+"#;
+
+        // events of analytics (passages shown)
+        let script = analytics::script(exercise, &self.hash("Start"));
+        if !script.is_empty() {
+            output += &format!(
+                "        <script>{}
+            function tweeproblemsSlide(event) {{
+                tweeproblemsShown(event.currentSlide.id, event.currentSlide.dataset.kind);
+            }}
+            Reveal.addEventListener('ready', tweeproblemsSlide);
+            Reveal.addEventListener('slidechanged', tweeproblemsSlide);
         </script>
-    </body>
-</html>
-"#,
-        )
+",
+                script
+            );
+        }
+
+        output += "    </body>\n</html>\n";
+
+        output
     }
 
-    fn begin_passage(&self, id: &str) -> String {
-        format!("\n<section id=\"{}\">\n", self.hash(id))
+    fn anchor(&self, id: &str) -> String {
+        self.hash(id)
+    }
+
+    fn begin_passage(&self, id: &str, kind: Kind) -> String {
+        format!(
+            "\n<section id=\"{}\" data-kind=\"{}\">\n",
+            self.hash(id),
+            kind.name()
+        )
     }
 
     fn end_passage(&self, id: &str) -> String {
         format!("<!-- {} --></section>\n", self.hash(id))
     }
 
-    fn text(&mut self, text: &str) -> String {
-        let mut output = String::new();
-        let mut in_paragraph = false;
-
-        let paragraphs: Vec<&str> = text.split('\n').collect();
-        for line in paragraphs {
-            let mut line = line.trim().to_string();
-
-            // empty line
-            if line.is_empty() {
-                if in_paragraph {
-                    output += "\n  </p>\n\n";
-                    in_paragraph = false;
-                }
-                continue;
-            }
-
-            // normal line
-            if !in_paragraph {
-                output += "  <p>\n    ";
-                in_paragraph = true;
-            }
-            line = self.display_eq(&line);
-            line = self.inline_eq(&line);
-            output = output + &line + " ";
-        }
-
-        if in_paragraph {
-            output += "\n  </p>\n\n";
-        }
-
-        output
+    fn text(&mut self, text: &str, context: &mut Context) -> String {
+        let output = figure::blocks(text)
+            .into_iter()
+            .map(|block| match block {
+                Block::Text(text) => markdown::to_html(&text),
+                Block::Figure(figure) => figure.html(&context.templates, &mut context.figures),
+            })
+            .collect::<String>();
+        let output = self.display_eq(&output);
+        self.inline_eq(&output)
     }
 
     fn link(&self, text: &str, target: &str) -> String {
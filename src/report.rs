@@ -0,0 +1,167 @@
+// Report of the events of analytics (command "report")
+//
+// Summarizes the events stored by "collect": sessions and completions of
+// each exercise and, for each step, the choices, the error rate and the
+// bad gates chosen (the most common first). The ids of the passages are
+// mapped back to the gates of the source with the maps written next to
+// the outputs (see sourcemap) in the directories of maps.
+//
+
+use anyhow::Result;
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// gate of a passage in a map (key, line and text)
+struct Gate {
+    key: String,
+    line: Option<u64>,
+    text: String,
+}
+
+// exercise id -> (source, passage id -> gate)
+type Maps = HashMap<String, (String, HashMap<String, Gate>)>;
+
+#[derive(Default)]
+struct Summary {
+    title: String,
+    sessions: usize,
+    completed: usize,
+    // passage -> (choices, bad target -> times)
+    steps: BTreeMap<String, (usize, BTreeMap<String, usize>)>,
+}
+
+pub fn run(events: &Path, map_dirs: &[PathBuf]) -> Result<()> {
+    let maps = load_maps(map_dirs)?;
+    let mut exercises: BTreeMap<String, Summary> = BTreeMap::new();
+
+    for (number, line) in fs::read_to_string(events)?.lines().enumerate() {
+        let event: Value = match serde_json::from_str(line) {
+            Ok(event) => event,
+            Err(e) => {
+                println!("Bad event in line {}: {}", number + 1, e);
+                continue;
+            }
+        };
+        let exercise = match event["exercise"].as_str() {
+            Some(exercise) => exercise.to_string(),
+            None => continue,
+        };
+        let text = |name: &str| event[name].as_str().unwrap_or_default().to_string();
+        let summary = exercises.entry(exercise).or_default();
+        if let Some(title) = event["title"].as_str() {
+            summary.title = title.to_string();
+        }
+        match event["type"].as_str() {
+            Some("session") => summary.sessions += 1,
+            Some("complete") => summary.completed += 1,
+            Some("choice") => {
+                let step = summary.steps.entry(text("passage")).or_default();
+                step.0 += 1;
+                if event["good"] == Value::Bool(false) {
+                    *step.1.entry(text("target")).or_default() += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (id, summary) in exercises {
+        let map = maps.get(&id);
+        match map {
+            Some((source, _)) => println!("{} ({}, {})", summary.title, id, source),
+            None => println!("{} ({}, without map)", summary.title, id),
+        }
+        println!(
+            "    {} sessions, {} completed",
+            summary.sessions, summary.completed
+        );
+
+        let gate = |passage: &str| map.and_then(|(_, gates)| gates.get(passage));
+
+        // steps with more bad choices first
+        let mut steps: Vec<_> = summary.steps.into_iter().collect();
+        steps.sort_by_key(|(_, (_, bad))| Reverse(bad.values().sum::<usize>()));
+        for (passage, (choices, bad)) in steps {
+            let errors: usize = bad.values().sum();
+            println!(
+                "    {}: {} choices, {} bad ({:.0}%)",
+                describe(&passage, gate(&passage)),
+                choices,
+                errors,
+                100.0 * errors as f64 / choices as f64
+            );
+            let mut bad: Vec<_> = bad.into_iter().collect();
+            bad.sort_by_key(|(_, times)| Reverse(*times));
+            for (target, times) in bad {
+                println!("        {} x {}", times, describe(&target, gate(&target)));
+            }
+        }
+    }
+    Ok(())
+}
+
+// passage with its gate (first line of its text)
+fn describe(passage: &str, gate: Option<&Gate>) -> String {
+    match gate {
+        Some(gate) => format!(
+            "{} line {}: {:?}",
+            gate.key,
+            gate.line
+                .map_or_else(|| String::from("?"), |line| line.to_string()),
+            gate.text.lines().next().unwrap_or("").trim()
+        ),
+        None => passage.to_string(),
+    }
+}
+
+// maps (*.map.json) of directories
+fn load_maps(dirs: &[PathBuf]) -> Result<Maps> {
+    let mut maps = Maps::new();
+    let mut pending = dirs.to_vec();
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)?.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            if !path.to_string_lossy().ends_with(".map.json") {
+                continue;
+            }
+            let map: Value = match serde_json::from_str(&fs::read_to_string(&path)?) {
+                Ok(map) => map,
+                Err(e) => {
+                    println!("Bad map {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            let (exercise, source) = match (map["exercise"].as_str(), map["source"].as_str()) {
+                (Some(exercise), Some(source)) => (exercise, source),
+                _ => {
+                    println!("Map without exercise: {:?}", path);
+                    continue;
+                }
+            };
+            let gates = match map["passages"].as_object() {
+                Some(passages) => passages
+                    .iter()
+                    .map(|(id, gate)| {
+                        let text = |name: &str| gate[name].as_str().unwrap_or_default().to_string();
+                        let gate = Gate {
+                            key: text("key"),
+                            line: gate["line"].as_u64(),
+                            text: text("text"),
+                        };
+                        (id.clone(), gate)
+                    })
+                    .collect(),
+                None => HashMap::new(),
+            };
+            maps.insert(exercise.to_string(), (source.to_string(), gates));
+        }
+    }
+    Ok(maps)
+}
@@ -0,0 +1,261 @@
+// Preview server (command "serve")
+//
+// Builds the exercises (see build), serves the output directory (and the
+// reveal assets) over HTTP and rebuilds when an exercise or its macros or
+// units files change. The served pages listen to the server (server sent
+// events) and reload on the first passage changed by the rebuild.
+//
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::build;
+
+const EVENTS: &str = "/__events";
+
+const RELOAD: &str = r##"
+        <script>
+            // reload when the exercise is rebuilt (tweeproblems serve)
+            new EventSource("/__events").onmessage = function (event) {
+                const [page, section] = event.data.split("#");
+                if (decodeURIComponent(location.pathname).endsWith("/" + page)) {
+                    if (section) {
+                        location.hash = "#/" + section;
+                    }
+                    location.reload();
+                }
+            };
+        </script>
+"##;
+
+type Clients = Arc<Mutex<Vec<TcpStream>>>;
+
+pub fn run(
+    patterns: Vec<String>,
+    output_dir: &Path,
+    assets: Option<&Path>,
+    paths: Vec<String>,
+    port: u16,
+) -> Result<()> {
+    let jobs = thread::available_parallelism().map_or(1, |n| n.get());
+    build::build(&patterns, output_dir, &paths, jobs)?;
+    let mut fingerprint = build::fingerprint(&patterns, &paths)?;
+
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("serving {:?} at http://127.0.0.1:{}/", output_dir, port);
+
+    let clients: Clients = Arc::new(Mutex::new(vec![]));
+    {
+        let clients = clients.clone();
+        let roots: Vec<PathBuf> = std::iter::once(output_dir)
+            .chain(assets)
+            .map(Path::to_path_buf)
+            .collect();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let clients = clients.clone();
+                let roots = roots.clone();
+                thread::spawn(move || handle(stream, &roots, &clients));
+            }
+        });
+    }
+
+    loop {
+        thread::sleep(Duration::from_millis(500));
+        let current = match build::fingerprint(&patterns, &paths) {
+            Ok(current) => current,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        if current == fingerprint {
+            continue;
+        }
+        fingerprint = current;
+
+        let before = snapshot(output_dir);
+        let report = match build::build(&patterns, output_dir, &paths, jobs) {
+            Ok(report) => report,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        for output in report.built {
+            let section = first_changed_section(
+                before.get(&output).map(String::as_str).unwrap_or(""),
+                &fs::read_to_string(output_dir.join(&output)).unwrap_or_default(),
+            );
+            let event = format!(
+                "data: {}#{}\n\n",
+                output.to_string_lossy(),
+                section.unwrap_or_default()
+            );
+            clients
+                .lock()
+                .unwrap()
+                .retain_mut(|client| client.write_all(event.as_bytes()).is_ok());
+        }
+    }
+}
+
+// html pages of the output directory before a rebuild
+fn snapshot(output_dir: &Path) -> HashMap<PathBuf, String> {
+    let mut output = HashMap::new();
+    let mut pending = vec![output_dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if path.extension().is_some_and(|ext| ext == "html") {
+                if let Ok(contents) = fs::read_to_string(&path) {
+                    output.insert(
+                        path.strip_prefix(output_dir).unwrap().to_path_buf(),
+                        contents,
+                    );
+                }
+            }
+        }
+    }
+    output
+}
+
+// id of the first section (passage) new or different in after
+fn first_changed_section(before: &str, after: &str) -> Option<String> {
+    fn sections(html: &str) -> Vec<(&str, &str)> {
+        html.split("<section id=\"")
+            .skip(1)
+            .filter_map(|section| section.split_once('"'))
+            .collect()
+    }
+    let before: HashMap<&str, &str> = sections(before).into_iter().collect();
+
+    sections(after)
+        .into_iter()
+        .find(|(id, contents)| before.get(id) != Some(contents))
+        .map(|(id, _)| id.to_string())
+}
+
+//--------------------------------------------
+// HTTP
+
+fn handle(mut stream: TcpStream, roots: &[PathBuf], clients: &Clients) {
+    let mut request = String::new();
+    let mut reader = BufReader::new(&stream);
+    if reader.read_line(&mut request).is_err() {
+        return;
+    }
+    // headers are ignored
+    let mut line = String::new();
+    while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+        line.clear();
+    }
+
+    let path = match request.split_whitespace().nth(1) {
+        Some(path) => decode(path.split(['?', '#']).next().unwrap()),
+        None => return,
+    };
+
+    if path == EVENTS {
+        let header =
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n";
+        if stream.write_all(header.as_bytes()).is_ok() {
+            clients.lock().unwrap().push(stream);
+        }
+        return;
+    }
+
+    let path = if path.ends_with('/') {
+        format!("{}index.html", path)
+    } else {
+        path
+    };
+    let file = if path.split('/').any(|part| part == "..") {
+        None
+    } else {
+        roots
+            .iter()
+            .map(|root| root.join(path.trim_start_matches('/')))
+            .find(|file| file.is_file())
+    };
+
+    let response = match file {
+        Some(file) => {
+            let mut body = fs::read(&file).unwrap_or_default();
+            let extension = file.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+            if extension == "html" {
+                body = inject_reload(&String::from_utf8_lossy(&body)).into_bytes();
+            }
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+                content_type(extension),
+                body.len()
+            )
+            .into_bytes();
+            response.extend(body);
+            response
+        }
+        None => {
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\nConnection: close\r\n\r\nNot found"
+                .to_vec()
+        }
+    };
+    let _ = stream.write_all(&response);
+}
+
+fn inject_reload(html: &str) -> String {
+    match html.rfind("</body>") {
+        Some(position) => format!("{}{}{}", &html[..position], RELOAD, &html[position..]),
+        None => format!("{}{}", html, RELOAD),
+    }
+}
+
+fn content_type(extension: &str) -> &'static str {
+    match extension {
+        "html" => "text/html; charset=utf-8",
+        "css" => "text/css",
+        "js" => "application/javascript",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        _ => "application/octet-stream",
+    }
+}
+
+// percent decoding of the path of an url
+fn decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut output = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                output.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                output.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&output).to_string()
+}
@@ -0,0 +1,572 @@
+// Simplification and equivalence of expressions
+//
+// simplify: algebraic clean-up of an expression tree
+//   * folds constant subexpressions
+//   * flattens and sorts sums and products
+//   * cancels opposite summands and common factors of fractions
+//   * moves signs out of products and fractions
+//   * removes empty products and neutral elements (x+0, x*1, x/1, x^1)
+//
+// equivalent: two expressions are equivalent if they simplify to the same
+//   tree or, when that fails, if they evaluate to the same magnitude for
+//   several random values of their free variables. The values are drawn with
+//   a fixed seed, so the result is the same in every build. Expressions
+//   with lists or matrices are equivalent only if they simplify to the
+//   same tree.
+//
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::expression::{Arguments, DictVariables, Expression};
+use crate::magnitude::Magnitude;
+use crate::rational::Rational;
+
+// number of random evaluations before accepting two expressions as equivalent
+const TRIALS: usize = 8;
+// relative tolerance comparing values
+const TOLERANCE: f64 = 1e-9;
+
+impl Expression {
+    /// Returns an algebraically simplified copy of the expression.
+    /// The value of the result is the same but the formula may be different, so
+    /// it is not intended to be shown (show() displays what the author wrote).
+    pub fn simplify(&self) -> Expression {
+        let expr = self.clone().map_operands(&|e| e.simplify());
+
+        if is_constant(&expr) {
+            return fold(&expr);
+        }
+
+        match expr {
+            Expression::Add(items) => simplify_add(items),
+            Expression::Neg(expr) => negate(*expr),
+            Expression::Prod(items) => simplify_prod(items),
+            Expression::Div(items) => {
+                let mut items = items.into_iter();
+                let num = items.next().unwrap();
+                let den = items.next().unwrap();
+                simplify_div(num, den)
+            }
+            Expression::Pow(items) => simplify_pow(items),
+            Expression::Not(expr) => match *expr {
+                Expression::Not(inner) => *inner,
+                other => Expression::Not(Box::new(other)),
+            },
+            other => other,
+        }
+    }
+
+    /// Determines if self and other have the same value for any value of the
+    /// variables. Variables whose definition (in dict) depends on other variables
+    /// are replaced by that definition. The rest are free variables.
+    pub fn equivalent(&self, other: &Expression, dict: &DictVariables) -> bool {
+        let a = self.expand(dict).simplify();
+        let b = other.expand(dict).simplify();
+
+        if same(&a, &b) {
+            return true;
+        }
+
+        // random values are scalars
+        if a.is_matrix(dict) || b.is_matrix(dict) || a.is_list(dict) || b.is_list(dict) {
+            return false;
+        }
+
+        // random values have unknown unit, so they can't be converted
+        let a = a.without_conversions();
+        let b = b.without_conversions();
+
+        let mut names = a.free_variables();
+        names.extend(b.free_variables());
+        names.sort();
+        names.dedup();
+
+        // lists and matrices can't be replaced with random scalars (L mean and
+        // L median aren't the same), so they are unknown: not equivalent
+        if names.iter().any(|name| {
+            let variable = Expression::Variable(name.clone());
+            variable.is_list(dict) || variable.is_matrix(dict)
+        }) {
+            return false;
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..TRIALS {
+            let sample: DictVariables = names
+                .iter()
+                .map(|name| {
+                    let value = 0.5 + 4.5 * rng.gen::<f64>();
+                    let magnitude = Magnitude {
+                        value,
+                        uncertainty: 0.0,
+                        unit: String::from("¿?"),
+                        rational: None,
+                    };
+                    (name.clone(), Expression::Magnitude(magnitude))
+                })
+                .collect();
+
+            let va = a.value(&sample);
+            let vb = b.value(&sample);
+
+            if va.value.is_nan() && vb.value.is_nan() {
+                continue;
+            }
+            if !close(&va, &vb) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Removes the conversions of units (expression keeps the value in the original unit)
+    pub fn without_conversions(&self) -> Expression {
+        match self {
+            Expression::Convert(expr, ..) => expr.without_conversions(),
+            _ => self.clone().map_operands(&|e| e.without_conversions()),
+        }
+    }
+
+    /// Replaces the variables defined in dict with their definitions, except the
+    /// ones defined by constant expressions (data of the exercise).
+    pub fn expand(&self, dict: &DictVariables) -> Expression {
+        match self {
+            Expression::Variable(name) => match dict.get(name) {
+                Some(definition) if !definition.free_variables().is_empty() => {
+                    definition.expand(dict)
+                }
+                _ => self.clone(),
+            },
+            // the unknown of an equation isn't replaced
+            Expression::Root(_, unknown) => {
+                let mut inner = dict.clone();
+                inner.remove(unknown);
+                self.clone().map_operands(&|e| e.expand(&inner))
+            }
+            _ => self.clone().map_operands(&|e| e.expand(dict)),
+        }
+    }
+
+    /// Names of variables used in expression (without repetitions)
+    pub fn free_variables(&self) -> Vec<String> {
+        let mut output = vec![];
+        self.collect_variables(&mut output);
+        output.sort();
+        output.dedup();
+        output
+    }
+
+    fn collect_variables(&self, output: &mut Vec<String>) {
+        match self {
+            Expression::Variable(name) => output.push(name.clone()),
+            Expression::Root(items, unknown) => {
+                let mut inner = vec![];
+                for operand in items {
+                    operand.collect_variables(&mut inner);
+                }
+                output.extend(inner.into_iter().filter(|name| name != unknown));
+            }
+            _ => {
+                for operand in self.operands() {
+                    operand.collect_variables(output);
+                }
+            }
+        }
+    }
+}
+
+//------------------------------------------------
+
+fn is_constant(expr: &Expression) -> bool {
+    match expr {
+        Expression::Magnitude(..) => true,
+        Expression::Variable(..)
+        | Expression::Unbound(..)
+        | Expression::Rand(..)
+        | Expression::Matrix(..)
+        | Expression::List(..) => false,
+        _ => expr.operands().into_iter().all(is_constant),
+    }
+}
+
+fn fold(expr: &Expression) -> Expression {
+    match expr {
+        Expression::Magnitude(..) => expr.clone(),
+        Expression::Neg(inner) if matches!(**inner, Expression::Magnitude(..)) => expr.clone(),
+        _ => from_magnitude(expr.value(&DictVariables::new())),
+    }
+}
+
+fn from_magnitude(magnitude: Magnitude) -> Expression {
+    if magnitude.value >= 0.0 {
+        Expression::Magnitude(magnitude)
+    } else {
+        let value = magnitude.value.abs();
+        Expression::Neg(Box::new(Expression::Magnitude(Magnitude {
+            value,
+            rational: magnitude.rational.map(|r| r.abs()),
+            ..magnitude
+        })))
+    }
+}
+
+fn number(value: f64) -> Expression {
+    from_magnitude(Magnitude {
+        value,
+        uncertainty: 0.0,
+        unit: String::from("¿?"),
+        rational: Rational::from_integer(value),
+    })
+}
+
+/// magnitude of a literal expression
+fn literal(expr: &Expression) -> Option<&Magnitude> {
+    match expr {
+        Expression::Magnitude(mag) => Some(mag),
+        _ => None,
+    }
+}
+
+fn is_number(expr: &Expression, value: f64) -> bool {
+    match literal(expr) {
+        Some(mag) => mag.value == value && (mag.unit.is_empty() || mag.unit == "¿?"),
+        None => false,
+    }
+}
+
+fn sort_key(expr: &Expression) -> (bool, String) {
+    (literal(expr).is_none(), expr.show())
+}
+
+//------------------------------------------------
+
+fn negate(expr: Expression) -> Expression {
+    match expr {
+        Expression::Neg(inner) => *inner,
+        Expression::Add(items) => simplify_add(items.into_iter().map(negate).collect()),
+        Expression::Magnitude(ref mag) if mag.value == 0.0 => expr,
+        _ => Expression::Neg(Box::new(expr)),
+    }
+}
+
+fn with_sign(negative: bool, expr: Expression) -> Expression {
+    if negative {
+        negate(expr)
+    } else {
+        expr
+    }
+}
+
+fn simplify_add(items: Arguments) -> Expression {
+    // flattening
+    let mut terms: Arguments = vec![];
+    for item in items {
+        match item {
+            Expression::Add(sub) => terms.extend(sub),
+            _ => terms.push(item),
+        }
+    }
+
+    // constant folding (only between compatible units)
+    let mut constants: Vec<Magnitude> = vec![];
+    let mut rest: Arguments = vec![];
+    for term in terms {
+        let constant = match &term {
+            Expression::Magnitude(mag) => Some(mag.clone()),
+            Expression::Neg(inner) => literal(inner).map(|mag| Magnitude {
+                value: -mag.value,
+                rational: mag.rational.and_then(|r| r.neg()),
+                ..mag.clone()
+            }),
+            _ => None,
+        };
+
+        match constant {
+            Some(mag) => match constants
+                .iter_mut()
+                .find(|c| c.compatible_unit(&mag).is_some())
+            {
+                Some(acc) => {
+                    acc.unit = acc.compatible_unit(&mag).unwrap();
+                    acc.value += mag.value;
+                    acc.rational = acc.rational.zip(mag.rational).and_then(|(a, b)| a.add(&b));
+                }
+                None => constants.push(mag),
+            },
+            None => rest.push(term),
+        }
+    }
+
+    // cancellation of opposite terms
+    let mut terms: Arguments = vec![];
+    for term in rest {
+        let opposite = negate(term.clone());
+        match terms.iter().position(|t| same(t, &opposite)) {
+            Some(pos) => {
+                terms.remove(pos);
+            }
+            None => terms.push(term),
+        }
+    }
+
+    terms.sort_by_key(sort_key);
+    for constant in constants {
+        if constant.value != 0.0 {
+            terms.insert(0, from_magnitude(constant));
+        }
+    }
+
+    match terms.len() {
+        0 => number(0.0),
+        1 => terms.pop().unwrap(),
+        _ => Expression::Add(terms),
+    }
+}
+
+/// split an expression in factors of numerator and denominator
+fn factors(expr: Expression, negative: &mut bool, num: &mut Arguments, den: &mut Arguments) {
+    match expr {
+        Expression::Neg(inner) => {
+            *negative = !*negative;
+            factors(*inner, negative, num, den);
+        }
+        Expression::Prod(items) => {
+            for item in items {
+                factors(item, negative, num, den);
+            }
+        }
+        Expression::Div(items) => {
+            let mut items = items.into_iter();
+            factors(items.next().unwrap(), negative, num, den);
+            factors(items.next().unwrap(), negative, den, num);
+        }
+        _ => num.push(expr),
+    }
+}
+
+fn product(mut items: Arguments) -> Expression {
+    items.sort_by_key(sort_key);
+    match items.len() {
+        0 => number(1.0),
+        1 => items.pop().unwrap(),
+        _ => Expression::Prod(items),
+    }
+}
+
+/// multiplies all constants of items, returning the remaining items
+/// product of literal factors (with its exact value) and the other factors
+fn constant_factor(items: Arguments) -> (f64, Option<Rational>, Arguments) {
+    let mut value = 1.0;
+    let mut rational = Rational::new(1, 1);
+    let mut rest = vec![];
+
+    for item in items {
+        match literal(&item) {
+            Some(mag) => {
+                value *= mag.value;
+                rational = rational.zip(mag.rational).and_then(|(a, b)| a.mul(&b));
+            }
+            None => rest.push(item),
+        }
+    }
+
+    (value, rational, rest)
+}
+
+fn simplify_fraction(negative: bool, num: Arguments, den: Arguments) -> Expression {
+    let (num_value, num_rational, num) = constant_factor(num);
+    let (den_value, den_rational, den) = constant_factor(den);
+    let value = num_value / den_value;
+    let rational = num_rational.zip(den_rational).and_then(|(a, b)| a.div(&b));
+
+    if value == 0.0 {
+        return number(0.0);
+    }
+
+    // cancellation of common factors
+    let mut num = num;
+    let mut den_rest: Arguments = vec![];
+    for factor in den {
+        match num.iter().position(|n| same(n, &factor)) {
+            Some(pos) => {
+                num.remove(pos);
+            }
+            None => den_rest.push(factor),
+        }
+    }
+
+    let negative = negative ^ (value < 0.0);
+    if value.abs() != 1.0 {
+        let factor = Magnitude::new(value.abs(), String::from("¿?"))
+            .with_rational(rational.map(|r| r.abs()));
+        num.push(Expression::Magnitude(factor));
+    }
+
+    let expr = if den_rest.is_empty() {
+        product(num)
+    } else {
+        Expression::Div(vec![product(num), product(den_rest)])
+    };
+
+    with_sign(negative, expr)
+}
+
+fn simplify_prod(items: Arguments) -> Expression {
+    let mut negative = false;
+    let mut num = vec![];
+    let mut den = vec![];
+
+    factors(Expression::Prod(items), &mut negative, &mut num, &mut den);
+
+    simplify_fraction(negative, num, den)
+}
+
+fn simplify_div(num: Expression, den: Expression) -> Expression {
+    let mut negative = false;
+    let mut num_factors = vec![];
+    let mut den_factors = vec![];
+
+    factors(num, &mut negative, &mut num_factors, &mut den_factors);
+    factors(den, &mut negative, &mut den_factors, &mut num_factors);
+
+    simplify_fraction(negative, num_factors, den_factors)
+}
+
+fn simplify_pow(items: Arguments) -> Expression {
+    if is_number(&items[1], 1.0) {
+        items[0].clone()
+    } else if is_number(&items[1], 0.0) {
+        number(1.0)
+    } else {
+        Expression::Pow(items)
+    }
+}
+
+//------------------------------------------------
+
+fn close(a: &Magnitude, b: &Magnitude) -> bool {
+    if a.compatible_unit(b).is_none() {
+        return false;
+    }
+
+    let scale = a.value.abs().max(b.value.abs());
+    (a.value - b.value).abs() <= TOLERANCE * scale
+}
+
+/// structural equality, comparing literals with tolerance
+fn same(a: &Expression, b: &Expression) -> bool {
+    match (a, b) {
+        (Expression::Magnitude(ma), Expression::Magnitude(mb)) => close(ma, mb),
+        (Expression::Variable(na), Expression::Variable(nb)) => na == nb,
+        (Expression::Unit(ea, ua), Expression::Unit(eb, ub))
+        | (Expression::Convert(ea, ua, _), Expression::Convert(eb, ub, _)) => {
+            ua == ub && same(ea, eb)
+        }
+        (Expression::Aggregate(ka, ea), Expression::Aggregate(kb, eb)) => ka == kb && same(ea, eb),
+        (Expression::Element(ea, ia), Expression::Element(eb, ib)) => ia == ib && same(ea, eb),
+        (Expression::Root(oa, ua), Expression::Root(ob, ub)) => ua == ub && same_all(oa, ob),
+        (Expression::Matrix(ra, ca, oa), Expression::Matrix(rb, cb, ob)) => {
+            (ra, ca) == (rb, cb) && same_all(oa, ob)
+        }
+        (Expression::Unbound(na), Expression::Unbound(nb)) => na == nb,
+        (Expression::Rand(oa, sa), Expression::Rand(ob, sb)) => sa == sb && same_all(oa, ob),
+        _ => {
+            if std::mem::discriminant(a) != std::mem::discriminant(b) {
+                return false;
+            }
+            let oa = a.operands();
+            let ob = b.operands();
+            oa.len() == ob.len() && oa.iter().zip(ob.iter()).all(|(x, y)| same(x, y))
+        }
+    }
+}
+
+fn same_all(a: &[Expression], b: &[Expression]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| same(x, y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::macros::Macros;
+
+    fn expr(rpn: &str) -> Expression {
+        Expression::from(rpn, &Macros::new())
+    }
+
+    fn equivalent(a: &str, b: &str, dict: &DictVariables) -> bool {
+        expr(a).equivalent(&expr(b), dict)
+    }
+
+    #[test]
+    fn identities_are_removed() {
+        for (rpn, simplified) in [
+            ("x 0 +", "x"),
+            ("x 1 *", "x"),
+            ("x 1 /", "x"),
+            ("x 1 pow", "x"),
+            ("x 0 pow", "1"),
+            ("x y + y neg +", "x"),
+            ("2 3 * x +", "x 6 +"),
+        ] {
+            assert!(
+                same(&expr(rpn).simplify(), &expr(simplified).simplify()),
+                "{} isn't simplified to {}: {:?}",
+                rpn,
+                simplified,
+                expr(rpn).simplify()
+            );
+        }
+    }
+
+    #[test]
+    fn equivalent_expressions() {
+        let dict = DictVariables::new();
+        for (a, b) in [
+            ("x y +", "y x +"),
+            ("x y *", "y x *"),
+            ("x y + z +", "z y x + +"),
+            ("x y * x z * +", "y z + x *"),
+            ("x 2 * y 2 * /", "x y /"),
+            ("x 2 pow", "x x *"),
+        ] {
+            assert!(equivalent(a, b, &dict), "{} and {} aren't equivalent", a, b);
+        }
+    }
+
+    #[test]
+    fn not_equivalent_expressions() {
+        let dict = DictVariables::new();
+        for (a, b) in [
+            ("x y +", "x y *"),
+            ("x y /", "y x /"),
+            ("x y -", "y x -"),
+            ("x 2 pow", "x 2 *"),
+        ] {
+            assert!(!equivalent(a, b, &dict), "{} and {} are equivalent", a, b);
+        }
+    }
+
+    #[test]
+    fn units_must_match() {
+        let dict = DictVariables::new();
+        assert!(equivalent("2V 3V +", "5V", &dict));
+        assert!(!equivalent("2V 3V +", "5A", &dict));
+        assert!(!equivalent("1V", "1A", &dict));
+    }
+
+    #[test]
+    fn lists_and_matrices_are_not_sampled() {
+        let mut dict = DictVariables::new();
+        dict.insert(String::from("L"), expr("1 2 7 3 4 list"));
+        dict.insert(String::from("M"), expr("1 2 3 4 2 2 mat"));
+
+        assert!(equivalent("L mean", "L mean", &dict));
+        assert!(!equivalent("L mean", "L median", &dict));
+        assert!(!equivalent("L max", "L sum", &dict));
+        assert!(equivalent("M det", "M det", &dict));
+        assert!(!equivalent("M det", "M 1 1 elem", &dict));
+    }
+}
@@ -0,0 +1,206 @@
+// Map of passages to the source (sidecar of the output)
+//
+// Next to the output, the renderers write a JSON file (<output>.map.json)
+// with the passages of the exercise: the id of each one in the output (the
+// anchor of its section in reveal, its name in twee) mapped to its title,
+// kind and the gate leading to it: the yaml key of the gate (text, pre_bad
+// or post_bad), its text (with the expressions) and its line in the source.
+// The lines come from the markers of the parser of yaml (or the lines of
+// the options of twp files), kept in Lines while the exercise is loaded.
+// The command "report" uses the map to show the events of analytics with
+// their gates.
+//
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+use crate::exercise::Exercise;
+use crate::render::{Kind, Render};
+
+pub struct Entry {
+    pub title: String, // title of passage (PassageTitle)
+    pub kind: Kind,
+    pub key: &'static str,   // key of the gate (text, pre_bad or post_bad)
+    pub line: Option<usize>, // line of the gate in the source
+    pub source: String,      // text of the gate in the source
+}
+
+/// File of the map of output
+pub fn map_file(output: &Path) -> PathBuf {
+    let mut name = output.file_name().unwrap_or_default().to_os_string();
+    name.push(".map.json");
+    output.with_file_name(name)
+}
+
+/// Writes the map of the passages rendered (from input) next to output
+pub fn write(
+    output: &Path,
+    input: &Path,
+    exercise: &Exercise,
+    renderer: &dyn Render,
+    passages: &[Entry],
+) -> Result<()> {
+    let passages: Map<String, Value> = passages
+        .iter()
+        .map(|passage| {
+            let gate = json!({
+                "title": passage.title,
+                "kind": passage.kind.name(),
+                "key": passage.key,
+                "line": passage.line,
+                "text": passage.source.trim(),
+            });
+            (renderer.anchor(&passage.title), gate)
+        })
+        .collect();
+
+    let map = json!({
+        "exercise": exercise.uuid.to_string(),
+        "title": exercise.title,
+        "source": input.to_string_lossy(),
+        "passages": passages,
+    });
+    fs::write(map_file(output), serde_json::to_string_pretty(&map)? + "\n")?;
+    Ok(())
+}
+
+//--------------------------------------------
+// Lines of a yaml document
+
+/// Lines (from 1) of the nodes of a yaml document, a tree parallel to its
+/// Yaml: the line of a node (the first line of the text of block scalars,
+/// as given by the parser) and the lines of the items of arrays and of the values of hashes
+#[derive(Debug, Clone, Default)]
+pub struct Lines {
+    pub line: Option<usize>,
+    items: Vec<Lines>,
+    values: Vec<(String, Lines)>,
+}
+
+lazy_static! {
+    static ref NO_LINES: Lines = Lines::default();
+}
+
+impl Lines {
+    pub fn scalar(line: usize) -> Self {
+        Lines {
+            line: Some(line),
+            ..Lines::default()
+        }
+    }
+
+    pub fn array(line: Option<usize>, items: Vec<Lines>) -> Self {
+        Lines {
+            line,
+            items,
+            ..Lines::default()
+        }
+    }
+
+    pub fn hash(line: Option<usize>, values: Vec<(&str, Lines)>) -> Self {
+        Lines {
+            line,
+            values: values
+                .into_iter()
+                .map(|(key, lines)| (key.to_string(), lines))
+                .collect(),
+            ..Lines::default()
+        }
+    }
+
+    /// Lines of the item of an array (without lines if there isn't item)
+    pub fn item(&self, index: usize) -> &Lines {
+        self.items.get(index).unwrap_or(&NO_LINES)
+    }
+
+    /// Lines of the value of a key of a hash (without lines if there isn't
+    /// key)
+    pub fn value(&self, key: &str) -> &Lines {
+        self.values
+            .iter()
+            .find(|(k, _)| k == key)
+            .map_or(&NO_LINES, |(_, lines)| lines)
+    }
+
+    /// Lines of the (first) yaml document of contents
+    pub fn load(contents: &str) -> Result<Self> {
+        let mut loader = Loader::default();
+        Parser::new(contents.chars()).load(&mut loader, false)?;
+        Ok(loader.root.unwrap_or_default())
+    }
+}
+
+// node being loaded: array or hash (with the key of the next value, None
+// while the key is read), with its anchor and line
+enum Node {
+    Array(Vec<Lines>),
+    Hash(Vec<(String, Lines)>, Option<String>),
+}
+
+#[derive(Default)]
+struct Loader {
+    stack: Vec<(Node, usize, usize)>, // (node, anchor, line)
+    anchors: HashMap<usize, Lines>,
+    root: Option<Lines>,
+}
+
+impl Loader {
+    // adds a node loaded (key is its text if it's a scalar)
+    fn add(&mut self, lines: Lines, anchor: usize, key: Option<String>) {
+        if anchor > 0 {
+            self.anchors.insert(anchor, lines.clone());
+        }
+        match self.stack.last_mut() {
+            Some((Node::Array(items), _, _)) => items.push(lines),
+            Some((Node::Hash(_, pending @ None), _, _)) => *pending = Some(key.unwrap_or_default()),
+            Some((Node::Hash(values, pending), _, _)) => {
+                values.push((pending.take().unwrap(), lines))
+            }
+            None => {
+                if self.root.is_none() {
+                    self.root = Some(lines)
+                }
+            }
+        }
+    }
+}
+
+impl MarkedEventReceiver for Loader {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        match event {
+            Event::Scalar(value, _, anchor, _) => {
+                self.add(Lines::scalar(mark.line()), anchor, Some(value))
+            }
+            Event::Alias(anchor) => {
+                let lines = self.anchors.get(&anchor).cloned().unwrap_or_default();
+                self.add(lines, 0, None);
+            }
+            Event::SequenceStart(anchor) => {
+                self.stack.push((Node::Array(vec![]), anchor, mark.line()))
+            }
+            Event::MappingStart(anchor) => {
+                self.stack
+                    .push((Node::Hash(vec![], None), anchor, mark.line()))
+            }
+            Event::SequenceEnd | Event::MappingEnd => {
+                let (node, anchor, line) = self.stack.pop().unwrap();
+                let lines = match node {
+                    Node::Array(items) => Lines::array(Some(line), items),
+                    Node::Hash(values, _) => Lines {
+                        line: Some(line),
+                        values,
+                        ..Lines::default()
+                    },
+                };
+                self.add(lines, anchor, None);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,257 @@
+// Statistics over lists and combinatorics
+//
+// Lists (Expression::List, or a vector) are sequences of magnitudes. Their
+// elements must have compatible units. Aggregates propagate units:
+//   * sum, mean, median, stdev, min, max: unit of elements
+//   * var: square of unit (m/s2 -> m2/s4)
+//   * count: without units
+// stdev and var are the sample ones (divided by n - 1).
+//
+// fact, nCr and nPr need non negative integers without units.
+//
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+
+use crate::expression::{Aggregate, DictVariables, Expression};
+use crate::magnitude::Magnitude;
+use crate::rational::Rational;
+
+impl Aggregate {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aggregate::Sum => "sum",
+            Aggregate::Mean => "mean",
+            Aggregate::Median => "median",
+            Aggregate::Stdev => "stdev",
+            Aggregate::Var => "var",
+            Aggregate::Count => "count",
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+        }
+    }
+}
+
+impl Expression {
+    pub fn is_list(&self, dict: &DictVariables) -> bool {
+        match self {
+            Expression::List(..) => true,
+            Expression::Variable(name) => dict.get(name).is_some_and(|e| e.is_list(dict)),
+            _ => false,
+        }
+    }
+
+    /// Values of the elements of a list (or vector)
+    pub fn list_values(&self, dict: &DictVariables) -> Vec<Magnitude> {
+        match self {
+            Expression::List(items) => items.iter().map(|e| e.value(dict)).collect(),
+            Expression::Variable(name) => match dict.get(name) {
+                Some(expr) => expr.list_values(dict),
+                None => panic!("Variable {} not in dictionary", name),
+            },
+            _ if self.is_matrix(dict) => self.matrix_value(dict).elements,
+            _ => panic!("Expression {:?} isn't a list", self),
+        }
+    }
+
+    pub fn aggregate_value(&self, aggregate: Aggregate, dict: &DictVariables) -> Magnitude {
+        let values = self.list_values(dict);
+        if values.is_empty() && aggregate != Aggregate::Count && aggregate != Aggregate::Sum {
+            panic!("{} of empty list {:?}", aggregate.name(), self);
+        }
+        let unit = common_unit(&values);
+        let n = values.len() as f64;
+
+        match aggregate {
+            Aggregate::Sum => {
+                let terms = values.into_iter().map(Expression::Magnitude).collect();
+                let mut sum = Expression::Add(terms).value(&DictVariables::new());
+                if sum.unit == "¿?" {
+                    sum.unit = unit;
+                }
+                sum
+            }
+            Aggregate::Mean => {
+                let sum = self.aggregate_value(Aggregate::Sum, dict);
+                Magnitude {
+                    value: sum.value / n,
+                    uncertainty: sum.uncertainty / n,
+                    unit: sum.unit,
+                    rational: sum
+                        .rational
+                        .zip(Rational::from_integer(n))
+                        .and_then(|(sum, n)| sum.div(&n)),
+                }
+            }
+            Aggregate::Median => {
+                let mut sorted: Vec<f64> = values.iter().map(|m| m.value).collect();
+                sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                // both middle elements are the same one with odd length
+                let len = sorted.len();
+                let value = (sorted[(len - 1) / 2] + sorted[len / 2]) / 2.0;
+                with_unit(value, unit)
+            }
+            Aggregate::Stdev => {
+                let variance = variance(&values);
+                with_unit(variance.sqrt(), unit)
+            }
+            Aggregate::Var => with_unit(variance(&values), squared(&unit)),
+            Aggregate::Count => with_unit(n, String::new()),
+            Aggregate::Min => with_unit(
+                values.iter().map(|m| m.value).fold(f64::INFINITY, f64::min),
+                unit,
+            ),
+            Aggregate::Max => with_unit(
+                values
+                    .iter()
+                    .map(|m| m.value)
+                    .fold(f64::NEG_INFINITY, f64::max),
+                unit,
+            ),
+        }
+    }
+}
+
+fn with_unit(value: f64, unit: String) -> Magnitude {
+    Magnitude {
+        value,
+        uncertainty: 0.0,
+        unit,
+        rational: Rational::from_integer(value),
+    }
+}
+
+/// unit of all values (they must be compatible)
+fn common_unit(values: &[Magnitude]) -> String {
+    let mut unit = String::from("¿?");
+    for value in values {
+        unit = value
+            .compatible_unit_str(&unit)
+            .unwrap_or_else(|| panic!("Wrong units in list: {:?} and {}", value, unit));
+    }
+    unit
+}
+
+/// square of unit: exponents of its factors are doubled (m/s2 -> m2/s4)
+fn squared(unit: &str) -> String {
+    lazy_static! {
+        static ref RE_FACTOR: Regex = Regex::new(r"([^\d\s/*·^()]+)(\^?)(\d*)").unwrap();
+    }
+
+    if unit.is_empty() || unit == "¿?" {
+        return unit.to_string();
+    }
+
+    RE_FACTOR
+        .replace_all(unit, |cap: &Captures| {
+            let exponent = cap[3].parse::<u32>().unwrap_or(1);
+            format!("{}{}{}", &cap[1], &cap[2], 2 * exponent)
+        })
+        .to_string()
+}
+
+fn variance(values: &[Magnitude]) -> f64 {
+    if values.len() < 2 {
+        panic!("Sample variance needs 2 values at least: {:?}", values);
+    }
+    let n = values.len() as f64;
+    let mean = values.iter().map(|m| m.value).sum::<f64>() / n;
+    values.iter().map(|m| (m.value - mean).powi(2)).sum::<f64>() / (n - 1.0)
+}
+
+/// value of a non negative integer without units
+fn natural(magnitude: &Magnitude, operation: &str) -> u64 {
+    magnitude
+        .compatible_unit_str("")
+        .unwrap_or_else(|| panic!("{} of magnitude with units: {:?}", operation, magnitude));
+    if magnitude.value < 0.0 || magnitude.value.fract() != 0.0 {
+        panic!(
+            "{} of {:?} (it must be a non negative integer)",
+            operation, magnitude
+        );
+    }
+    magnitude.value as u64
+}
+
+/// n (n-1) ... (n-r+1) as float (to avoid overflows)
+fn falling(n: u64, r: u64) -> f64 {
+    (0..r).map(|i| (n - i) as f64).product()
+}
+
+pub fn factorial(n: &Magnitude) -> Magnitude {
+    let n = natural(n, "fact");
+    with_unit(falling(n, n), String::new())
+}
+
+pub fn permutations(n: &Magnitude, r: &Magnitude) -> Magnitude {
+    let (n, r) = (natural(n, "nPr"), natural(r, "nPr"));
+    let value = if r > n { 0.0 } else { falling(n, r) };
+    with_unit(value, String::new())
+}
+
+pub fn combinations(n: &Magnitude, r: &Magnitude) -> Magnitude {
+    let (n, r) = (natural(n, "nCr"), natural(r, "nCr"));
+    if r > n {
+        return with_unit(0.0, String::new());
+    }
+    // multiplicative formula (exact while it fits in f64)
+    let r = r.min(n - r);
+    let value = (1..=r).fold(1.0, |acc, i| acc * (n - r + i) as f64 / i as f64);
+    with_unit(value.round(), String::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::macros::Macros;
+
+    fn value(rpn: &str) -> Magnitude {
+        Expression::from(rpn, &Macros::new()).value(&DictVariables::new())
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn aggregates_of_a_list() {
+        let list = "2 4 4 4 5 5 7 9 8 list";
+        assert_close(value(&format!("{} sum", list)).value, 40.0);
+        assert_close(value(&format!("{} mean", list)).value, 5.0);
+        assert_close(value(&format!("{} median", list)).value, 4.5);
+        assert_close(
+            value(&format!("{} stdev", list)).value,
+            (32.0f64 / 7.0).sqrt(),
+        );
+        assert_close(value(&format!("{} var", list)).value, 32.0 / 7.0);
+        assert_close(value(&format!("{} count", list)).value, 8.0);
+        assert_close(value(&format!("{} min", list)).value, 2.0);
+        assert_close(value(&format!("{} max", list)).value, 9.0);
+
+        assert_close(value("3 1 2 3 list median").value, 2.0);
+        assert_eq!(value("1 2 2 list mean").rational, Rational::new(3, 2));
+    }
+
+    #[test]
+    fn units_of_aggregates() {
+        assert_eq!(value("1m 3m 2 list mean").unit, "m");
+        let var = value("1m/s2 3m/s2 2 list var");
+        assert_close(var.value, 2.0);
+        assert_eq!(var.unit, "m2/s4");
+        assert_eq!(value("1m 3m 2 list count").unit, "");
+    }
+
+    #[test]
+    #[should_panic(expected = "Wrong units in list")]
+    fn incompatible_units() {
+        value("1m 3s 2 list sum");
+    }
+
+    #[test]
+    fn combinatorics() {
+        assert_close(value("5 fact").value, 120.0);
+        assert_close(value("0 fact").value, 1.0);
+        assert_close(value("5 2 nCr").value, 10.0);
+        assert_close(value("5 2 nPr").value, 20.0);
+    }
+}
@@ -0,0 +1,601 @@
+// Plain text exercises (.twp)
+//
+//     Title of the exercise
+//
+//     Statement of the problem
+//
+//     --> good option
+//     -- note of the good option
+//     --X wrong option
+//     -- note of the wrong option
+//     >>>
+//     Context after the good option (start of next step)
+//     ...
+//
+// The text is converted to the yaml document of the exercise: the statement
+// is the first passage, each step is a passage (an alternative of passages
+// if it has several good options) with the wrong options as pre_bad and the
+// text after ">>>" is the follow of the good options. An optional yaml
+// header between "---" lines sets the other keys (macros, units, format...).
+//
+
+use anyhow::{bail, Result};
+use yaml_rust::yaml::Hash;
+use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
+
+use crate::passage::is_macros;
+use crate::sourcemap::Lines;
+
+#[derive(Debug)]
+struct Choice {
+    line: usize, // of the marker
+    good: bool,
+    text: Vec<String>,
+    note: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default)]
+struct Step {
+    line: Option<usize>, // first line of the intro
+    intro: Vec<String>,
+    options: Vec<Choice>,
+}
+
+//--------------------------------------------
+// twp -> yaml
+
+/// Yaml document of a twp exercise with the lines of the gates of its
+/// passages
+pub fn to_yaml(contents: &str) -> Result<(Yaml, Lines)> {
+    // lines with their numbers (from 1)
+    let mut lines: Vec<(usize, &str)> = contents.lines().zip(1..).map(|(l, n)| (n, l)).collect();
+
+    // yaml header
+    let mut doc = Hash::new();
+    if lines.first().map(|(_, line)| line.trim_end()) == Some("---") {
+        let end = match lines
+            .iter()
+            .skip(1)
+            .position(|(_, line)| line.trim_end() == "---")
+        {
+            Some(end) => end + 1,
+            None => bail!("yaml header without end (---)"),
+        };
+        let header: Vec<&str> = lines[1..end].iter().map(|(_, line)| *line).collect();
+        let header = YamlLoader::load_from_str(&header.join("\n"))?;
+        match header.into_iter().next() {
+            Some(Yaml::Hash(hash)) => doc = hash,
+            Some(Yaml::Null) | None => {}
+            Some(_) => bail!("yaml header isn't a hash"),
+        }
+        lines.drain(..=end);
+    }
+
+    if !doc.contains_key(&Yaml::from_str("title")) {
+        let title = match lines.iter().position(|(_, line)| !line.trim().is_empty()) {
+            Some(position) => lines.remove(position).1.trim().to_string(),
+            None => bail!("Exercise without title"),
+        };
+        doc.insert(Yaml::from_str("title"), Yaml::String(title));
+    }
+
+    let steps = parse_steps(&lines)?;
+    let (passages, passages_lines) = passages(&steps)?;
+    doc.insert(Yaml::from_str("passages"), passages);
+    let lines = Lines::hash(None, vec![("passages", passages_lines)]);
+    Ok((Yaml::Hash(doc), lines))
+}
+
+fn parse_steps(lines: &[(usize, &str)]) -> Result<Vec<Step>> {
+    let mut steps = vec![Step::default()];
+
+    for &(number, line) in lines {
+        let step = steps.last_mut().unwrap();
+        if let Some(rest) = marker(line, ">>>") {
+            steps.push(Step::default());
+            steps
+                .last_mut()
+                .unwrap()
+                .intro
+                .push(rest.trim().to_string());
+        } else if let Some(rest) = marker(line, "-->") {
+            step.options.push(Choice {
+                line: number,
+                good: true,
+                text: vec![rest.trim().to_string()],
+                note: None,
+            });
+        } else if let Some(rest) = marker(line, "--X") {
+            step.options.push(Choice {
+                line: number,
+                good: false,
+                text: vec![rest.trim().to_string()],
+                note: None,
+            });
+        } else if let Some(rest) = marker(line, "--") {
+            match step.options.last_mut() {
+                Some(option) if option.note.is_none() => {
+                    option.note = Some(vec![rest.trim().to_string()])
+                }
+                Some(_) => bail!("Second note (--) of an option: {:?}", line),
+                None => bail!("Note (--) without option: {:?}", line),
+            }
+        } else {
+            match step.options.last_mut() {
+                Some(Choice {
+                    note: Some(note), ..
+                }) => note.push(line.to_string()),
+                Some(option) => option.text.push(line.to_string()),
+                None => {
+                    if step.line.is_none() && !line.trim().is_empty() {
+                        step.line = Some(number);
+                    }
+                    step.intro.push(line.to_string())
+                }
+            }
+        }
+    }
+    Ok(steps)
+}
+
+// text after marker (markers are followed by a blank or the end of line:
+// "---" or "--Xylophone" aren't markers)
+fn marker<'a>(line: &'a str, marker: &str) -> Option<&'a str> {
+    line.strip_prefix(marker)
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+}
+
+// passages (statement, steps and the text after the last step) with the
+// lines of their gates
+fn passages(steps: &[Step]) -> Result<(Yaml, Lines)> {
+    let mut passages = vec![passage(join(&steps[0].intro))];
+    let mut lines = vec![passage_lines(steps[0].line, vec![])];
+
+    for (i, step) in steps.iter().enumerate() {
+        let follow = match steps.get(i + 1) {
+            Some(next) => join(&next.intro),
+            None => String::new(),
+        };
+        if step.options.is_empty() {
+            if i + 1 < steps.len() {
+                bail!("Step {} without options", i + 1);
+            }
+            continue;
+        }
+
+        let bad: Vec<Yaml> = step
+            .options
+            .iter()
+            .filter(|option| !option.good)
+            .map(|option| Yaml::String(gate(option, "")))
+            .collect();
+        let mut good: Vec<Yaml> = step
+            .options
+            .iter()
+            .filter(|option| option.good)
+            .map(|option| passage(gate(option, &follow)))
+            .collect();
+        if good.is_empty() {
+            bail!("Step {} without good option (-->)", i + 1);
+        }
+        let mut bad_lines: Vec<Lines> = step
+            .options
+            .iter()
+            .filter(|option| !option.good)
+            .map(|option| Lines::scalar(option.line))
+            .collect();
+        let good_lines: Vec<Lines> = step
+            .options
+            .iter()
+            .filter(|option| option.good)
+            .map(|option| passage_lines(Some(option.line), std::mem::take(&mut bad_lines)))
+            .collect();
+
+        // the wrong options are shown once, with the first good one
+        if let Yaml::Hash(hash) = &mut good[0] {
+            if let Some(Yaml::Hash(pass)) = hash.get_mut(&Yaml::from_str("pass")) {
+                if !bad.is_empty() {
+                    pass.insert(Yaml::from_str("pre_bad"), Yaml::Array(bad));
+                }
+            }
+        }
+
+        if good.len() == 1 {
+            passages.extend(good);
+            lines.extend(good_lines);
+        } else {
+            let mut alt = Hash::new();
+            alt.insert(Yaml::from_str("alt"), Yaml::Array(good));
+            passages.push(Yaml::Hash(alt));
+            lines.push(Lines::hash(
+                None,
+                vec![("alt", Lines::array(None, good_lines))],
+            ));
+        }
+    }
+    Ok((Yaml::Array(passages), Lines::array(None, lines)))
+}
+
+fn passage(text: String) -> Yaml {
+    let mut pass = Hash::new();
+    pass.insert(Yaml::from_str("text"), Yaml::String(text));
+    let mut hash = Hash::new();
+    hash.insert(Yaml::from_str("pass"), Yaml::Hash(pass));
+    Yaml::Hash(hash)
+}
+
+// lines of passage: the line of its text and of its wrong options
+fn passage_lines(line: Option<usize>, bad: Vec<Lines>) -> Lines {
+    let text = line.map_or_else(Lines::default, Lines::scalar);
+    let pass = Lines::hash(
+        None,
+        vec![("text", text), ("pre_bad", Lines::array(None, bad))],
+    );
+    Lines::hash(None, vec![("pass", pass)])
+}
+
+// gate with markers of note (---) and follow (...)
+fn gate(choice: &Choice, follow: &str) -> String {
+    let mut output = join(&choice.text);
+    if let Some(note) = &choice.note {
+        output.push_str(&format!("\n---{}", join(note)));
+    }
+    if !follow.is_empty() {
+        output.push_str(&format!("\n...{}", follow));
+    }
+    output
+}
+
+// lines without blank lines at the beginning and end
+fn join(lines: &[String]) -> String {
+    let start = lines.iter().position(|line| !line.trim().is_empty());
+    let end = lines.iter().rposition(|line| !line.trim().is_empty());
+    match (start, end) {
+        (Some(start), Some(end)) => lines[start..=end].join("\n"),
+        _ => String::new(),
+    }
+}
+
+//--------------------------------------------
+// yaml -> twp
+
+/// twp text of an exercise (only sequences of passages and alternatives of
+/// passages, without conditions or post_bad gates)
+pub fn from_yaml(doc: &Yaml) -> Result<String> {
+    let hash = match doc {
+        Yaml::Hash(hash) => hash,
+        _ => bail!("The exercise isn't a hash"),
+    };
+    let mut header = hash.clone();
+    header.remove(&Yaml::from_str("title"));
+    header.remove(&Yaml::from_str("passages"));
+
+    // paths and macros directives between passages go to the header
+    let mut passages = vec![];
+    for elem in doc["passages"].as_vec().into_iter().flatten() {
+        let directive = ["paths", "macros"]
+            .iter()
+            .find(|&&key| main_key(elem) == Some(key));
+        match directive {
+            Some(&key) => {
+                let values = is_macros(key, elem).unwrap_or_default();
+                let key = Yaml::from_str(key);
+                let mut items = match header.remove(&key) {
+                    Some(Yaml::Array(items)) => items,
+                    Some(item) => vec![item],
+                    None => vec![],
+                };
+                items.extend(values.into_iter().map(Yaml::String));
+                header.insert(key, Yaml::Array(items));
+            }
+            None => passages.push(elem),
+        }
+    }
+    if passages.is_empty() {
+        bail!("The exercise hasn't passages");
+    }
+
+    let mut output = String::new();
+
+    // yaml header with the other keys
+    if !header.is_empty() {
+        let mut emitted = String::new();
+        YamlEmitter::new(&mut emitted).dump(&Yaml::Hash(header))?;
+        output.push_str(&format!("{}\n---\n", emitted));
+    }
+    output.push_str(&format!("{}\n\n", doc["title"].as_str().unwrap_or("")));
+
+    // the statement is written as it is (with its markers of note and follow)
+    if !passages[0]["pass"]["pre_bad"].is_badvalue() {
+        bail!("The first passage has wrong options");
+    }
+    output.push_str(&paragraph(pass_text(passages[0])?.trim_end()));
+
+    let mut follow = String::new();
+    for (i, elem) in passages.iter().enumerate().skip(1) {
+        let goods: Vec<&Yaml> = match &elem["alt"] {
+            Yaml::Array(alternatives) => alternatives.iter().collect(),
+            Yaml::BadValue => vec![elem],
+            _ => bail!("Passage {} isn't a pass or an alt of passes", i + 1),
+        };
+        if i > 1 {
+            output.push_str(">>>\n");
+            output.push_str(&paragraph(&follow));
+        }
+
+        let mut bad = vec![];
+        for good in &goods {
+            let (text, note, next) = split_gate(pass_text(good)?);
+            output.push_str(&option("-->", &text, &note));
+            follow = next;
+            if let Yaml::Array(gates) = &good["pass"]["pre_bad"] {
+                bad.extend(gates.iter());
+            }
+        }
+        for gate in bad {
+            let (text, note, _) = split_gate(gate.as_str().unwrap_or(""));
+            output.push_str(&option("--X", &text, &note));
+        }
+    }
+    if !follow.is_empty() {
+        output.push_str(">>>\n");
+        output.push_str(&paragraph(&follow));
+    }
+    Ok(output)
+}
+
+// the only key of a hash
+fn main_key(elem: &Yaml) -> Option<&str> {
+    match elem {
+        Yaml::Hash(hash) if hash.len() == 1 => hash.keys().next()?.as_str(),
+        _ => None,
+    }
+}
+
+fn pass_text(elem: &Yaml) -> Result<&str> {
+    let pass = &elem["pass"];
+    let keys = ["text", "pre_bad"];
+    match pass {
+        Yaml::Hash(hash)
+            if hash
+                .keys()
+                .all(|key| keys.contains(&key.as_str().unwrap_or(""))) =>
+        {
+            Ok(pass["text"].as_str().unwrap_or(""))
+        }
+        _ => bail!("Passage without twp equivalent: {:?}", elem),
+    }
+}
+
+// text, note and follow of a gate
+fn split_gate(gate: &str) -> (String, String, String) {
+    let mut parts = [vec![], vec![], vec![]];
+    let mut current = 0;
+    for mut line in gate.lines() {
+        for (i, marker) in ["___", "---", "..."].iter().enumerate() {
+            if let Some(rest) = line.strip_prefix(marker) {
+                current = i;
+                line = rest;
+            }
+        }
+        parts[current].push(line.to_string());
+    }
+    let [text, note, follow] = parts;
+    (join(&text), join(&note), join(&follow))
+}
+
+fn option(marker: &str, text: &str, note: &str) -> String {
+    let mut output = format!("{} {}\n", marker, text);
+    if !note.is_empty() {
+        output.push_str(&format!("-- {}\n", note));
+    }
+    output
+}
+
+fn paragraph(text: &str) -> String {
+    if text.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n\n", text)
+    }
+}
+
+//--------------------------------------------
+// yaml emitter
+
+/// Yaml text of an exercise (strings of several lines as blocks)
+pub fn emit_yaml(doc: &Yaml) -> String {
+    let mut output = String::from("---\n");
+    if let Yaml::Hash(hash) = doc {
+        for (key, value) in hash {
+            emit(key, value, 0, &mut output);
+        }
+    }
+    output
+}
+
+fn emit(key: &Yaml, value: &Yaml, indent: usize, output: &mut String) {
+    let pad = " ".repeat(indent);
+    let key = key.as_str().unwrap_or("");
+    match value {
+        Yaml::String(string) => {
+            output.push_str(&format!("{}{}:{}\n", pad, key, scalar(string, indent + 2)))
+        }
+        Yaml::Hash(hash) => {
+            output.push_str(&format!("{}{}:\n", pad, key));
+            for (key, value) in hash {
+                emit(key, value, indent + 2, output);
+            }
+        }
+        Yaml::Array(items) => {
+            output.push_str(&format!("{}{}:\n", pad, key));
+            for item in items {
+                emit_item(item, indent + 2, output);
+            }
+        }
+        _ => {
+            let mut emitted = String::new();
+            let _ = YamlEmitter::new(&mut emitted).dump(value);
+            let emitted = emitted.trim_start_matches("---").trim();
+            output.push_str(&format!("{}{}: {}\n", pad, key, emitted));
+        }
+    }
+}
+
+fn emit_item(item: &Yaml, indent: usize, output: &mut String) {
+    let pad = " ".repeat(indent);
+    match item {
+        Yaml::Hash(hash) => {
+            // first key after "- ", the rest aligned with it
+            let mut emitted = String::new();
+            for (key, value) in hash {
+                emit(key, value, indent + 2, &mut emitted);
+            }
+            output.push_str(&format!("{}- {}", pad, &emitted[indent + 2..]));
+        }
+        Yaml::String(string) => {
+            output.push_str(&format!("{}-{}\n", pad, scalar(string, indent + 2)))
+        }
+        _ => {
+            let mut emitted = String::new();
+            let _ = YamlEmitter::new(&mut emitted).dump(item);
+            let emitted = emitted.trim_start_matches("---").trim();
+            output.push_str(&format!("{}- {}\n", pad, emitted));
+        }
+    }
+}
+
+// " value" or a block (|) of lines for strings of several lines
+fn scalar(string: &str, indent: usize) -> String {
+    if !string.contains('\n') {
+        let mut emitted = String::new();
+        let _ = YamlEmitter::new(&mut emitted).dump(&Yaml::String(string.to_string()));
+        return format!(" {}", emitted.trim_start_matches("---").trim());
+    }
+    let pad = " ".repeat(indent);
+    // indentation indicator if the first line starts with spaces
+    let indicator = if string.starts_with(' ') { "2" } else { "" };
+    // "-": without the final new line
+    let lines: Vec<String> = string
+        .lines()
+        .map(|line| {
+            if line.is_empty() {
+                String::new()
+            } else {
+                format!("{}{}", pad, line)
+            }
+        })
+        .collect();
+    format!(" |{}-\n{}", indicator, lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    const EXERCISE: &str = "\
+---
+macros: macros-TEn.yaml
+---
+Ohm
+
+Statement {{; V = 10V }}
+
+--> First step {{; R = 1kohm }}
+-- Note of the good option
+--X Wrong option
+-- Why it's wrong
+--X Other wrong option
+>>>
+Follow of the first step
+
+--> Good A
+--> Good B
+--X Wrong of both
+";
+
+    fn source(name: &str) -> String {
+        fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("sources")
+                .join(name),
+        )
+        .unwrap()
+    }
+
+    fn yaml(contents: &str) -> Yaml {
+        to_yaml(contents).unwrap().0
+    }
+
+    #[test]
+    fn steps_and_options() {
+        let doc = yaml(EXERCISE);
+        assert_eq!(doc["title"].as_str(), Some("Ohm"));
+        assert_eq!(doc["macros"].as_str(), Some("macros-TEn.yaml"));
+
+        let passages = &doc["passages"];
+        assert_eq!(
+            passages[0]["pass"]["text"].as_str(),
+            Some("Statement {{; V = 10V }}")
+        );
+        let first = &passages[1]["pass"];
+        assert_eq!(
+            first["text"].as_str(),
+            Some("First step {{; R = 1kohm }}\n---Note of the good option\n...Follow of the first step")
+        );
+        assert_eq!(
+            first["pre_bad"][0].as_str(),
+            Some("Wrong option\n---Why it's wrong")
+        );
+        assert_eq!(first["pre_bad"][1].as_str(), Some("Other wrong option"));
+
+        // several good options are an alternative (the wrong ones go with the first)
+        let alt = &passages[2]["alt"];
+        assert_eq!(alt[0]["pass"]["text"].as_str(), Some("Good A"));
+        assert_eq!(alt[0]["pass"]["pre_bad"][0].as_str(), Some("Wrong of both"));
+        assert_eq!(alt[1]["pass"]["text"].as_str(), Some("Good B"));
+        assert!(alt[1]["pass"]["pre_bad"].is_badvalue());
+    }
+
+    #[test]
+    fn lines_of_gates() {
+        let (_, lines) = to_yaml(EXERCISE).unwrap();
+        let passages = lines.value("passages");
+        let line = |lines: &Lines| lines.line;
+        assert_eq!(line(passages.item(0).value("pass").value("text")), Some(6));
+        let first = passages.item(1).value("pass");
+        assert_eq!(line(first.value("text")), Some(8));
+        assert_eq!(line(first.value("pre_bad").item(0)), Some(10));
+        assert_eq!(line(first.value("pre_bad").item(1)), Some(12));
+        let alt = passages.item(2).value("alt");
+        assert_eq!(line(alt.item(1).value("pass").value("text")), Some(17));
+        assert_eq!(
+            line(alt.item(0).value("pass").value("pre_bad").item(0)),
+            Some(18)
+        );
+    }
+
+    #[test]
+    fn round_trip_of_twp() {
+        for contents in [EXERCISE.to_string(), source("test.twp")] {
+            let doc = yaml(&contents);
+            assert_eq!(yaml(&from_yaml(&doc).unwrap()), doc);
+            let emitted = YamlLoader::load_from_str(&emit_yaml(&doc)).unwrap();
+            assert_eq!(emitted[0], doc);
+        }
+    }
+
+    #[test]
+    fn yaml_without_twp_equivalent() {
+        // post_bad gates can't be written in twp
+        let doc = YamlLoader::load_from_str(&source("test.yaml")).unwrap();
+        let error = from_yaml(&doc[0]).unwrap_err().to_string();
+        assert!(
+            error.starts_with("Passage without twp equivalent"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,287 @@
+// Registry of units
+//
+// Units are defined in yaml files (located with the same paths as macros
+// files) with the key "units". Every field is optional:
+//
+//   km/h:
+//     symbol: km/h           # LaTeX of unit (default: name of unit)
+//     base: m/s              # unit used to convert (default: itself)
+//     factor: 0.277777777778 # value in base = value * factor + offset
+//     offset: 0
+//     prefix: false          # SI prefixes can be added to unit when it is shown
+//                            # (default: only if factor is 1 and offset is 0)
+//     aliases: [kph]         # other names of the unit
+//
+// Units not defined are base units of themselves. A unit with a SI prefix
+// (mA, kohm, ...) is converted as the factor of prefix and the unit.
+//
+// The registry is kept with the macros (Macros::units) and follows their
+// scopes: units loaded in a passage are known where the macros loaded in the
+// same place are. It is passed to the code that needs it: magnitudes are
+// normalized with the registry of the expression when it is parsed, a
+// conversion (->) keeps the registry of the place where it is written and
+// the symbols are shown with the registry of Format.
+//
+
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::magnitude::prefix_factor;
+
+// maximum length of chains of base units
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Unit {
+    symbol: String,
+    base: Option<String>,
+    factor: f64,
+    offset: f64,
+    prefix: bool,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Registry {
+    units: HashMap<String, Unit>,
+    aliases: HashMap<String, String>,
+}
+
+impl Registry {
+    /// Registry with the builtin units
+    pub fn builtin() -> Self {
+        let mut units = HashMap::new();
+        units.insert(
+            String::from("ohm"),
+            Unit {
+                symbol: String::from("\\Omega"),
+                base: None,
+                factor: 1.0,
+                offset: 0.0,
+                prefix: true,
+            },
+        );
+
+        Registry {
+            units,
+            aliases: HashMap::new(),
+        }
+    }
+
+    /// Adds the units defined in yaml file
+    pub fn load(&mut self, file: &Path) -> Result<()> {
+        let contents = fs::read_to_string(file)?;
+        let docs = YamlLoader::load_from_str(&contents)?;
+
+        let hash = match docs.first() {
+            Some(Yaml::Hash(hash)) => hash.clone(),
+            Some(Yaml::Null) | None => return Ok(()),
+            Some(_) => bail!("File of units {:?} isn't a hash", file),
+        };
+
+        for (key, value) in hash {
+            let name = match key.as_str() {
+                Some(name) => name.to_string(),
+                None => bail!("Wrong unit name {:?} in {:?}", key, file),
+            };
+
+            let factor = number(&value["factor"]).unwrap_or(1.0);
+            let offset = number(&value["offset"]).unwrap_or(0.0);
+            let unit = Unit {
+                symbol: value["symbol"].as_str().unwrap_or(&name).to_string(),
+                base: value["base"].as_str().map(|s| s.to_string()),
+                factor,
+                offset,
+                prefix: value["prefix"]
+                    .as_bool()
+                    .unwrap_or(factor == 1.0 && offset == 0.0),
+            };
+
+            let mut aliases = vec![];
+            if let Some(vec) = value["aliases"].as_vec() {
+                for alias in vec {
+                    match alias.as_str() {
+                        Some(alias) => aliases.push(alias.to_string()),
+                        None => bail!("Wrong alias {:?} of unit {} in {:?}", alias, name, file),
+                    }
+                }
+            }
+
+            for alias in aliases {
+                self.aliases.insert(alias, name.clone());
+            }
+            self.units.insert(name, unit);
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Option<&Unit> {
+        match self.aliases.get(name) {
+            Some(canonical) => self.units.get(canonical),
+            None => self.units.get(name),
+        }
+    }
+
+    /// (base unit, factor, offset) of name
+    fn resolve(&self, name: &str, depth: usize) -> Option<(String, f64, f64)> {
+        if name == "¿?" || depth > MAX_DEPTH {
+            return None;
+        }
+
+        if let Some(unit) = self.get(name) {
+            return match &unit.base {
+                Some(base) => {
+                    let (base, factor, offset) = self.resolve(base, depth + 1)?;
+                    Some((base, unit.factor * factor, unit.offset * factor + offset))
+                }
+                None => {
+                    let name = self.aliases.get(name).map_or(name, |n| n.as_str());
+                    Some((name.to_string(), unit.factor, unit.offset))
+                }
+            };
+        }
+
+        // prefixed units (same rule as magnitudes: prefix and, at least, one letter)
+        let mut chars = name.chars();
+        if let (Some(first), Some(second)) = (chars.next(), chars.next()) {
+            if second.is_alphabetic() {
+                if let Some(factor) = prefix_factor(first) {
+                    let rest = &name[first.len_utf8()..];
+                    if let Some((base, f, 0.0)) = self.resolve(rest, depth + 1) {
+                        return Some((base, factor * f, 0.0));
+                    }
+                }
+            }
+        }
+
+        Some((name.to_string(), 1.0, 0.0))
+    }
+
+    /// Name of unit (aliases are replaced with its unit)
+    pub fn canonical(&self, name: &str) -> String {
+        match self.aliases.get(name) {
+            Some(canonical) => canonical.clone(),
+            None => name.to_string(),
+        }
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// LaTeX of unit
+    pub fn symbol(&self, name: &str) -> String {
+        match self.get(name) {
+            Some(unit) => unit.symbol.clone(),
+            None => name.to_string(),
+        }
+    }
+
+    /// Determines if a SI prefix can be added to unit when it is shown
+    pub fn admits_prefix(&self, name: &str) -> bool {
+        match self.get(name) {
+            Some(unit) => unit.prefix,
+            None => true,
+        }
+    }
+
+    /// (factor, offset) to convert a value in unit from to unit to:
+    /// value_to = value_from * factor + offset
+    /// None if units haven't the same base unit
+    pub fn conversion(&self, from: &str, to: &str) -> Option<(f64, f64)> {
+        let (base_from, factor_from, offset_from) = self.resolve(from, 0)?;
+        let (base_to, factor_to, offset_to) = self.resolve(to, 0)?;
+
+        if base_from != base_to {
+            return None;
+        }
+
+        Some((
+            factor_from / factor_to,
+            (offset_from - offset_to) / factor_to,
+        ))
+    }
+}
+
+// only the names of units (expressions with conversions are printed in
+// the messages of errors)
+impl fmt::Debug for Registry {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<&String> = self.units.keys().chain(self.aliases.keys()).collect();
+        names.sort();
+        formatter.debug_set().entries(names).finish()
+    }
+}
+
+fn number(yaml: &Yaml) -> Option<f64> {
+    match yaml {
+        Yaml::Real(_) => yaml.as_f64(),
+        Yaml::Integer(i) => Some(*i as f64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> Registry {
+        let mut registry = Registry::builtin();
+        registry
+            .load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("macros/units-SI.yaml"))
+            .unwrap();
+        registry
+    }
+
+    fn convert(registry: &Registry, value: f64, from: &str, to: &str) -> f64 {
+        let (factor, offset) = registry.conversion(from, to).unwrap();
+        value * factor + offset
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn conversions_with_factors() {
+        let registry = registry();
+        assert_close(convert(&registry, 36.0, "km/h", "m/s"), 10.0);
+        assert_close(convert(&registry, 10.0, "m/s", "kph"), 36.0);
+        assert_close(convert(&registry, 1.0, "kWh", "J"), 3.6e6);
+        assert_close(convert(&registry, 2.0, "h", "min"), 120.0);
+    }
+
+    #[test]
+    fn conversions_with_offsets() {
+        let registry = registry();
+        assert_close(convert(&registry, 100.0, "degC", "degF"), 212.0);
+        assert_close(convert(&registry, -40.0, "°F", "°C"), -40.0);
+        assert_close(convert(&registry, 0.0, "degC", "K"), 273.15);
+    }
+
+    #[test]
+    fn prefixed_and_unknown_units() {
+        let registry = registry();
+        assert_close(convert(&registry, 4.7, "kohm", "ohm"), 4700.0);
+        assert_close(convert(&registry, 250.0, "mA", "A"), 0.25);
+        assert_eq!(registry.conversion("m", "s"), None);
+        assert_eq!(registry.conversion("¿?", "m"), None);
+        // prefixes aren't added to units with offset
+        assert_eq!(registry.conversion("kdegC", "degC"), None);
+    }
+
+    #[test]
+    fn names_and_symbols() {
+        let registry = registry();
+        assert_eq!(registry.canonical("kph"), "km/h");
+        assert_eq!(registry.symbol("°C"), "^{\\circ}C");
+        assert_eq!(registry.symbol("ohm"), "\\Omega");
+        assert!(registry.admits_prefix("ohm"));
+        assert!(!registry.admits_prefix("km/h"));
+        assert!(!Registry::builtin().is_defined("km/h"));
+    }
+}